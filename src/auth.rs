@@ -1,13 +1,102 @@
+use crate::channels::{ChannelType, PresenceMember};
 use crate::error::{PusherError, PusherResult};
 use base64::{engine::general_purpose, Engine as _};
 use hmac::{Hmac, Mac};
 use log::debug;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::Sha256;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The body an auth endpoint returns to a client subscribing to a private or presence
+/// channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthResponse {
+    /// The `key:signature` token.
+    pub auth: String,
+    /// The signed member data, for presence channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_data: Option<String>,
+}
+
+impl AuthResponse {
+    /// Serializes the response as the JSON body Pusher's client libraries expect from an
+    /// auth endpoint, `{"auth":...}` with `channel_data` for presence channels.
+    pub fn to_json(&self) -> PusherResult<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// The user a server signs in with `PusherAuth::authenticate_user`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserData {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_info: Option<Value>,
+    /// The ids of users whose online status this user is notified of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchlist: Option<Vec<String>>,
+}
+
+/// The body an auth endpoint returns to a client signing in, for it to send in
+/// `pusher:signin`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserAuthResponse {
+    /// The `key:signature` token.
+    pub auth: String,
+    /// The signed user data, serialized as JSON.
+    pub user_data: String,
+}
+
+/// Tokens from the auth endpoint for the current socket, keyed by channel, see
+/// `PusherConfig::cache_channel_auth`. Since tokens are bound to the socket ID, those of an
+/// old socket are dropped once a new one is used.
+#[derive(Default)]
+pub(crate) struct AuthCache {
+    tokens: Mutex<SocketTokens>,
+}
 
+#[derive(Default)]
+struct SocketTokens {
+    socket_id: String,
+    by_channel: HashMap<String, AuthResponse>,
+}
+
+impl AuthCache {
+    pub(crate) fn get(&self, socket_id: &str, channel_name: &str) -> Option<AuthResponse> {
+        let tokens = self.tokens.lock().unwrap();
+        if tokens.socket_id != socket_id {
+            return None;
+        }
+        tokens.by_channel.get(channel_name).cloned()
+    }
+
+    pub(crate) fn insert(&self, socket_id: &str, channel_name: &str, auth: AuthResponse) {
+        self.reset(socket_id);
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.by_channel.insert(channel_name.to_string(), auth);
+    }
+
+    /// Forgets the token for `channel_name`, for example after the server rejected it.
+    pub(crate) fn remove(&self, channel_name: &str) {
+        self.tokens.lock().unwrap().by_channel.remove(channel_name);
+    }
+
+    /// Drops the tokens of any socket other than `socket_id`.
+    pub(crate) fn reset(&self, socket_id: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.socket_id != socket_id {
+            *tokens = SocketTokens {
+                socket_id: socket_id.to_string(),
+                by_channel: HashMap::new(),
+            };
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct PusherAuth {
     key: String,
     secret: String,
@@ -22,7 +111,24 @@ impl PusherAuth {
     }
 
     pub fn authenticate_socket(&self, socket_id: &str, channel_name: &str) -> PusherResult<String> {
-        let auth_signature = self.sign_socket(socket_id, channel_name)?;
+        self.sign_subscription(socket_id, channel_name, None)
+    }
+
+    /// Produces the `key:signature` token for a subscription, signing
+    /// `socket_id:channel_name`, or `socket_id:channel_name:channel_data` when presence
+    /// `channel_data` is given, with HMAC-SHA256 as Pusher does.
+    pub fn sign_subscription(
+        &self,
+        socket_id: &str,
+        channel_name: &str,
+        channel_data: Option<&str>,
+    ) -> PusherResult<String> {
+        let auth_signature = match channel_data {
+            Some(channel_data) => {
+                self.sign_socket_with_channel_data(socket_id, channel_name, channel_data)?
+            }
+            None => self.sign_socket(socket_id, channel_name)?,
+        };
         Ok(format!("{}:{}", self.key, auth_signature))
     }
 
@@ -42,12 +148,65 @@ impl PusherAuth {
         }
 
         let channel_data_str = serde_json::to_string(&channel_data)?;
-        let auth_signature = self.sign_socket_with_channel_data(socket_id, channel_name, &channel_data_str)?;
+        self.sign_subscription(socket_id, channel_name, Some(&channel_data_str))
+    }
 
-        Ok(format!(
-            "{}:{}",
-            self.key, auth_signature
-        ))
+    /// Signs a subscription to a private or presence channel for the client with `socket_id`,
+    /// as an auth endpoint does. Presence channels need `presence_member`, which is signed
+    /// along as the `channel_data`. `AuthResponse::to_json` gives the body to answer with.
+    pub fn authenticate_channel(
+        &self,
+        socket_id: &str,
+        channel_name: &str,
+        presence_member: Option<&PresenceMember>,
+    ) -> PusherResult<AuthResponse> {
+        validate_socket_id(socket_id)?;
+        match (ChannelType::from_name(channel_name), presence_member) {
+            (ChannelType::Public, _) => Err(PusherError::AuthError(format!(
+                "Public channel '{}' doesn't need authentication",
+                channel_name
+            ))),
+            (ChannelType::Presence, None) => Err(PusherError::AuthError(format!(
+                "Presence channel '{}' needs presence data",
+                channel_name
+            ))),
+            (ChannelType::Presence, Some(member)) => {
+                let channel_data = serde_json::to_string(member)?;
+                let signature =
+                    self.sign_socket_with_channel_data(socket_id, channel_name, &channel_data)?;
+                Ok(AuthResponse {
+                    auth: format!("{}:{}", self.key, signature),
+                    channel_data: Some(channel_data),
+                })
+            }
+            (_, Some(_)) => Err(PusherError::AuthError(format!(
+                "Presence data given for non-presence channel '{}'",
+                channel_name
+            ))),
+            (_, None) => Ok(AuthResponse {
+                auth: self.authenticate_socket(socket_id, channel_name)?,
+                channel_data: None,
+            }),
+        }
+    }
+
+    /// Signs `user_data` for the client with `socket_id`, following Pusher's user
+    /// authentication scheme, which signs `socket_id::user::user_data`.
+    pub fn authenticate_user(
+        &self,
+        socket_id: &str,
+        user_data: &UserData,
+    ) -> PusherResult<UserAuthResponse> {
+        validate_socket_id(socket_id)?;
+        if user_data.id.is_empty() {
+            return Err(PusherError::AuthError("User data needs a non-empty id".to_string()));
+        }
+        let user_data = serde_json::to_string(user_data)?;
+        let signature = self.sign(&format!("{}::user::{}", socket_id, user_data))?;
+        Ok(UserAuthResponse {
+            auth: format!("{}:{}", self.key, signature),
+            user_data,
+        })
     }
 
     pub fn authenticate_private_encrypted_channel(
@@ -71,7 +230,23 @@ impl PusherAuth {
         path: &str,
         body: &serde_json::Value,
     ) -> PusherResult<BTreeMap<String, String>> {
-        let mut params = BTreeMap::new();
+        self.authenticate_query(method, path, BTreeMap::new(), Some(body))
+    }
+
+    /// Signs a REST request whose query string carries `params` besides the auth parameters,
+    /// such as the `info` of a channel query. GET requests have no `body`, so no `body_md5`
+    /// is added for them.
+    ///
+    /// # Returns
+    ///
+    /// The complete query parameters, including `params` and the `auth_signature`.
+    pub fn authenticate_query(
+        &self,
+        method: &str,
+        path: &str,
+        mut params: BTreeMap<String, String>,
+        body: Option<&serde_json::Value>,
+    ) -> PusherResult<BTreeMap<String, String>> {
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .map_err(|e| PusherError::AuthError(e.to_string()))?
@@ -82,8 +257,10 @@ impl PusherAuth {
         params.insert("auth_timestamp".to_string(), timestamp);
         params.insert("auth_version".to_string(), "1.0".to_string());
 
-        let body_md5 = format!("{:x}", md5::compute(serde_json::to_string(body)?));
-        params.insert("body_md5".to_string(), body_md5);
+        if let Some(body) = body {
+            let body_md5 = format!("{:x}", md5::compute(serde_json::to_string(body)?));
+            params.insert("body_md5".to_string(), body_md5);
+        }
         let to_sign = self.create_signing_string(method, path, &params)?;
 
         let signature = self.sign(&to_sign)?;
@@ -108,6 +285,30 @@ impl PusherAuth {
         self.sign(&to_sign)
     }
 
+    /// Checks that a webhook request comes from Pusher: `X-Pusher-Key` must be this app's key
+    /// and `X-Pusher-Signature` the hex HMAC-SHA256 of the raw body, signed with the secret.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing whether the request is authentic.
+    pub fn verify_webhook(
+        &self,
+        key_header: &str,
+        signature_header: &str,
+        body: &[u8],
+    ) -> PusherResult<bool> {
+        if key_header != self.key {
+            return Ok(false);
+        }
+        let Ok(signature) = hex::decode(signature_header.trim()) else {
+            return Ok(false);
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .map_err(|e| PusherError::AuthError(e.to_string()))?;
+        mac.update(body);
+        Ok(mac.verify_slice(&signature).is_ok())
+    }
+
     fn sign(&self, to_sign: &str) -> PusherResult<String> {
         let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
             .map_err(|e| PusherError::AuthError(e.to_string()))?;
@@ -141,6 +342,23 @@ impl PusherAuth {
     }
 }
 
+/// Socket ids look like `1234.5678`.
+pub(crate) fn is_valid_socket_id(socket_id: &str) -> bool {
+    socket_id.split_once('.').is_some_and(|(a, b)| {
+        [a, b]
+            .iter()
+            .all(|part| !part.is_empty() && part.bytes().all(|c| c.is_ascii_digit()))
+    })
+}
+
+fn validate_socket_id(socket_id: &str) -> PusherResult<()> {
+    if is_valid_socket_id(socket_id) {
+        Ok(())
+    } else {
+        Err(PusherError::AuthError(format!("Invalid socket id '{}'", socket_id)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -164,4 +382,168 @@ mod tests {
         assert!(result.is_ok());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_signing_with_degenerate_keys() {
+        let auth = PusherAuth::new("", "");
+        assert!(auth.authenticate_socket("", "").is_ok());
+        assert!(auth
+            .authenticate_request("POST", "/apps/1/events", &json!({}))
+            .is_ok());
+
+        let auth = PusherAuth::new("key", &"x".repeat(10_000));
+        assert!(auth.authenticate_private_encrypted_channel("1.1", "private-encrypted-a").is_ok());
+    }
+
+    #[test]
+    fn test_authenticate_query_signs_extra_params() {
+        let auth = PusherAuth::new("key", "secret");
+        let query = BTreeMap::from([("info".to_string(), "user_count".to_string())]);
+        let params = auth
+            .authenticate_query("GET", "/apps/1/channels/presence-a", query, None)
+            .unwrap();
+        assert!(!params.contains_key("body_md5"));
+        assert_eq!(params["info"], "user_count");
+
+        let to_sign = format!(
+            "GET\n/apps/1/channels/presence-a\n\
+             auth_key=key&auth_timestamp={}&auth_version=1.0&info=user_count",
+            params["auth_timestamp"]
+        );
+        assert_eq!(params["auth_signature"], auth.sign(&to_sign).unwrap());
+    }
+
+    #[test]
+    fn test_verify_webhook() {
+        let auth = PusherAuth::new("key", "secret");
+        let body = br#"{"time_ms":1,"events":[]}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        assert!(auth.verify_webhook("key", &signature, body).unwrap());
+        assert!(!auth.verify_webhook("other", &signature, body).unwrap());
+        assert!(!auth.verify_webhook("key", &signature, b"{}").unwrap());
+        assert!(!auth.verify_webhook("key", "not hex", body).unwrap());
+    }
+
+    #[test]
+    fn test_authenticate_channel_known_vectors() {
+        // The example app from Pusher's channel authorization docs.
+        let auth = PusherAuth::new("278d425bdf160c739803", "7ad3773142a6692b25b8");
+
+        let response = auth
+            .authenticate_channel("1234.1234", "private-foobar", None)
+            .unwrap();
+        assert_eq!(
+            response.auth,
+            "278d425bdf160c739803:58df8b0c36d6982b82c3ecf6b4662e34fe8c25bba48f5369f135bf843651c3a4"
+        );
+        assert_eq!(response.channel_data, None);
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({"auth": response.auth})
+        );
+
+        let member = PresenceMember {
+            user_id: "10".to_string(),
+            user_info: Some(json!({"name": "Mr. Channels"})),
+        };
+        let response = auth
+            .authenticate_channel("1234.1234", "presence-foobar", Some(&member))
+            .unwrap();
+        assert_eq!(
+            response.channel_data.as_deref(),
+            Some(r#"{"user_id":"10","user_info":{"name":"Mr. Channels"}}"#)
+        );
+        assert_eq!(
+            response.auth,
+            "278d425bdf160c739803:4c6d8fc42a207ba96a0779844171b0bb819d96ffceef9609f5cce596ab17a800"
+        );
+        assert_eq!(
+            response.to_json().unwrap(),
+            format!(
+                r#"{{"auth":"{}","channel_data":{}}}"#,
+                response.auth,
+                json!(response.channel_data.as_deref().unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn test_sign_subscription_vectors() {
+        let auth = PusherAuth::new("278d425bdf160c739803", "7ad3773142a6692b25b8");
+        assert_eq!(
+            auth.sign_subscription("1234.1234", "private-foobar", None).unwrap(),
+            "278d425bdf160c739803:58df8b0c36d6982b82c3ecf6b4662e34fe8c25bba48f5369f135bf843651c3a4"
+        );
+        assert_eq!(
+            auth.sign_subscription(
+                "1234.1234",
+                "presence-foobar",
+                Some(r#"{"user_id":"10","user_info":{"name":"Mr. Channels"}}"#)
+            )
+            .unwrap(),
+            "278d425bdf160c739803:4c6d8fc42a207ba96a0779844171b0bb819d96ffceef9609f5cce596ab17a800"
+        );
+    }
+
+    #[test]
+    fn test_authenticate_channel_rejects_bad_input() {
+        let auth = PusherAuth::new("key", "secret");
+        let member = PresenceMember {
+            user_id: "1".to_string(),
+            user_info: None,
+        };
+        for (socket_id, channel, member) in [
+            ("1234", "private-a", None),
+            ("1234.abc", "private-a", None),
+            ("1.1", "public-a", None),
+            ("1.1", "presence-a", None),
+            ("1.1", "private-a", Some(&member)),
+        ] {
+            assert!(matches!(
+                auth.authenticate_channel(socket_id, channel, member),
+                Err(PusherError::AuthError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_authenticate_user() {
+        let auth = PusherAuth::new("278d425bdf160c739803", "7ad3773142a6692b25b8");
+        let user = UserData {
+            id: "12345".to_string(),
+            user_info: Some(json!({"name": "John Doe"})),
+            watchlist: Some(vec!["friend_1".to_string(), "friend_2".to_string()]),
+        };
+
+        let response = auth.authenticate_user("1234.1234", &user).unwrap();
+        assert_eq!(
+            response.user_data,
+            r#"{"id":"12345","user_info":{"name":"John Doe"},"watchlist":["friend_1","friend_2"]}"#
+        );
+        assert_eq!(
+            response.auth,
+            "278d425bdf160c739803:41b4a3674d0588dafd060b870f2cfe936aa4dd497d4c9689503a89853ebd2be6"
+        );
+
+        let user = UserData {
+            id: "12345".to_string(),
+            user_info: None,
+            watchlist: None,
+        };
+        let response = auth.authenticate_user("1234.1234", &user).unwrap();
+        assert_eq!(response.user_data, r#"{"id":"12345"}"#);
+        assert_eq!(
+            response.auth,
+            "278d425bdf160c739803:4708d583dada6a56435fb8bc611c77c359a31eebde13337c16ab43aa6de336ba"
+        );
+
+        let anonymous = UserData {
+            id: String::new(),
+            ..user
+        };
+        assert!(auth.authenticate_user("1234.1234", &anonymous).is_err());
+        assert!(auth.authenticate_user("invalid", &UserData { id: "1".into(), ..anonymous }).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The state of a channel as reported by the REST API. Attributes that weren't requested
+/// through `info` are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    /// Whether anyone is subscribed to the channel.
+    #[serde(default)]
+    pub occupied: bool,
+    /// The number of distinct users on a presence channel.
+    #[serde(default)]
+    pub user_count: Option<u64>,
+    /// The number of connections subscribed, if subscription counting is enabled for the app.
+    #[serde(default)]
+    pub subscription_count: Option<u64>,
+}
+
+/// The occupied channels of an app, as returned by `PusherClient::get_channels`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelList {
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelInfo>,
+}
+
+/// The attributes returned by `PusherClient::trigger_with_info`, keyed by channel. The
+/// response doesn't say whether a channel is occupied, so `occupied` is always false; check
+/// `subscription_count` instead.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TriggerResponse {
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelInfo>,
+}
+
+/// The users of a presence channel, as returned by the REST API.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChannelUsers {
+    pub(crate) users: Vec<ChannelUser>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChannelUser {
+    pub(crate) id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_channel_responses() {
+        let info: ChannelInfo =
+            serde_json::from_str(r#"{"occupied": true, "user_count": 3}"#).unwrap();
+        assert!(info.occupied);
+        assert_eq!(info.user_count, Some(3));
+        assert_eq!(info.subscription_count, None);
+
+        let list: ChannelList = serde_json::from_str(
+            r#"{"channels": {"presence-a": {"user_count": 2}, "presence-b": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(list.channels.len(), 2);
+        assert_eq!(list.channels["presence-a"].user_count, Some(2));
+        assert_eq!(list.channels["presence-b"], ChannelInfo::default());
+    }
+}
@@ -1,8 +1,12 @@
-use crate::error::PusherResult;
+use crate::error::{PusherError, PusherResult};
+use crate::events::Event;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// The longest channel name Pusher accepts.
+pub const MAX_CHANNEL_NAME_LEN: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ChannelType {
     Public,
     Private,
@@ -10,36 +14,119 @@ pub enum ChannelType {
     PrivateEncrypted,
 }
 
+impl ChannelType {
+    /// Classifies a channel by the prefix of its name.
+    pub fn from_name(name: &str) -> Self {
+        if name.starts_with("private-encrypted-") {
+            ChannelType::PrivateEncrypted
+        } else if name.starts_with("private-") {
+            ChannelType::Private
+        } else if name.starts_with("presence-") {
+            ChannelType::Presence
+        } else {
+            ChannelType::Public
+        }
+    }
+}
+
+/// A member of a presence channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceMember {
+    pub user_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_info: Option<serde_json::Value>,
+}
+
+/// Where a channel subscription is, as confirmed by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    /// The subscribe frame was sent, but the server hasn't answered yet.
+    Pending,
+    /// The server confirmed the subscription with `subscription_succeeded`.
+    Subscribed,
+    /// The server rejected the subscription with `subscription_error`.
+    Failed,
+}
+
+/// How the members of a presence channel changed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemberDiff {
+    /// Members that joined, sorted by user id.
+    pub added: Vec<PresenceMember>,
+    /// The user ids of members that left, sorted.
+    pub removed: Vec<String>,
+}
+
+impl MemberDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Channel {
     name: String,
     channel_type: ChannelType,
-    subscribed: bool,
+    state: SubscriptionState,
     members: Option<HashMap<String, serde_json::Value>>,
+    subscription_count: Option<usize>,
+    last_event: Option<Event>,
+    presence_user: Option<PresenceMember>,
+    user_id: Option<String>,
+    authorized: bool,
+    counts_requested: bool,
 }
 
 impl Channel {
     pub fn new(name: &str) -> Self {
-        let channel_type = if name.starts_with("private-encrypted-") {
-            ChannelType::PrivateEncrypted
-        } else if name.starts_with("private-") {
-            ChannelType::Private
-        } else if name.starts_with("presence-") {
-            ChannelType::Presence
-        } else {
-            ChannelType::Public
-        };
+        let channel_type = ChannelType::from_name(name);
 
         Self {
             name: name.to_string(),
             channel_type: channel_type.clone(),
-            subscribed: false,
+            state: SubscriptionState::Pending,
             members: if channel_type == ChannelType::Presence {
                 Some(HashMap::new())
             } else {
                 None
             },
+            subscription_count: None,
+            last_event: None,
+            presence_user: None,
+            user_id: None,
+            authorized: false,
+            counts_requested: false,
+        }
+    }
+
+    /// Checks that `name` is a channel name Pusher accepts: at most
+    /// `MAX_CHANNEL_NAME_LEN` characters from `a-zA-Z0-9_-=@,.;`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PusherError::ChannelError` naming the first offending character and its
+    /// position, or the length.
+    pub fn validate_name(name: &str) -> PusherResult<()> {
+        if name.is_empty() {
+            return Err(PusherError::ChannelError("Channel name is empty".to_string()));
+        }
+        let len = name.chars().count();
+        if len > MAX_CHANNEL_NAME_LEN {
+            return Err(PusherError::ChannelError(format!(
+                "Channel name is {} characters long, the maximum is {}",
+                len, MAX_CHANNEL_NAME_LEN
+            )));
+        }
+        let invalid = name.chars().enumerate().find(|(_, c)| {
+            !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '=' | '@' | ',' | '.' | ';'))
+        });
+        if let Some((position, c)) = invalid {
+            return Err(PusherError::ChannelError(format!(
+                "Invalid character {:?} at position {} in channel name '{}'",
+                c, position, name
+            )));
         }
+        Ok(())
     }
 
     pub fn name(&self) -> &str {
@@ -51,11 +138,23 @@ impl Channel {
     }
 
     pub fn is_subscribed(&self) -> bool {
-        self.subscribed
+        self.state == SubscriptionState::Subscribed
     }
 
     pub fn set_subscribed(&mut self, subscribed: bool) {
-        self.subscribed = subscribed;
+        self.state = if subscribed {
+            SubscriptionState::Subscribed
+        } else {
+            SubscriptionState::Pending
+        };
+    }
+
+    pub fn subscription_state(&self) -> SubscriptionState {
+        self.state
+    }
+
+    pub fn set_subscription_state(&mut self, state: SubscriptionState) {
+        self.state = state;
     }
 
     pub fn members(&self) -> Option<&HashMap<String, serde_json::Value>> {
@@ -80,6 +179,31 @@ impl Channel {
         }
     }
 
+    /// Replaces the members with a fresh roster, such as the one in `subscription_succeeded`
+    /// after reconnecting, and returns how it differs from the previous one.
+    pub fn replace_members(&mut self, members: HashMap<String, serde_json::Value>) -> MemberDiff {
+        let Some(current) = &mut self.members else {
+            return MemberDiff::default();
+        };
+        let mut added: Vec<PresenceMember> = members
+            .iter()
+            .filter(|(id, _)| !current.contains_key(*id))
+            .map(|(id, info)| PresenceMember {
+                user_id: id.clone(),
+                user_info: Some(info.clone()).filter(|info| !info.is_null()),
+            })
+            .collect();
+        let mut removed: Vec<String> = current
+            .keys()
+            .filter(|id| !members.contains_key(*id))
+            .cloned()
+            .collect();
+        added.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        removed.sort();
+        *current = members;
+        MemberDiff { added, removed }
+    }
+
     pub fn clear_members(&mut self) {
         if let Some(members) = &mut self.members {
             members.clear();
@@ -89,6 +213,73 @@ impl Channel {
     pub fn member_count(&self) -> usize {
         self.members.as_ref().map_or(0, |m| m.len())
     }
+
+    /// The latest subscriber count reported by a `pusher_internal:subscription_count` event.
+    pub fn subscription_count(&self) -> Option<usize> {
+        self.subscription_count
+    }
+
+    pub fn set_subscription_count(&mut self, count: usize) {
+        self.subscription_count = Some(count);
+    }
+
+    /// The most recent event received on this channel, if last-event tracking is enabled.
+    pub fn last_event(&self) -> Option<&Event> {
+        self.last_event.as_ref()
+    }
+
+    pub fn set_last_event(&mut self, event: Event) {
+        self.last_event = Some(event);
+    }
+
+    /// Whether the subscription was sent with an auth token, so it is authorized again for a
+    /// new socket after reconnecting.
+    pub(crate) fn is_authorized(&self) -> bool {
+        self.authorized
+    }
+
+    pub(crate) fn set_authorized(&mut self) {
+        self.authorized = true;
+    }
+
+    /// Whether the subscription asked the server for subscription counts, so it asks again
+    /// after reconnecting.
+    pub(crate) fn counts_requested(&self) -> bool {
+        self.counts_requested
+    }
+
+    pub(crate) fn set_counts_requested(&mut self) {
+        self.counts_requested = true;
+    }
+
+    /// The member data this client subscribed to the presence channel with. It is kept so the
+    /// membership can be signed again for a new socket after reconnecting.
+    pub fn presence_user(&self) -> Option<&PresenceMember> {
+        self.presence_user.as_ref()
+    }
+
+    pub fn set_presence_user(&mut self, member: PresenceMember) {
+        self.user_id = Some(member.user_id.clone());
+        self.presence_user = Some(member);
+    }
+
+    /// Sets the user ID this client subscribed to the presence channel as, for `me`.
+    pub(crate) fn set_user_id(&mut self, user_id: String) {
+        self.user_id = Some(user_id);
+    }
+
+    /// This client's own entry in the members, like `members.me` in pusher-js. The server
+    /// doesn't send it separately, so it is looked up by the user ID in the `channel_data`
+    /// this client subscribed with. `None` until the subscription succeeds, and for channels
+    /// that aren't presence channels.
+    pub fn me(&self) -> Option<PresenceMember> {
+        let user_id = self.user_id.as_ref()?;
+        let user_info = self.members.as_ref()?.get(user_id)?;
+        Some(PresenceMember {
+            user_id: user_id.clone(),
+            user_info: Some(user_info.clone()),
+        })
+    }
 }
 
 #[allow(dead_code)]
@@ -142,3 +333,89 @@ impl Default for ChannelList {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_type_from_name() {
+        assert_eq!(ChannelType::from_name("my-channel"), ChannelType::Public);
+        assert_eq!(ChannelType::from_name("private-channel"), ChannelType::Private);
+        assert_eq!(ChannelType::from_name("presence-room"), ChannelType::Presence);
+        assert_eq!(
+            ChannelType::from_name("private-encrypted-secrets"),
+            ChannelType::PrivateEncrypted
+        );
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(Channel::validate_name("private-encrypted-a_b=c@d,e.f;g-1").is_ok());
+        assert!(Channel::validate_name(&"a".repeat(MAX_CHANNEL_NAME_LEN)).is_ok());
+
+        let message = |name: &str| match Channel::validate_name(name) {
+            Err(PusherError::ChannelError(message)) => message,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        assert_eq!(
+            message("my channel"),
+            "Invalid character ' ' at position 2 in channel name 'my channel'"
+        );
+        assert!(message("café").contains("'é' at position 3"));
+        assert!(message(&"a".repeat(MAX_CHANNEL_NAME_LEN + 1)).contains("201 characters"));
+        assert_eq!(message(""), "Channel name is empty");
+    }
+
+    #[test]
+    fn test_replace_members_diff() {
+        let mut channel = Channel::new("presence-room");
+        let roster = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| (id.to_string(), serde_json::json!({ "name": id })))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let diff = channel.replace_members(roster(&["a", "b"]));
+        assert_eq!(diff.added.len(), 2);
+        assert!(diff.removed.is_empty());
+
+        let diff = channel.replace_members(roster(&["b", "c"]));
+        assert_eq!(
+            diff.added,
+            vec![PresenceMember {
+                user_id: "c".to_string(),
+                user_info: Some(serde_json::json!({"name": "c"})),
+            }]
+        );
+        assert_eq!(diff.removed, vec!["a".to_string()]);
+        assert_eq!(channel.member_count(), 2);
+
+        assert!(channel.replace_members(roster(&["b", "c"])).is_empty());
+        assert!(Channel::new("private-room")
+            .replace_members(roster(&["a"]))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_me() {
+        let mut channel = Channel::new("presence-room");
+        channel.set_user_id("a".to_string());
+        assert_eq!(channel.me(), None);
+
+        let members = HashMap::from([
+            ("a".to_string(), serde_json::json!({"name": "Alice"})),
+            ("b".to_string(), serde_json::json!({"name": "Bob"})),
+        ]);
+        channel.replace_members(members);
+        assert_eq!(
+            channel.me(),
+            Some(PresenceMember {
+                user_id: "a".to_string(),
+                user_info: Some(serde_json::json!({"name": "Alice"})),
+            })
+        );
+        channel.clear_members();
+        assert_eq!(channel.me(), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CircuitBreakerConfig;
+use crate::error::{PusherError, PusherResult};
+
+/// The state of the REST circuit breaker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CircuitState {
+    /// Requests flow normally.
+    Closed,
+    /// Requests fail fast until the cooldown has elapsed.
+    Open,
+    /// The cooldown has elapsed; the next request decides whether the circuit closes again,
+    /// while the others keep failing fast until it does.
+    HalfOpen,
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// Whether the trial request of the half-open circuit is still waiting for its result.
+    trial_in_flight: bool,
+}
+
+/// Fast-fails REST requests after repeated failures, so a down service isn't hammered.
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.config.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns `PusherError::CircuitOpen` if requests should not be attempted right now.
+    ///
+    /// Once the cooldown has elapsed, only one trial request is let through at a time; the
+    /// others fail with a zero wait until its result is recorded, or the returned permit is
+    /// dropped without one.
+    pub fn check(&self) -> PusherResult<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let mut trial = false;
+        if let Some(opened_at) = state.opened_at {
+            let elapsed = opened_at.elapsed();
+            if elapsed < self.config.cooldown {
+                return Err(PusherError::CircuitOpen(self.config.cooldown - elapsed));
+            }
+            if state.trial_in_flight {
+                return Err(PusherError::CircuitOpen(Duration::ZERO));
+            }
+            state.trial_in_flight = true;
+            trial = true;
+        }
+        Ok(Permit {
+            breaker: self,
+            trial,
+        })
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+        state.trial_in_flight = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        state.trial_in_flight = false;
+        // A failed trial request while half-open re-opens the circuit straight away.
+        if state.opened_at.is_some() || state.consecutive_failures >= self.config.failure_threshold
+        {
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Lets a request through the breaker, and records its result.
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    /// Whether this is the trial request of the half-open circuit.
+    trial: bool,
+}
+
+impl Permit<'_> {
+    pub fn record_success(mut self) {
+        self.trial = false;
+        self.breaker.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.trial = false;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    /// A trial request that was cancelled decides nothing, so the next one gets to try.
+    fn drop(&mut self) {
+        if self.trial {
+            self.breaker.state.lock().unwrap().trial_in_flight = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold,
+            cooldown,
+        })
+    }
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = breaker(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(matches!(breaker.check(), Err(PusherError::CircuitOpen(_))));
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_after_cooldown() {
+        let breaker = breaker(1, Duration::from_millis(20));
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.check().is_ok());
+
+        // A failed trial re-opens the circuit, a successful one closes it.
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        std::thread::sleep(Duration::from_millis(30));
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_admits_one_trial() {
+        let breaker = breaker(1, Duration::from_millis(20));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(30));
+
+        let trial = breaker.check().unwrap();
+        assert!(matches!(breaker.check(), Err(PusherError::CircuitOpen(wait)) if wait.is_zero()));
+        // A cancelled trial lets the next request try instead.
+        drop(trial);
+        let trial = breaker.check().unwrap();
+        assert!(breaker.check().is_err());
+        trial.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        breaker.check().unwrap().record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        let _first = breaker.check().unwrap();
+        assert!(breaker.check().is_ok());
+    }
+}
@@ -1,8 +1,6 @@
 use thiserror::Error;
 use std::io;
 use url::ParseError;
-use reqwest;
-use serde_json;
 
 /// Specific errors that can occur when interacting with the Pusher API.
 /// TODO: Add more specific errors
@@ -32,15 +30,20 @@ pub enum PusherError {
     #[error("Event error: {0}")]
     EventError(String),
 
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
     #[error("Connection error: {0}")]
     ConnectionError(String),
 
+    /// `host` couldn't be resolved. This is usually temporary, e.g. while a mobile device
+    /// switches networks.
+    #[error("DNS resolution failed for '{host}': {message}")]
+    DnsError { host: String, message: String },
+
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
-    #[error("Rate limit error: {0}")]
-    RateLimitError(String),
-
     #[error("Encryption error: {0}")]
     EncryptionError(String),
 
@@ -50,12 +53,79 @@ pub enum PusherError {
     #[error("Presence data error: {0}")]
     PresenceDataError(String),
 
-    #[error("API error: {0}")]
-    ApiError(String),
+    /// The REST API answered with an error status. `code` is the Pusher error code, if the
+    /// response body carried one.
+    #[error(
+        "API error (status {status}{}): {message}",
+        code.map(|code| format!(", code {}", code)).unwrap_or_default()
+    )]
+    ApiError {
+        status: u16,
+        code: Option<u32>,
+        message: String,
+    },
 
     #[error("Timeout error: {0}")]
     TimeoutError(String),
 
+    /// The REST API answered `429 Too Many Requests` and the retries, if any, are used up or
+    /// it asked to wait longer than `PusherConfig::http_max_retry_after`. `retry_after` comes
+    /// from the `Retry-After` header.
+    #[error("Rate limited, retry in {retry_after:?}")]
+    RateLimited { retry_after: std::time::Duration },
+
+    #[error("Circuit breaker open, retry in {0:?}")]
+    CircuitOpen(std::time::Duration),
+
+    #[error(
+        "Subscription to '{channel}' failed{}: {message}",
+        status.map(|status| format!(" with status {}", status)).unwrap_or_default()
+    )]
+    SubscriptionError {
+        channel: String,
+        status: Option<u16>,
+        message: String,
+    },
+
+    /// The server refused a presence subscription because the channel already has as many
+    /// members as it allows, `MAX_PRESENCE_MEMBERS` on Pusher.
+    #[error("Presence channel '{channel}' is full: {message}")]
+    MemberLimitReached { channel: String, message: String },
+
+    #[error("Failed to deserialize data of event '{event}': {source} (data: {data})")]
+    DeserializeError {
+        event: String,
+        /// The raw event data, truncated to keep errors readable.
+        data: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("Server over capacity: {0}")]
+    OverCapacity(String),
+
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
+    /// A `pusher:error` the server sent over the connection, with its Pusher error code.
+    #[error("Pusher error {code}: {message}")]
+    PusherProtocolError { code: u16, message: String },
+
+    /// The server closed the connection with a Pusher close code. Codes 4000-4099 mean the
+    /// client must not reconnect, 4100-4199 that it should back off first and 4200-4299 that
+    /// it may reconnect right away.
+    #[error("Connection closed by the server with code {code}: {reason}")]
+    ConnectionClosed { code: u16, reason: String },
+
+    #[error("Event data is {size} bytes, more than the limit of {limit}")]
+    PayloadTooLarge { size: usize, limit: usize },
+
+    #[error("{} trigger(s) failed: {}", .0.len(), .0.join("; "))]
+    TriggersFailed(Vec<String>),
+
+    #[error("Realtime features are disabled for this client")]
+    RealtimeDisabled,
+
     #[error("Unknown error: {0}")]
     UnknownError(String),
 }
@@ -72,6 +142,77 @@ impl From<&str> for PusherError {
     }
 }
 
+impl PusherError {
+    /// Whether this is a connection failure caused by DNS resolution, a `DnsError`.
+    pub fn is_dns_failure(&self) -> bool {
+        matches!(self, PusherError::DnsError { .. })
+    }
+
+    /// A copy of this error, for keeping it as well as returning it. Errors wrapping a
+    /// source that can't be copied, which connecting doesn't produce, become a
+    /// `ConnectionError` with the same message.
+    pub(crate) fn duplicate(&self) -> PusherError {
+        use PusherError::*;
+        match self {
+            WebSocketError(message) => WebSocketError(message.clone()),
+            IoError(e) => IoError(io::Error::new(e.kind(), e.to_string())),
+            UrlParseError(e) => UrlParseError(*e),
+            AuthError(message) => AuthError(message.clone()),
+            ChannelError(message) => ChannelError(message.clone()),
+            EventError(message) => EventError(message.clone()),
+            InvalidArgument(message) => InvalidArgument(message.clone()),
+            ConnectionError(message) => ConnectionError(message.clone()),
+            DnsError { host, message } => DnsError {
+                host: host.clone(),
+                message: message.clone(),
+            },
+            ConfigError(message) => ConfigError(message.clone()),
+            EncryptionError(message) => EncryptionError(message.clone()),
+            DecryptionError(message) => DecryptionError(message.clone()),
+            PresenceDataError(message) => PresenceDataError(message.clone()),
+            ApiError { status, code, message } => ApiError {
+                status: *status,
+                code: *code,
+                message: message.clone(),
+            },
+            TimeoutError(message) => TimeoutError(message.clone()),
+            RateLimited { retry_after } => RateLimited {
+                retry_after: *retry_after,
+            },
+            CircuitOpen(retry_in) => CircuitOpen(*retry_in),
+            SubscriptionError { channel, status, message } => SubscriptionError {
+                channel: channel.clone(),
+                status: *status,
+                message: message.clone(),
+            },
+            MemberLimitReached { channel, message } => MemberLimitReached {
+                channel: channel.clone(),
+                message: message.clone(),
+            },
+            OverCapacity(message) => OverCapacity(message.clone()),
+            ProtocolError(message) => ProtocolError(message.clone()),
+            PusherProtocolError { code, message } => PusherProtocolError {
+                code: *code,
+                message: message.clone(),
+            },
+            ConnectionClosed { code, reason } => ConnectionClosed {
+                code: *code,
+                reason: reason.clone(),
+            },
+            PayloadTooLarge { size, limit } => PayloadTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            TriggersFailed(failures) => TriggersFailed(failures.clone()),
+            RealtimeDisabled => RealtimeDisabled,
+            UnknownError(message) => UnknownError(message.clone()),
+            HttpError(_) | JsonError(_) | DeserializeError { .. } => {
+                ConnectionError(self.to_string())
+            }
+        }
+    }
+}
+
 /// A specialized Result type for Pusher operations.
 pub type PusherResult<T> = Result<T, PusherError>;
 
@@ -101,10 +242,6 @@ pub mod test_helpers {
         PusherError::ConfigError(message.into())
     }
 
-    pub fn rate_limit_error(message: impl Into<String>) -> PusherError {
-        PusherError::RateLimitError(message.into())
-    }
-
     pub fn encryption_error(message: impl Into<String>) -> PusherError {
         PusherError::EncryptionError(message.into())
     }
@@ -117,8 +254,12 @@ pub mod test_helpers {
         PusherError::PresenceDataError(message.into())
     }
 
-    pub fn api_error(message: impl Into<String>) -> PusherError {
-        PusherError::ApiError(message.into())
+    pub fn api_error(status: u16, message: impl Into<String>) -> PusherError {
+        PusherError::ApiError {
+            status,
+            code: None,
+            message: message.into(),
+        }
     }
 
     pub fn timeout_error(message: impl Into<String>) -> PusherError {
@@ -146,6 +287,33 @@ mod tests {
 
         let err = PusherError::ChannelError("Channel not found".to_string());
         assert_eq!(err.to_string(), "Channel error: Channel not found");
+
+        let err = PusherError::SubscriptionError {
+            channel: "private-a".to_string(),
+            status: Some(403),
+            message: "Forbidden".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Subscription to 'private-a' failed with status 403: Forbidden"
+        );
+
+        let err = PusherError::MemberLimitReached {
+            channel: "presence-a".to_string(),
+            message: "Too many members".to_string(),
+        };
+        assert_eq!(err.to_string(), "Presence channel 'presence-a' is full: Too many members");
+
+        let err = PusherError::ApiError {
+            status: 429,
+            code: Some(4290),
+            message: "Too many requests".to_string(),
+        };
+        assert_eq!(err.to_string(), "API error (status 429, code 4290): Too many requests");
+        assert_eq!(
+            test_helpers::api_error(403, "Forbidden").to_string(),
+            "API error (status 403): Forbidden"
+        );
     }
 
     #[test]
@@ -1,9 +1,37 @@
-use serde::{Serialize, Deserialize};
+use serde::{de::DeserializeOwned, Deserializer, Serialize, Deserialize};
 use serde_json::Value;
 
+use crate::error::{PusherError, PusherResult};
+
+/// How much of the raw data is kept in a `PusherError::DeserializeError`, and of a malformed
+/// frame in the `PusherError::ProtocolError` reporting it.
+pub(crate) const MAX_ERROR_DATA_LEN: usize = 200;
+
+/// The longest event name Pusher accepts.
+const MAX_EVENT_NAME_LEN: usize = 200;
+
+/// Checks that `name` is an event name Pusher accepts, returning `PusherError::EventError`
+/// for an empty or overlong name.
+pub(crate) fn validate_event_name(name: &str) -> PusherResult<()> {
+    let len = name.chars().count();
+    if len == 0 {
+        return Err(PusherError::EventError("Event name is empty".to_string()));
+    }
+    if len > MAX_EVENT_NAME_LEN {
+        return Err(PusherError::EventError(format!(
+            "Event name is {} characters long, the maximum is {}",
+            len, MAX_EVENT_NAME_LEN
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub event: String,
+    /// The event's payload as a JSON string. Frames whose `data` is an object or any other
+    /// JSON value rather than a string get it re-serialized, so handlers see one shape.
+    #[serde(deserialize_with = "deserialize_data")]
     pub data: String,
     pub channel: Option<String>,
 }
@@ -18,10 +46,82 @@ impl Event {
     }
 
     pub fn is_system_event(&self) -> bool {
-        self.event.starts_with("pusher:")
+        self.event.starts_with("pusher:") || self.event.starts_with("pusher_internal:")
+    }
+
+    /// Parses `data`, which Pusher sends as a JSON string, into `T`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PusherError::JsonError` if `data` is not valid JSON for `T`.
+    pub fn json<T: DeserializeOwned>(&self) -> PusherResult<T> {
+        Ok(serde_json::from_str(&self.data)?)
+    }
+
+    /// Parses `data` into `T`, reporting the event name and data on failure.
+    pub(crate) fn parse_data<T: DeserializeOwned>(&self) -> PusherResult<T> {
+        serde_json::from_str(&self.data).map_err(|source| PusherError::DeserializeError {
+            event: self.event.clone(),
+            data: truncate(&self.data, MAX_ERROR_DATA_LEN),
+            source,
+        })
     }
 }
 
+/// Rejects JSON nested more than `max_depth` arrays or objects deep.
+///
+/// This only scans the text, so it is safe to run on untrusted input before parsing it.
+pub(crate) fn check_json_depth(text: &str, max_depth: usize) -> PusherResult<()> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in text.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(PusherError::ProtocolError(format!(
+                        "JSON nesting exceeds the maximum depth of {}",
+                        max_depth
+                    )));
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Keeps a string `data` as is and serializes any other JSON value to a string.
+fn deserialize_data<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(data) => data,
+        other => other.to_string(),
+    })
+}
+
+pub(crate) fn truncate(data: &str, max_len: usize) -> String {
+    if data.len() <= max_len {
+        return data.to_string();
+    }
+    let mut end = max_len;
+    while !data.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &data[..end])
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SystemEvent {
     pub event: String,
@@ -32,8 +132,10 @@ pub struct SystemEvent {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum SystemEventData {
+    /// `activity_timeout` is in seconds, and 0 if the server didn't send one.
     ConnectionEstablished {
         socket_id: String,
+        #[serde(default)]
         activity_timeout: u64,
     },
     SubscriptionSucceeded {
@@ -47,16 +149,36 @@ pub enum SystemEventData {
     MemberRemoved {
         user_id: String,
     },
+    SubscriptionCount {
+        subscription_count: u64,
+    },
     Error {
         code: Option<u32>,
         message: String,
     },
+    /// Why a subscription was rejected. `message` is the `error` field, falling back to the
+    /// `message` or `type` field or the whole data.
+    SubscriptionError {
+        #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+        error_type: Option<String>,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+    },
+    Ping,
+    Pong,
+    /// A system event without a typed payload, holding its raw data.
+    Other(String),
 }
 
+/// The roster in a presence channel's `subscription_succeeded`. Only `hash` is needed to
+/// know the members, so `count` and `ids` may be left out.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PresenceData {
+    #[serde(default)]
     pub count: u32,
     pub hash: std::collections::HashMap<String, Value>,
+    #[serde(default)]
     pub ids: Vec<String>,
 }
 
@@ -104,6 +226,14 @@ impl SystemEvent {
         )
     }
 
+    pub fn subscription_count(channel: String, subscription_count: u64) -> Self {
+        Self::new(
+            "pusher:subscription_count".to_string(),
+            Some(channel),
+            SystemEventData::SubscriptionCount { subscription_count },
+        )
+    }
+
     pub fn error(code: Option<u32>, message: String) -> Self {
         Self::new(
             "pusher:error".to_string(),
@@ -113,6 +243,121 @@ impl SystemEvent {
     }
 }
 
+impl Event {
+    pub fn is_presence_event(&self) -> bool {
+        matches!(self.event.as_str(), "pusher:member_added" | "pusher:member_removed")
+    }
+
+    pub fn is_subscription_event(&self) -> bool {
+        self.event == "pusher:subscription_succeeded" || self.event == "pusher:subscription_error"
+    }
+
+    pub fn as_system_event(&self) -> Option<SystemEvent> {
+        SystemEvent::from_event(self)
+    }
+}
+
+impl SystemEvent {
+    pub fn is_presence_event(&self) -> bool {
+        matches!(self.event.as_str(), "pusher:member_added" | "pusher:member_removed")
+    }
+
+    pub fn is_subscription_event(&self) -> bool {
+        self.event == "pusher:subscription_succeeded" || self.event == "pusher:subscription_error"
+    }
+
+    /// Parses a `pusher:` or `pusher_internal:` event into its typed form.
+    ///
+    /// The payload type is picked by event name, so both `pusher:member_added` and
+    /// `pusher_internal:member_added` give `SystemEventData::MemberAdded`. Events that aren't
+    /// modelled, or whose data doesn't have the expected shape, give `SystemEventData::Other`.
+    ///
+    /// Returns `None` for events that aren't system events.
+    pub fn from_event(event: &Event) -> Option<Self> {
+        let (_, name) = event
+            .event
+            .split_once(':')
+            .filter(|_| event.is_system_event())?;
+        let data = parse_system_data(name, &event.data)
+            .unwrap_or_else(|| SystemEventData::Other(event.data.clone()));
+        Some(Self::new(event.event.clone(), event.channel.clone(), data))
+    }
+
+    pub fn as_event(&self) -> Event {
+        Event {
+            event: self.event.clone(),
+            channel: self.channel.clone(),
+            data: serde_json::to_string(&self.data).unwrap_or_default(),
+        }
+    }
+}
+
+fn parse_system_data(name: &str, data: &str) -> Option<SystemEventData> {
+    let value: Value = if data.trim().is_empty() {
+        Value::Object(Default::default())
+    } else {
+        serde_json::from_str(data).ok()?
+    };
+    // User ids may be sent as numbers.
+    let id_field = |key: &str| match value.get(key)? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    };
+
+    Some(match name {
+        "connection_established" => SystemEventData::ConnectionEstablished {
+            socket_id: value.get("socket_id")?.as_str()?.to_string(),
+            activity_timeout: value
+                .get("activity_timeout")
+                .and_then(Value::as_u64)
+                .unwrap_or_default(),
+        },
+        "subscription_succeeded" => SystemEventData::SubscriptionSucceeded {
+            presence: match value.get("presence") {
+                Some(presence) => Some(serde_json::from_value(presence.clone()).ok()?),
+                None => None,
+            },
+        },
+        "member_added" => SystemEventData::MemberAdded {
+            user_id: id_field("user_id")?,
+            user_info: value.get("user_info").cloned().unwrap_or(Value::Null),
+        },
+        "member_removed" => SystemEventData::MemberRemoved {
+            user_id: id_field("user_id")?,
+        },
+        "subscription_count" => SystemEventData::SubscriptionCount {
+            subscription_count: value.get("subscription_count")?.as_u64()?,
+        },
+        "error" => SystemEventData::Error {
+            code: value
+                .get("code")
+                .and_then(Value::as_u64)
+                .and_then(|code| u32::try_from(code).ok()),
+            message: value
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        },
+        "subscription_error" => SystemEventData::SubscriptionError {
+            error_type: value.get("type").and_then(Value::as_str).map(str::to_string),
+            message: ["error", "message", "type"]
+                .iter()
+                .find_map(|key| value.get(*key).and_then(Value::as_str))
+                .unwrap_or(data)
+                .to_string(),
+            status: value
+                .get("status")
+                .and_then(Value::as_u64)
+                .and_then(|status| u16::try_from(status).ok()),
+        },
+        "ping" => SystemEventData::Ping,
+        "pong" => SystemEventData::Pong,
+        _ => return None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,6 +379,19 @@ mod tests {
         assert_eq!(event.data, deserialized.data);
     }
 
+    #[test]
+    fn test_event_data_shapes() {
+        let parse = |frame: &str| serde_json::from_str::<Event>(frame).unwrap().data;
+        assert_eq!(parse(r#"{"event":"e","data":"{\"a\":1}"}"#), r#"{"a":1}"#);
+        assert_eq!(parse(r#"{"event":"e","data":"plain text"}"#), "plain text");
+        assert_eq!(parse(r#"{"event":"e","data":{"a":[1,2]}}"#), r#"{"a":[1,2]}"#);
+        assert_eq!(parse(r#"{"event":"e","data":[1,"x"]}"#), r#"[1,"x"]"#);
+        assert_eq!(parse(r#"{"event":"e","data":5}"#), "5");
+
+        let event: Event = serde_json::from_str(r#"{"event":"e","data":{"text":"hi"}}"#).unwrap();
+        assert_eq!(event.json::<Value>().unwrap(), json!({"text": "hi"}));
+    }
+
     #[test]
     fn test_system_event_connection_established() {
         let event = SystemEvent::connection_established("socket123".to_string(), 120);
@@ -222,40 +480,165 @@ mod tests {
             panic!("Unexpected event data");
         }
     }
-}
 
-impl Event {
-    pub fn is_presence_event(&self) -> bool {
-        matches!(self.event.as_str(), "pusher:member_added" | "pusher:member_removed")
-    }
+    #[test]
+    fn test_json() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Message {
+            text: String,
+        }
 
-    pub fn is_subscription_event(&self) -> bool {
-        self.event == "pusher:subscription_succeeded" || self.event == "pusher:subscription_error"
+        let event = Event::new("chat".to_string(), None, r#"{"text": "hi"}"#.to_string());
+        assert_eq!(event.json::<Message>().unwrap(), Message { text: "hi".to_string() });
+        let value: serde_json::Value = event.json().unwrap();
+        assert_eq!(value["text"], "hi");
+
+        let event = Event::new("chat".to_string(), None, r#"{"text": 5}"#.to_string());
+        assert!(matches!(event.json::<Message>(), Err(PusherError::JsonError(_))));
     }
 
-    pub fn as_system_event(&self) -> Option<SystemEvent> {
-        if self.is_system_event() {
-            serde_json::from_value(serde_json::to_value(self).unwrap()).ok()
+    #[test]
+    fn test_parse_data_error_context() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Message {
+            text: String,
+        }
+
+        let event = Event::new("chat".to_string(), None, r#"{"text": 5}"#.to_string());
+        match event.parse_data::<Message>() {
+            Err(PusherError::DeserializeError { event, data, .. }) => {
+                assert_eq!(event, "chat");
+                assert_eq!(data, r#"{"text": 5}"#);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let event = Event::new("chat".to_string(), None, "é".repeat(150));
+        if let Err(PusherError::DeserializeError { data, .. }) = event.parse_data::<Message>() {
+            assert!(data.len() <= MAX_ERROR_DATA_LEN + 3);
+            assert!(data.ends_with("..."));
         } else {
-            None
+            panic!("Expected a deserialize error");
         }
     }
-}
 
-impl SystemEvent {
-    pub fn is_presence_event(&self) -> bool {
-        matches!(self.event.as_str(), "pusher:member_added" | "pusher:member_removed")
-    }
+    #[test]
+    fn test_system_event_from_event() {
+        let parse = |name: &str, data: &str| {
+            let event = Event::new(name.to_string(), Some("presence-room".to_string()), data.to_string());
+            SystemEvent::from_event(&event).unwrap().data
+        };
 
-    pub fn is_subscription_event(&self) -> bool {
-        self.event == "pusher:subscription_succeeded" || self.event == "pusher:subscription_error"
+        match parse(
+            "pusher:connection_established",
+            r#"{"socket_id":"1.2","activity_timeout":30}"#,
+        ) {
+            SystemEventData::ConnectionEstablished { socket_id, activity_timeout } => {
+                assert_eq!(socket_id, "1.2");
+                assert_eq!(activity_timeout, 30);
+            }
+            other => panic!("Unexpected data: {:?}", other),
+        }
+        assert!(matches!(
+            parse("pusher:connection_established", r#"{"socket_id":"1.2"}"#),
+            SystemEventData::ConnectionEstablished { activity_timeout: 0, .. }
+        ));
+
+        match parse(
+            "pusher:subscription_error",
+            r#"{"type":"AuthError","error":"Invalid signature","status":401}"#,
+        ) {
+            SystemEventData::SubscriptionError { error_type, message, status } => {
+                assert_eq!(error_type.as_deref(), Some("AuthError"));
+                assert_eq!(message, "Invalid signature");
+                assert_eq!(status, Some(401));
+            }
+            other => panic!("Unexpected data: {:?}", other),
+        }
+        assert!(matches!(
+            parse("pusher_internal:subscription_error", r#"{"type":"LimitReached"}"#),
+            SystemEventData::SubscriptionError { message, status: None, .. }
+                if message == "LimitReached"
+        ));
+
+        match parse(
+            "pusher_internal:subscription_succeeded",
+            r#"{"presence":{"count":1,"ids":["1"],"hash":{"1":{"name":"A"}}}}"#,
+        ) {
+            SystemEventData::SubscriptionSucceeded { presence: Some(presence) } => {
+                assert_eq!(presence.count, 1);
+                assert_eq!(presence.ids, vec!["1"]);
+            }
+            other => panic!("Unexpected data: {:?}", other),
+        }
+        match parse(
+            "pusher_internal:subscription_succeeded",
+            r#"{"presence":{"hash":{"1":{"name":"A"},"2":null}}}"#,
+        ) {
+            SystemEventData::SubscriptionSucceeded { presence: Some(presence) } => {
+                assert_eq!(presence.hash.len(), 2);
+                assert!(presence.ids.is_empty());
+            }
+            other => panic!("Unexpected data: {:?}", other),
+        }
+        assert!(matches!(
+            parse("pusher:subscription_succeeded", ""),
+            SystemEventData::SubscriptionSucceeded { presence: None }
+        ));
+
+        match parse("pusher_internal:member_added", r#"{"user_id":42,"user_info":{"name":"B"}}"#) {
+            SystemEventData::MemberAdded { user_id, user_info } => {
+                assert_eq!(user_id, "42");
+                assert_eq!(user_info, json!({"name": "B"}));
+            }
+            other => panic!("Unexpected data: {:?}", other),
+        }
+        assert!(matches!(
+            parse("pusher:member_removed", r#"{"user_id":"42"}"#),
+            SystemEventData::MemberRemoved { user_id } if user_id == "42"
+        ));
+        assert!(matches!(
+            parse("pusher_internal:subscription_count", r#"{"subscription_count":12}"#),
+            SystemEventData::SubscriptionCount { subscription_count: 12 }
+        ));
+        assert!(matches!(
+            parse("pusher:error", r#"{"code":4001,"message":"App disabled"}"#),
+            SystemEventData::Error { code: Some(4001), message } if message == "App disabled"
+        ));
+        assert!(matches!(parse("pusher:ping", "{}"), SystemEventData::Ping));
+        assert!(matches!(parse("pusher:pong", ""), SystemEventData::Pong));
+
+        // Unknown events and malformed payloads fall back to the raw data.
+        assert!(matches!(
+            parse("pusher:something_new", r#"{"a":1}"#),
+            SystemEventData::Other(data) if data == r#"{"a":1}"#
+        ));
+        assert!(matches!(
+            parse("pusher:member_removed", "{}"),
+            SystemEventData::Other(_)
+        ));
+        assert!(matches!(
+            parse("pusher_internal:subscription_count", r#"{"subscription_count":"many"}"#),
+            SystemEventData::Other(_)
+        ));
+
+        let event = Event::new("my-event".to_string(), None, "{}".to_string());
+        assert!(SystemEvent::from_event(&event).is_none());
+        assert!(event.as_system_event().is_none());
     }
 
-    pub fn as_event(&self) -> Event {
-        Event {
-            event: self.event.clone(),
-            channel: self.channel.clone(),
-            data: serde_json::to_value(&self.data).unwrap().to_string(),
-        }
+    #[test]
+    fn test_check_json_depth() {
+        assert!(check_json_depth(r#"{"a":[1,{"b":2}]}"#, 3).is_ok());
+        assert!(matches!(
+            check_json_depth(r#"{"a":[1,{"b":2}]}"#, 2),
+            Err(PusherError::ProtocolError(_))
+        ));
+        // Brackets inside strings don't count.
+        assert!(check_json_depth(r#"{"a":"[[[[\"{{{{"}"#, 1).is_ok());
+
+        let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        assert!(check_json_depth(&nested, 64).is_err());
     }
-}
\ No newline at end of file
+}
@@ -1,50 +1,287 @@
 /// A client for interacting with the Pusher service.
 ///
 mod auth;
+mod channel_info;
 mod channels;
+mod circuit_breaker;
 mod config;
 mod error;
 mod events;
+mod metrics;
+mod offline_queue;
+mod subscribe_builder;
+#[cfg(test)]
+mod test_server;
+mod text_frames;
+mod trace;
+mod trigger_tracker;
+mod webhook;
 mod websocket;
 
 use aes::{
-    cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit},
+    cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit},
     Aes256,
 };
-use cbc::Encryptor;
+use cbc::{Decryptor, Encryptor};
 use hmac::{Hmac, Mac};
-use log::info;
+use log::{info, warn};
 use rand::Rng;
+use serde::de::DeserializeOwned;
 use serde_json::json;
 use sha2::Sha256;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::any::Any;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, Stream, StreamExt};
 use url::Url;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub use auth::PusherAuth;
-pub use channels::{Channel, ChannelType};
-pub use config::PusherConfig;
+pub use auth::{AuthResponse, PusherAuth, UserAuthResponse, UserData};
+use auth::AuthCache;
+pub use channel_info::{ChannelInfo, ChannelList, TriggerResponse};
+pub use channels::{Channel, ChannelType, MemberDiff, PresenceMember, SubscriptionState};
+pub use circuit_breaker::CircuitState;
+pub use config::{CircuitBreakerConfig, PusherConfig, TlsConfig};
 pub use error::{PusherError, PusherResult};
-pub use events::{Event, SystemEvent};
+pub use events::{Event, SystemEvent, SystemEventData};
+pub use metrics::Metrics;
+pub use subscribe_builder::SubscribeBuilder;
+pub use webhook::{Webhook, WebhookEvent};
+
+use channel_info::ChannelUsers;
+use events::validate_event_name;
+use circuit_breaker::CircuitBreaker;
+use metrics::MetricsCounters;
+use offline_queue::{OfflineQueue, QueuedTrigger};
+use trace::{record_field, traced};
+use trigger_tracker::TriggerTracker;
+pub use websocket::OVER_CAPACITY_EVENT;
+use websocket::{PendingSubscriptions, ReconnectPolicy, WebSocketClient, WebSocketCommand};
+
+/// How many times `connect` retries after a DNS resolution failure before giving up.
+const DNS_RETRY_ATTEMPTS: u32 = 3;
+
+/// How long a `429` response is taken to ask for when it has no usable `Retry-After` header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// How long the REST host's addresses are used for `PusherConfig::tls_sni` requests before
+/// the host is resolved again.
+const SNI_RESOLVE_TTL: Duration = Duration::from_secs(60);
+
+/// The synthetic event with the `MemberDiff` of a presence channel, sent whenever its
+/// members change, including when the roster is replaced after reconnecting.
+pub const MEMBER_DIFF_EVENT: &str = "pusher:member_diff";
+
+const IDEMPOTENCY_KEY_HEADER: &str = "X-Pusher-Idempotency-Key";
+
+/// How many events a stream from `events` may fall behind before it skips the oldest.
+const EVENT_STREAM_CAPACITY: usize = 128;
+
+/// A callback run for each matching event.
+pub type EventCallback = Box<dyn Fn(Event) + Send + Sync + 'static>;
+
+/// A callback bound with `bind_async`, returning the future the dispatcher awaits.
+type AsyncEventCallback = Box<dyn Fn(Event) -> BoxFuture<'static, ()> + Send + Sync + 'static>;
+
+enum HandlerCallback {
+    Sync(EventCallback),
+    Async(AsyncEventCallback),
+}
+
+type EventHandlers = Arc<RwLock<HashMap<String, Vec<EventHandler>>>>;
+
+/// One lock per channel with triggers in flight, used by `PusherConfig::ordered_publish`.
+/// Tokio's mutex is fair, so waiting triggers publish in the order they queued up.
+type PublishQueues = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+// Error handlers are invoked from synchronous callbacks, so they sit behind a std lock.
+pub(crate) type ErrorHandlers = Arc<std::sync::RwLock<Vec<Box<dyn Fn(&PusherError) + Send + Sync + 'static>>>>;
+
+type StateChangeCallback = Box<dyn Fn(ConnectionState, ConnectionState) + Send + Sync + 'static>;
+
+/// The callbacks registered with `on_state_change`.
+pub(crate) type StateChangeHandlers = Arc<std::sync::RwLock<Vec<StateChangeCallback>>>;
+
+type RawMessageCallback = Arc<dyn Fn(&str) + Send + Sync + 'static>;
+
+/// The callbacks registered with `on_raw_message`.
+pub(crate) type RawMessageHandlers = Arc<std::sync::RwLock<Vec<RawMessageCallback>>>;
+
+/// Moves the connection to `new_state`, telling the `on_state_change` handlers if it changed.
+pub(crate) async fn set_connection_state(
+    state: &RwLock<ConnectionState>,
+    handlers: &StateChangeHandlers,
+    new_state: ConnectionState,
+) {
+    let previous = std::mem::replace(&mut *state.write().await, new_state.clone());
+    if previous == new_state {
+        return;
+    }
+    log::debug!("Connection state changed from {:?} to {:?}", previous, new_state);
+    for handler in handlers.read().unwrap().iter() {
+        handler(previous.clone(), new_state.clone());
+    }
+}
+
+/// Logs an error and forwards it to the handlers registered with `on_error`.
+pub(crate) fn report_error(error_handlers: &ErrorHandlers, error: PusherError) {
+    log::error!("{}", error);
+    for handler in error_handlers.read().unwrap().iter() {
+        handler(&error);
+    }
+}
+
+/// The most channels `PusherClient::trigger_multi` can publish to in one request.
+pub const MAX_TRIGGER_CHANNELS: usize = 100;
+
+/// The most members a presence channel can have on Pusher. Subscribing to a full channel
+/// fails with `PusherError::MemberLimitReached`.
+pub const MAX_PRESENCE_MEMBERS: usize = 100;
+
+/// The most events Pusher accepts in one batch request. `PusherClient::trigger_batch` splits
+/// larger batches into requests of this size.
+pub const MAX_BATCH_EVENTS: usize = 10;
+
+/// How many batch requests of one `trigger_batch` call are in flight at once.
+const BATCH_CONCURRENCY: usize = 4;
+
+/// Identifies a callback registered with one of the `bind*` methods, so it can be removed
+/// again with `PusherClient::unbind_handler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BindingId(u64);
+
+impl BindingId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct EventHandler {
+    id: BindingId,
+    callback: HandlerCallback,
+    /// For handlers bound with `bind_weak`, the target whose lifetime bounds the handler.
+    target: Option<Weak<dyn Any + Send + Sync>>,
+    /// For handlers made with `for_channel`, the channel they run for.
+    channel: Option<String>,
+}
+
+impl EventHandler {
+    fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        Self {
+            id: BindingId::next(),
+            callback: HandlerCallback::Sync(Box::new(callback)),
+            target: None,
+            channel: None,
+        }
+    }
+
+    fn new_async<F, Fut>(callback: F) -> Self
+    where
+        F: Fn(Event) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            id: BindingId::next(),
+            callback: HandlerCallback::Async(Box::new(move |event| callback(event).boxed())),
+            target: None,
+            channel: None,
+        }
+    }
+
+    /// Runs a synchronous callback straight away, or adds the future of an async one to
+    /// `pending`.
+    fn call(&self, event: &Event, pending: &mut Vec<BoxFuture<'static, ()>>) {
+        match &self.callback {
+            HandlerCallback::Sync(callback) => callback(event.clone()),
+            HandlerCallback::Async(callback) => pending.push(callback(event.clone())),
+        }
+    }
+
+    /// Wraps `callback` so it only runs for events on `channel_name`.
+    fn for_channel<F>(channel_name: &str, callback: F) -> Self
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        let channel = channel_name.to_string();
+        Self {
+            channel: Some(channel_name.to_string()),
+            ..Self::new(move |event| {
+                if event.channel.as_deref() == Some(channel.as_str()) {
+                    callback(event);
+                }
+            })
+        }
+    }
 
-use websocket::{WebSocketClient, WebSocketCommand};
+    fn is_alive(&self) -> bool {
+        self.target
+            .as_ref()
+            .is_none_or(|target| target.strong_count() > 0)
+    }
+}
 
 /// This struct provides methods for connecting to Pusher, subscribing to channels,
 /// triggering events, and handling incoming events.
+///
+/// Clones are handles to the same client: they share the connection, channels and
+/// handlers, so one task can subscribe while another triggers.
+#[derive(Clone)]
 pub struct PusherClient {
     config: PusherConfig,
     auth: PusherAuth,
+    auth_cache: Arc<AuthCache>,
     // websocket: Option<WebSocketClient>,
-    websocket_command_tx: Option<mpsc::Sender<WebSocketCommand>>,
+    websocket_command_tx: Arc<RwLock<Option<mpsc::Sender<WebSocketCommand>>>>,
+    websocket_task: Arc<Mutex<Option<JoinHandle<()>>>>,
     channels: Arc<RwLock<HashMap<String, Channel>>>,
-    event_handlers: Arc<RwLock<HashMap<String, Vec<Box<dyn Fn(Event) + Send + Sync + 'static>>>>>,
+    event_handlers: EventHandlers,
+    global_handlers: Arc<RwLock<Vec<EventHandler>>>,
     state: Arc<RwLock<ConnectionState>>,
+    state_handlers: StateChangeHandlers,
+    raw_message_handlers: RawMessageHandlers,
     event_tx: mpsc::Sender<Event>,
+    /// Every dispatched event, for the streams returned by `events`.
+    event_stream_tx: broadcast::Sender<Event>,
+    event_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Event>>>,
+    dispatch_task: Arc<Mutex<Option<JoinHandle<()>>>>,
     encrypted_channels: Arc<RwLock<HashMap<String, Vec<u8>>>>,
     socket_id: Arc<RwLock<Option<String>>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    error_handlers: ErrorHandlers,
+    runtime: Handle,
+    pending_subscriptions: PendingSubscriptions,
+    connection_metadata: Arc<RwLock<HashMap<String, String>>>,
+    realtime: bool,
+    publish_queues: PublishQueues,
+    triggers: Arc<TriggerTracker>,
+    offline_queue: Arc<OfflineQueue>,
+    over_capacity: Arc<AtomicBool>,
+    last_error: Arc<Mutex<Option<Arc<PusherError>>>>,
+    activity_timeout: Arc<Mutex<Option<Duration>>>,
+    connection_info: Arc<Mutex<Option<ConnectionInfo>>>,
+    metrics: Arc<MetricsCounters>,
+    http_client: reqwest::Client,
+    /// With `PusherConfig::tls_sni`, the client pinned to the REST host's addresses, resolved
+    /// on first use and again once they are stale or couldn't be connected to.
+    sni_http_client: Arc<tokio::sync::Mutex<Option<PinnedClient>>>,
+}
+
+/// A REST client that sends requests for the `tls_sni` name to the addresses the REST host
+/// resolved to at `resolved_at`.
+struct PinnedClient {
+    client: reqwest::Client,
+    resolved_at: Instant,
 }
 
 #[derive(Debug, Clone)]
@@ -52,6 +289,138 @@ pub struct BatchEvent {
     pub channel: String,
     pub event: String,
     pub data: String,
+    /// A connection the event is not delivered to, usually the one that caused it.
+    pub socket_id: Option<String>,
+}
+
+/// The outcome of a `trigger_batch` call, with one entry per event in the order given.
+#[derive(Debug, Clone, Default)]
+pub struct BatchResult {
+    pub events: Vec<BatchEventResult>,
+    /// How many events were published.
+    pub succeeded: usize,
+    /// The events that weren't published, by their index in the batch given, with the
+    /// reason. Events of the same failed request share its error.
+    pub failures: Vec<(usize, Arc<PusherError>)>,
+}
+
+impl BatchResult {
+    /// Whether the server accepted every event in the batch.
+    pub fn all_accepted(&self) -> bool {
+        self.events.iter().all(BatchEventResult::is_accepted)
+    }
+
+    /// The result for `events` whose request failed with `error`.
+    fn failed(events: &[BatchEvent], error: PusherError) -> Self {
+        let error = Arc::new(error);
+        Self {
+            events: events
+                .iter()
+                .map(|event| BatchEventResult {
+                    channel: event.channel.clone(),
+                    event: event.event.clone(),
+                    error: Some(error.to_string()),
+                    info: HashMap::new(),
+                })
+                .collect(),
+            succeeded: 0,
+            failures: (0..events.len()).map(|index| (index, Arc::clone(&error))).collect(),
+        }
+    }
+
+    /// Appends the result of the events following this result's ones.
+    fn append(&mut self, other: BatchResult) {
+        let offset = self.events.len();
+        self.events.extend(other.events);
+        self.succeeded += other.succeeded;
+        self.failures.extend(
+            other
+                .failures
+                .into_iter()
+                .map(|(index, error)| (offset + index, error)),
+        );
+    }
+
+    /// Aligns the `batch` array of a batch events response with the events that were sent.
+    ///
+    /// Servers that don't report per-event results answer with `{}`, in which case every
+    /// event counts as accepted since the request as a whole succeeded.
+    fn from_response(events: &[BatchEvent], response: &serde_json::Value) -> Self {
+        let entries = response.get("batch").and_then(serde_json::Value::as_array);
+        let events = events
+            .iter()
+            .enumerate()
+            .map(|(index, event)| {
+                let mut info = match entries.and_then(|entries| entries.get(index)) {
+                    Some(serde_json::Value::Object(attributes)) => attributes.clone(),
+                    _ => serde_json::Map::new(),
+                };
+                let error = info.remove("error").map(|error| match error {
+                    serde_json::Value::String(message) => message,
+                    other => other.to_string(),
+                });
+                BatchEventResult {
+                    channel: event.channel.clone(),
+                    event: event.event.clone(),
+                    error,
+                    info: info.into_iter().collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let failures: Vec<_> = events
+            .iter()
+            .enumerate()
+            .filter_map(|(index, result)| {
+                let message = result.error.as_ref()?;
+                let error = PusherError::EventError(format!(
+                    "Event '{}' on '{}' was rejected: {}",
+                    result.event, result.channel, message
+                ));
+                Some((index, Arc::new(error)))
+            })
+            .collect();
+        Self {
+            succeeded: events.len() - failures.len(),
+            events,
+            failures,
+        }
+    }
+}
+
+/// What the server reported for one event of a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchEventResult {
+    pub channel: String,
+    pub event: String,
+    /// The reason the event was rejected, if it was.
+    pub error: Option<String>,
+    /// Info attributes returned for the event, such as `subscription_count`.
+    pub info: HashMap<String, serde_json::Value>,
+}
+
+impl BatchEventResult {
+    pub fn is_accepted(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// What the server sent in `pusher:connection_established` for the current connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub socket_id: String,
+    /// The activity timeout the server asked for, if it sent one. See
+    /// `PusherClient::activity_timeout` for the one in effect.
+    pub activity_timeout: Option<Duration>,
+}
+
+/// Where the client sends its requests, as returned by `PusherClient::endpoint_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointInfo {
+    pub cluster: String,
+    /// The websocket URL, including the app key and protocol version.
+    pub websocket_url: String,
+    /// The URL REST paths are appended to, including any `PusherConfig::rest_path_prefix`.
+    pub rest_url: String,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -74,69 +443,384 @@ impl PusherClient {
     ///
     /// A `PusherResult` containing the new `PusherClient` instance.
     pub fn new(config: PusherConfig) -> PusherResult<Self> {
+        Self::new_with_runtime(config, Handle::current())
+    }
+
+    /// Creates a new `PusherClient` whose background tasks run on the given runtime.
+    ///
+    /// Event dispatch and the websocket connection are spawned on `runtime` instead of the
+    /// runtime the client is used from, keeping event delivery isolated from a busy
+    /// application runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration for the Pusher client.
+    /// * `runtime` - A handle to the runtime that background tasks are spawned on.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the new `PusherClient` instance.
+    pub fn new_with_runtime(config: PusherConfig, runtime: Handle) -> PusherResult<Self> {
+        let client = Self::build(config, runtime, true)?;
+        client.spawn_dispatcher();
+        Ok(client)
+    }
+
+    /// Creates a new `PusherClient` that only talks to the REST API.
+    ///
+    /// No event dispatch task is spawned, which saves a task and its queue for server-side
+    /// publishers that only trigger events. The client keeps the full `PusherClient` API, so
+    /// code can switch between modes without changing types, but `connect`, the `subscribe*`
+    /// methods, the `bind*` methods and `send_test_event` return
+    /// `PusherError::RealtimeDisabled`. Use `new` if the same client needs both.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration for the Pusher client.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the new `PusherClient` instance.
+    pub fn new_rest_only(config: PusherConfig) -> PusherResult<Self> {
+        Self::build(config, Handle::current(), false)
+    }
+
+    fn build(config: PusherConfig, runtime: Handle, realtime: bool) -> PusherResult<Self> {
+        config.validate()?;
         let auth = PusherAuth::new(&config.app_key, &config.app_secret);
-        let (event_tx, event_rx) = mpsc::channel(100);
+        let (event_tx, event_rx) = mpsc::channel(config.event_buffer_size);
+        let (event_stream_tx, _) = broadcast::channel(EVENT_STREAM_CAPACITY);
         let state = Arc::new(RwLock::new(ConnectionState::Disconnected));
         let event_handlers = Arc::new(RwLock::new(std::collections::HashMap::new()));
         let encrypted_channels = Arc::new(RwLock::new(std::collections::HashMap::new()));
         let socket_id = Arc::new(RwLock::new(None));
+        let circuit_breaker = config
+            .circuit_breaker
+            .clone()
+            .map(|breaker_config| Arc::new(CircuitBreaker::new(breaker_config)));
+        let http_client = match &config.http_client {
+            Some(client) => client.clone(),
+            None => rest_client_builder(&config)?.build()?,
+        };
+        let offline_queue = Arc::new(OfflineQueue::new(config.offline_queue_size));
 
-        let client = Self {
+        Ok(Self {
             config,
             auth,
-            websocket_command_tx: None,
+            auth_cache: Arc::default(),
+            websocket_command_tx: Arc::new(RwLock::new(None)),
+            websocket_task: Arc::new(Mutex::new(None)),
             channels: Arc::new(RwLock::new(std::collections::HashMap::new())),
-            event_handlers: event_handlers.clone(),
+            event_handlers,
+            global_handlers: Arc::new(RwLock::new(Vec::new())),
             state: state.clone(),
+            state_handlers: Arc::new(std::sync::RwLock::new(Vec::new())),
+            raw_message_handlers: Arc::new(std::sync::RwLock::new(Vec::new())),
             event_tx,
+            event_stream_tx,
+            event_rx: Arc::new(tokio::sync::Mutex::new(event_rx)),
+            dispatch_task: Arc::new(Mutex::new(None)),
             encrypted_channels,
             socket_id,
-        };
+            circuit_breaker,
+            error_handlers: Arc::new(std::sync::RwLock::new(Vec::new())),
+            runtime,
+            pending_subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            connection_metadata: Arc::new(RwLock::new(HashMap::new())),
+            realtime,
+            publish_queues: Arc::new(Mutex::new(HashMap::new())),
+            triggers: Arc::new(TriggerTracker::new()),
+            offline_queue,
+            over_capacity: Arc::new(AtomicBool::new(false)),
+            last_error: Arc::new(Mutex::new(None)),
+            activity_timeout: Arc::new(Mutex::new(None)),
+            connection_info: Arc::new(Mutex::new(None)),
+            metrics: Arc::default(),
+            http_client,
+            sni_http_client: Arc::new(tokio::sync::Mutex::new(None)),
+        })
+    }
 
-        tokio::spawn(Self::handle_events(event_rx, event_handlers));
+    fn spawn_dispatcher(&self) {
+        let task = self.runtime.spawn(Self::handle_events(
+            Arc::clone(&self.event_rx),
+            Arc::clone(&self.event_handlers),
+            Arc::clone(&self.global_handlers),
+            Arc::clone(&self.channels),
+            Arc::clone(&self.encrypted_channels),
+            Arc::clone(&self.error_handlers),
+            self.event_stream_tx.clone(),
+            self.config.track_last_event,
+            Arc::clone(&self.metrics),
+        ));
+        *self.dispatch_task.lock().unwrap() = Some(task);
+    }
 
-        Ok(client)
+    /// Checks whether the background task that dispatches events to bound handlers is running.
+    ///
+    /// The dispatcher stops if a handler panics. While it is stopped, incoming events are
+    /// queued but no handler is invoked.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the dispatcher is running, `false` otherwise.
+    pub fn is_dispatch_alive(&self) -> bool {
+        self.dispatch_task
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
     }
 
-    async fn send(&self, message: String) -> PusherResult<()> {
-        if let Some(tx) = &self.websocket_command_tx {
-            tx.send(WebSocketCommand::Send(message))
-                .await
-                .map_err(|e| {
-                    PusherError::WebSocketError(format!("Failed to send command: {}", e))
-                })?;
+    /// Restarts the event dispatcher if it has stopped, reusing the existing handlers.
+    ///
+    /// This is called automatically by `bind`, `connect` and `send_test_event`. It does
+    /// nothing on a client created with `new_rest_only`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the dispatcher had to be restarted, `false` if it was already running.
+    pub fn ensure_dispatcher(&self) -> bool {
+        if !self.realtime || self.is_dispatch_alive() {
+            return false;
+        }
+        warn!("Event dispatcher has stopped, restarting it");
+        self.spawn_dispatcher();
+        true
+    }
+
+    fn require_presence(channel_name: &str) -> PusherResult<()> {
+        if ChannelType::from_name(channel_name) != ChannelType::Presence {
+            return Err(PusherError::ChannelError(format!(
+                "'{}' is not a presence channel",
+                channel_name
+            )));
+        }
+        Ok(())
+    }
+
+    fn require_realtime(&self) -> PusherResult<()> {
+        if self.realtime {
             Ok(())
+        } else {
+            Err(PusherError::RealtimeDisabled)
+        }
+    }
+
+    async fn send(&self, message: String) -> PusherResult<()> {
+        let command_tx = self.websocket_command_tx.read().await.clone();
+        if let Some(tx) = command_tx {
+            send_command(&tx, message).await
         } else {
             Err(PusherError::ConnectionError("Not connected".into()))
         }
     }
 
+    // The dispatcher holds its own handle to each piece of shared state it touches.
+    #[allow(clippy::too_many_arguments)]
     async fn handle_events(
-        mut event_rx: mpsc::Receiver<Event>,
-        event_handlers: Arc<
-            RwLock<
-                std::collections::HashMap<String, Vec<Box<dyn Fn(Event) + Send + Sync + 'static>>>,
-            >,
-        >,
+        event_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Event>>>,
+        event_handlers: EventHandlers,
+        global_handlers: Arc<RwLock<Vec<EventHandler>>>,
+        channels: Arc<RwLock<HashMap<String, Channel>>>,
+        encrypted_channels: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+        error_handlers: ErrorHandlers,
+        event_stream_tx: broadcast::Sender<Event>,
+        track_last_event: bool,
+        metrics: Arc<MetricsCounters>,
+    ) {
+        // The receiver lock is released if a handler panics, so a restarted dispatcher can
+        // pick up where this one left off.
+        let mut event_rx = event_rx.lock().await;
+        while let Some(mut event) = event_rx.recv().await {
+            // Handlers only ever see plaintext; an event that can't be decrypted is dropped.
+            if let Err(e) = Self::decrypt_event(&encrypted_channels, &mut event).await {
+                report_error(&error_handlers, e);
+                continue;
+            }
+            let member_diff = Self::apply_internal_event(&channels, &mut event).await;
+            if track_last_event {
+                if let Some(channel_name) = &event.channel {
+                    if let Some(channel) = channels.write().await.get_mut(channel_name) {
+                        channel.set_last_event(event.clone());
+                    }
+                }
+            }
+
+            for event in std::iter::once(event).chain(member_diff) {
+                Self::dispatch(&event, &event_handlers, &global_handlers).await;
+                MetricsCounters::add(&metrics.events_dispatched, 1);
+                if event_stream_tx.receiver_count() > 0 {
+                    let _ = event_stream_tx.send(event);
+                }
+            }
+        }
+    }
+
+    async fn dispatch(
+        event: &Event,
+        event_handlers: &EventHandlers,
+        global_handlers: &RwLock<Vec<EventHandler>>,
     ) {
-        while let Some(event) = event_rx.recv().await {
+        let mut has_dead_handlers = false;
+        let mut pending = Vec::new();
+        {
             let handlers = event_handlers.read().await;
             if let Some(callbacks) = handlers.get(&event.event) {
-                for callback in callbacks {
-                    callback(event.clone());
+                for handler in callbacks {
+                    if handler.is_alive() {
+                        handler.call(event, &mut pending);
+                    } else {
+                        has_dead_handlers = true;
+                    }
+                }
+            }
+        }
+
+        for handler in global_handlers.read().await.iter() {
+            handler.call(event, &mut pending);
+        }
+
+        // Awaited with the handlers unlocked, so async callbacks may bind and unbind. The
+        // next event waits until they finish.
+        for future in pending {
+            future.await;
+        }
+
+        if has_dead_handlers {
+            let mut handlers = event_handlers.write().await;
+            if let Some(callbacks) = handlers.get_mut(&event.event) {
+                callbacks.retain(EventHandler::is_alive);
+                if callbacks.is_empty() {
+                    handlers.remove(&event.event);
+                }
+            }
+        }
+    }
+
+    /// Replaces the data of an event on an encrypted channel with its plaintext. System
+    /// events are sent unencrypted and are left as they are.
+    async fn decrypt_event(
+        encrypted_channels: &RwLock<HashMap<String, Vec<u8>>>,
+        event: &mut Event,
+    ) -> PusherResult<()> {
+        if event.is_system_event() {
+            return Ok(());
+        }
+        let Some(channel_name) = &event.channel else {
+            return Ok(());
+        };
+        if let Some(shared_secret) = encrypted_channels.read().await.get(channel_name) {
+            event.data = decrypt_data(&event.data, shared_secret).map_err(|e| {
+                PusherError::DecryptionError(format!(
+                    "Event '{}' on '{}': {}",
+                    event.event, channel_name, e
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Updates channel state from subscription confirmations, presence changes and
+    /// `pusher_internal:*` events, renaming the latter to the `pusher:*` events exposed to
+    /// handlers where needed. The payloads are read from the event's `SystemEvent` form.
+    ///
+    /// Returns a `MEMBER_DIFF_EVENT` if the members of a presence channel changed.
+    async fn apply_internal_event(
+        channels: &RwLock<HashMap<String, Channel>>,
+        event: &mut Event,
+    ) -> Option<Event> {
+        let system_event = SystemEvent::from_event(event)?;
+        if event.event == "pusher_internal:subscription_count" {
+            event.event = "pusher:subscription_count".to_string();
+        }
+        let channel_name = event.channel.as_ref()?;
+
+        let diff = {
+            let mut channels = channels.write().await;
+            let channel = channels.get_mut(channel_name)?;
+            // Confirmations go by name, so one with a malformed roster still counts.
+            match system_event.event.as_str() {
+                "pusher_internal:subscription_succeeded" | "pusher:subscription_succeeded" => {
+                    channel.set_subscription_state(SubscriptionState::Subscribed);
+                }
+                "pusher_internal:subscription_error" | "pusher:subscription_error" => {
+                    channel.set_subscription_state(SubscriptionState::Failed);
+                }
+                _ => {}
+            }
+            if let SystemEventData::SubscriptionCount { subscription_count } = system_event.data {
+                channel.set_subscription_count(subscription_count as usize);
+            }
+            if *channel.channel_type() != ChannelType::Presence {
+                return None;
+            }
+            match system_event.data {
+                SystemEventData::SubscriptionSucceeded {
+                    presence: Some(presence),
+                } => channel.replace_members(presence.hash),
+                SystemEventData::MemberAdded { user_id, user_info } => {
+                    let mut members = channel.members().cloned().unwrap_or_default();
+                    members.insert(user_id, user_info);
+                    channel.replace_members(members)
+                }
+                SystemEventData::MemberRemoved { user_id } => {
+                    let mut members = channel.members().cloned().unwrap_or_default();
+                    members.remove(&user_id);
+                    channel.replace_members(members)
                 }
+                _ => return None,
             }
+        };
+        if diff.is_empty() {
+            return None;
         }
+        Some(Event::new(
+            MEMBER_DIFF_EVENT.to_string(),
+            Some(channel_name.clone()),
+            serde_json::to_string(&diff).ok()?,
+        ))
     }
 
-    /// Connects to the Pusher server and waits for the socket ID to be set.
+    /// Connects to the Pusher server and waits for `pusher:connection_established`, so once
+    /// this succeeds the socket ID is set and the state is `Connected`.
+    ///
+    /// Channels that were subscribed on a previous connection are subscribed again. A host
+    /// that can't be resolved is retried a few times with backoff before giving up.
+    ///
+    /// If the connection drops later without `disconnect` being called, it is re-established
+    /// in the background with the backoff of `reconnect_delay` and the channels are
+    /// subscribed again. The state is `Reconnecting` meanwhile, and `Failed` after
+    /// `PusherConfig::max_reconnection_attempts` attempts in a row have failed.
+    ///
+    /// Fails with `PusherError::ConnectionError("handshake timed out")` if the socket opens
+    /// but `pusher:connection_established` doesn't arrive within
+    /// `PusherConfig::handshake_timeout`, and with `PusherError::ConnectionError("timed out")`
+    /// if the whole attempt takes longer than `PusherConfig::connect_timeout`.
     ///
     /// # Returns
     ///
     /// A `PusherResult` indicating success or failure.
-    pub async fn connect(&mut self) -> PusherResult<()> {
+    pub async fn connect(&self) -> PusherResult<()> {
+        traced!(
+            self.open_connection(),
+            "pusher.connect",
+            url = tracing::field::Empty,
+            socket_id = tracing::field::Empty,
+        )
+        .await
+    }
+
+    async fn open_connection(&self) -> PusherResult<()> {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
         let url = self.get_websocket_url()?;
-        let (command_tx, command_rx) = mpsc::channel(100);
+        record_field!("url", &url);
+        // A connection left from an earlier call, possibly still reconnecting, would otherwise
+        // keep running alongside the new one and override its state.
+        let _ = self.close_connection().await;
+        let (command_tx, command_rx) = mpsc::channel(self.config.command_buffer_size);
+        *self.socket_id.write().await = None;
 
         let mut websocket = WebSocketClient::new(
             url.clone(),
@@ -144,520 +828,6544 @@ impl PusherClient {
             self.event_tx.clone(),
             command_rx,
             Arc::clone(&self.socket_id),
-        );
+            Arc::clone(&self.pending_subscriptions),
+            Arc::clone(&self.error_handlers),
+        )
+        .with_max_json_depth(self.config.max_json_depth)
+        .with_tls_sni(self.config.tls_sni.clone())
+        .with_tls(self.config.tls.clone())
+        .with_proxy(self.config.proxy_url()?)
+        .with_headers(self.config.ws_header_map()?)
+        .with_over_capacity_flag(Arc::clone(&self.over_capacity))
+        .with_state_change_handlers(Arc::clone(&self.state_handlers))
+        .with_raw_message_handlers(Arc::clone(&self.raw_message_handlers))
+        .with_lenient_utf8(self.config.lenient_utf8)
+        .with_keepalive(
+            self.config.activity_timeout,
+            self.config.pong_timeout,
+            Arc::clone(&self.activity_timeout),
+        )
+        .with_connection_info(Arc::clone(&self.connection_info))
+        .with_metrics(Arc::clone(&self.metrics))
+        .with_auth_cache(Arc::clone(&self.auth_cache))
+        .with_reconnection(self.reconnect_policy(), {
+            let resubscriber = self.resubscriber();
+            let command_tx = command_tx.downgrade();
+            Box::new(move |socket_id| {
+                let resubscriber = resubscriber.clone();
+                let command_tx = command_tx.clone();
+                Box::pin(async move {
+                    if let Some(command_tx) = command_tx.upgrade() {
+                        resubscriber.resubscribe_all(&command_tx, &socket_id).await;
+                    }
+                })
+            })
+        });
 
-        log::info!("Connecting to Pusher using URL: {}", url);
-        websocket.connect().await?;
+        info!(
+            "Connecting to Pusher cluster {} using URL: {}",
+            self.config.cluster,
+            url
+        );
+        self.set_state(ConnectionState::Connecting).await;
+        let connect_deadline = tokio::time::Instant::now() + self.config.connect_timeout;
+        let opened = tokio::time::timeout_at(connect_deadline, async {
+            // DNS failures are usually transient, and the host is resolved afresh each attempt.
+            let mut attempt = 0;
+            loop {
+                match websocket.connect().await {
+                    Err(e) if e.is_dns_failure() && attempt < DNS_RETRY_ATTEMPTS => {
+                        warn!("{}, retrying", e);
+                        tokio::time::sleep(self.reconnect_delay(attempt)).await;
+                        attempt += 1;
+                    }
+                    result => return result,
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| Err(PusherError::ConnectionError("timed out".into())));
+        if let Err(e) = opened {
+            *self.last_error.lock().unwrap() = Some(Arc::new(e.duplicate()));
+            self.set_state(ConnectionState::Disconnected).await;
+            return Err(e);
+        }
+        *self.last_error.lock().unwrap() = None;
+        *self.connection_metadata.write().await = websocket.connection_metadata().clone();
 
-        tokio::spawn(async move {
+        *self.websocket_task.lock().unwrap() = Some(self.runtime.spawn(async move {
             websocket.run().await;
-        });
+        }));
 
-        self.websocket_command_tx = Some(command_tx);
+        *self.websocket_command_tx.write().await = Some(command_tx);
 
-        // Wait for socket ID to be set
-        let mut attempts = 0;
-        const MAX_ATTEMPTS: u32 = 10;
-        const WAIT_TIME: Duration = Duration::from_millis(100);
-
-        while attempts < MAX_ATTEMPTS {
+        // Wait for connection_established to set the socket ID and the state to Connected.
+        const POLL_INTERVAL: Duration = Duration::from_millis(20);
+        let handshake_deadline = tokio::time::Instant::now() + self.config.handshake_timeout;
+        let deadline = handshake_deadline.min(connect_deadline);
+        loop {
             if let Some(socket_id) = self.socket_id.read().await.clone() {
-                if !socket_id.is_empty() {
-                    return Ok(());
+                if !socket_id.is_empty() && self.is_connected().await {
+                    record_field!("socket_id", &socket_id);
+                    return self.resubscribe_all(&socket_id).await;
                 }
             }
-            tokio::time::sleep(WAIT_TIME).await;
-            attempts += 1;
+            if tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
         }
 
-        Err(PusherError::ConnectionError("Socket ID was not set after connection".into()))
+        // The socket is open but the server never completed the Pusher handshake.
+        let _ = self.close_connection().await;
+        self.set_state(ConnectionState::Disconnected).await;
+        let message = if deadline < handshake_deadline {
+            "timed out"
+        } else {
+            "handshake timed out"
+        };
+        *self.last_error.lock().unwrap() =
+            Some(Arc::new(PusherError::ConnectionError(message.into())));
+        Err(PusherError::ConnectionError(message.into()))
     }
 
-    /// Disconnects from the Pusher server.
+    /// Gets the error that made the last connection attempt fail.
+    ///
+    /// A host name that couldn't be resolved gives a `PusherError::DnsError`, for which
+    /// `is_dns_failure` is true.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn disconnect(&mut self) -> PusherResult<()> {
-        if let Some(tx) = self.websocket_command_tx.take() {
-            tx.send(WebSocketCommand::Close).await.map_err(|e| {
-                PusherError::WebSocketError(format!("Failed to send close command: {}", e))
-            })?;
-        }
-        *self.state.write().await = ConnectionState::Disconnected;
-        Ok(())
+    /// The error, or `None` if the last attempt succeeded or none was made.
+    pub fn last_error(&self) -> Option<Arc<PusherError>> {
+        self.last_error.lock().unwrap().clone()
     }
 
-    /// Subscribes to a channel.
+    /// Gets the activity timeout in effect on the current connection: the shorter of
+    /// `PusherConfig::activity_timeout` and the one the server sent in
+    /// `pusher:connection_established`. A `pusher:ping` is sent once the connection has been
+    /// idle this long.
     ///
-    /// # Arguments
+    /// # Returns
     ///
-    /// * `channel_name` - The name of the channel to subscribe to.
+    /// The timeout, or `None` while not connected.
+    pub fn activity_timeout(&self) -> Option<Duration> {
+        *self.activity_timeout.lock().unwrap()
+    }
+
+    /// Gets what the server sent when the current connection was established: the socket ID
+    /// and the activity timeout it asked for.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn subscribe(&mut self, channel_name: &str) -> PusherResult<()> {
-        let channel = Channel::new(channel_name);
-        let mut channels = self.channels.write().await;
-        channels.insert(channel_name.to_string(), channel);
-
-        let data = json!({
-            "event": "pusher:subscribe",
-            "data": {
-                "channel": channel_name
-            }
-        });
+    /// The `ConnectionInfo`, or `None` while not connected.
+    pub fn get_connection_info(&self) -> Option<ConnectionInfo> {
+        self.connection_info.lock().unwrap().clone()
+    }
 
-        self.send(serde_json::to_string(&data)?).await
+    /// Gets where the client connects to, resolved from the cluster and any host overrides
+    /// in the config, for telling which region a client is talking to.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `EndpointInfo`, or an error if the config gives no
+    /// valid websocket URL.
+    pub fn endpoint_info(&self) -> PusherResult<EndpointInfo> {
+        Ok(EndpointInfo {
+            cluster: self.config.cluster.clone(),
+            websocket_url: self.get_websocket_url()?.to_string(),
+            rest_url: self.rest_base_url(),
+        })
     }
 
+    /// Gets the client's counters, for forwarding to a monitoring system such as
+    /// Prometheus.
+    ///
+    /// # Returns
+    ///
+    /// A `Metrics` snapshot, including the number of subscriptions currently confirmed.
+    pub async fn metrics(&self) -> Metrics {
+        let subscribed_channels = self
+            .channels
+            .read()
+            .await
+            .values()
+            .filter(|channel| channel.is_subscribed())
+            .count();
+        self.metrics.snapshot(subscribed_channels)
+    }
 
-    /// Subscribes to an encrypted channel.
+    /// Gets how long to wait before reconnection attempt number `attempt` (starting at 0).
+    ///
+    /// The delay doubles from `PusherConfig::backoff_interval` with each attempt, up to
+    /// `PusherConfig::max_reconnect_interval`. While the server reports being over capacity
+    /// (error code 4100) it is ten times longer, until a connection is established again.
     ///
     /// # Arguments
     ///
-    /// * `channel_name` - The name of the encrypted channel to subscribe to.
+    /// * `attempt` - The number of attempts made so far.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn subscribe_encrypted(&mut self, channel_name: &str) -> PusherResult<()> {
-        if !channel_name.starts_with("private-encrypted-") {
-            return Err(PusherError::ChannelError(
-                "Encrypted channels must start with 'private-encrypted-'".to_string(),
-            ));
-        }
-
-        let shared_secret = self.generate_shared_secret(channel_name);
+    /// The delay before the next attempt.
+    pub fn reconnect_delay(&self, attempt: u32) -> Duration {
+        self.reconnect_policy()
+            .delay(attempt, self.over_capacity.load(Ordering::Relaxed))
+    }
 
-        {
-            let mut encrypted_channels = self.encrypted_channels.write().await;
-            encrypted_channels.insert(channel_name.to_string(), shared_secret);
+    /// Asks the connection task to close the socket once the frames queued before are
+    /// written, then waits for the task to finish, aborting it after `close_timeout`.
+    async fn close_connection(&self) -> PusherResult<()> {
+        let command_tx = self.websocket_command_tx.write().await.take();
+        let sent = match command_tx {
+            Some(tx) => tx.send(WebSocketCommand::Close).await.map_err(|e| {
+                PusherError::WebSocketError(format!("Failed to send close command: {}", e))
+            }),
+            None => Ok(()),
+        };
+        let task = self.websocket_task.lock().unwrap().take();
+        if let Some(mut task) = task {
+            let timeout = self.config.close_timeout;
+            if tokio::time::timeout(timeout, &mut task).await.is_err() {
+                warn!("Connection did not close within {:?}, dropping it", timeout);
+                task.abort();
+            }
         }
+        sent
+    }
 
-        self.subscribe(channel_name).await
+    fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: self.config.max_reconnection_attempts,
+            base_delay: self.config.backoff_interval,
+            max_delay: self.config.max_reconnect_interval,
+        }
     }
 
-    /// Unsubscribes from a channel.
-    ///
-    /// # Arguments
+    /// Disconnects from the Pusher server.
     ///
-    /// * `channel_name` - The name of the channel to unsubscribe from.
+    /// Frames sent before are written first, and any reconnection in progress is stopped.
+    /// Returns once the socket is closed, or after `PusherConfig::close_timeout` if it is
+    /// stuck, in which case the connection is dropped.
     ///
     /// # Returns
     ///
     /// A `PusherResult` indicating success or failure.
+    pub async fn disconnect(&self) -> PusherResult<()> {
+        self.close_connection().await?;
+        self.set_state(ConnectionState::Disconnected).await;
+        *self.socket_id.write().await = None;
+        Ok(())
+    }
+
+    /// Waits for every trigger that is in flight, including those queued behind others by
+    /// `PusherConfig::ordered_publish`, to complete, then sends the triggers kept by
+    /// `PusherConfig::offline_queue_size`.
     ///
-    pub async fn unsubscribe(&mut self, channel_name: &str) -> PusherResult<()> {
-        {
-            let mut channels = self.channels.write().await;
-            channels.remove(channel_name);
+    /// Call this before shutting down a publisher so no event is lost, or use `shutdown`.
+    ///
+    /// # Returns
+    ///
+    /// `PusherError::TriggersFailed` listing the failures since the last flush that no
+    /// caller was given: queued triggers that were dropped or still can't be sent, and events
+    /// a batch didn't publish. Errors already returned by a trigger method aren't listed
+    /// again. Only the latest 100 are kept, with a count of the older ones. `Ok(())` if
+    /// there were none.
+    pub async fn flush_triggers(&self) -> PusherResult<()> {
+        self.triggers.wait_idle().await;
+        if let Err(e) = self.send_queued_triggers().await {
+            self.triggers.record_failure(format!(
+                "{} queued trigger(s) not sent yet: {}",
+                self.pending_triggers(),
+                e
+            ));
         }
+        self.triggers.flush().await
+    }
 
-        {
-            let mut encrypted_channels = self.encrypted_channels.write().await;
-            encrypted_channels.remove(channel_name);
-        }
+    /// Gets how many triggers are queued until the REST API can be reached again, see
+    /// `PusherConfig::offline_queue_size`.
+    pub fn pending_triggers(&self) -> usize {
+        self.offline_queue.len()
+    }
 
-        let data = json!({
-            "event": "pusher:unsubscribe",
-            "data": {
-                "channel": channel_name
-            }
-        });
+    /// Flushes pending triggers and then disconnects.
+    ///
+    /// # Returns
+    ///
+    /// The error from `flush_triggers` if any trigger failed, otherwise the result of
+    /// disconnecting. The client disconnects either way.
+    pub async fn shutdown(&self) -> PusherResult<()> {
+        let flushed = self.flush_triggers().await;
+        let disconnected = self.disconnect().await;
+        flushed.and(disconnected)
+    }
 
-        self.send(serde_json::to_string(&data)?).await
+    /// Replays the subscribe frame of every known channel on a new connection.
+    ///
+    /// The auth of the previous connection is bound to its socket ID, so private, presence
+    /// and encrypted channels are authorized again for the new `socket_id`, by
+    /// `PusherConfig::auth_endpoint` if set, and signed with the app secret otherwise.
+    /// Signed presence channels keep the member data they were first subscribed with, so
+    /// the user reappears to the other members. Without an app secret to sign with, the
+    /// channel's error goes to the handlers rather than sending an invalid token.
+    ///
+    /// A channel that can't be resubscribed doesn't stop the others; its error is sent to
+    /// the `on_error` handlers.
+    async fn resubscribe_all(&self, socket_id: &str) -> PusherResult<()> {
+        let Some(command_tx) = self.websocket_command_tx.read().await.clone() else {
+            return Err(PusherError::ConnectionError("Not connected".into()));
+        };
+        self.resubscriber().resubscribe_all(&command_tx, socket_id).await;
+        Ok(())
     }
 
-    /// Triggers an event on a channel.
+    fn resubscriber(&self) -> Resubscriber {
+        Resubscriber {
+            config: self.config.clone(),
+            auth: self.auth.clone(),
+            auth_cache: Arc::clone(&self.auth_cache),
+            http_client: self.http_client.clone(),
+            channels: Arc::clone(&self.channels),
+            error_handlers: Arc::clone(&self.error_handlers),
+        }
+    }
+
+    /// Subscribes to a channel.
+    ///
+    /// With `PusherConfig::auth_endpoint` set, private and presence channels are authorized
+    /// by the endpoint for the current socket first, which requires being connected. With
+    /// `PusherConfig::sign_auth_locally`, private channels are signed with the app secret
+    /// instead; presence channels then need `subscribe_builder` to supply the member data.
+    ///
+    /// Subscribing again to a channel that is subscribed, or still waiting for the server to
+    /// confirm it, sends nothing and returns `Ok`; use `force_resubscribe` to send the
+    /// subscription anyway. A rejected subscription is attempted again.
     ///
     /// # Arguments
     ///
-    /// * `channel` - The name of the channel to trigger the event on.
-    /// * `event` - The name of the event to trigger.
-    /// * `data` - The data to send with the event.
+    /// * `channel_name` - The name of the channel to subscribe to.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn trigger(&self, channel: &str, event: &str, data: &str) -> PusherResult<()> {
-        let url = format!(
-            "https://api-{}.pusher.com/apps/{}/events",
-            self.config.cluster, self.config.app_id
-        );
-
-        // Validate that the data is valid JSON, but keep it as a string
-        serde_json::from_str::<serde_json::Value>(data)
-            .map_err(|e| PusherError::JsonError(e))?;
-
-        let body = json!({
-            "name": event,
-            "channel": channel,
-            "data": data, // Keep data as a string
-        });
-        let path = format!("/apps/{}/events", self.config.app_id);
-        let auth_params = self.auth.authenticate_request("POST", &path, &body)?;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&body)
-            .query(&auth_params)
-            .send()
-            .await?;
-        let response_status = response.status();
-        if response_status.is_success() {
-            Ok(())
-        } else {
-            let error_body = response.text().await?;
-            Err(PusherError::ApiError(format!(
-                "Failed to trigger event: {} - {}",
-                response_status, error_body
-            )))
-        }
+    /// A `PusherResult` indicating success or failure. A failing or unreachable auth
+    /// endpoint gives a `PusherError::AuthError`.
+    pub async fn subscribe(&self, channel_name: &str) -> PusherResult<()> {
+        traced!(
+            self.subscribe_channel(channel_name, SubscribeOptions::default()),
+            "pusher.subscribe",
+            channel = %channel_name,
+            socket_id = tracing::field::Empty,
+        )
+        .await
     }
 
-    /// Triggers an event on an encrypted channel.
+    /// Subscribes to a channel even if it is already subscribed, replacing what is known
+    /// about it, such as its presence members, with what the server sends next. This is for
+    /// when the server may have dropped the subscription without saying so; reconnecting
+    /// resubscribes every channel by itself.
     ///
     /// # Arguments
     ///
-    /// * `channel` - The name of the encrypted channel to trigger the event on.
-    /// * `event` - The name of the event to trigger.
-    /// * `data` - The data to send with the event.
+    /// * `channel_name` - The name of the channel to subscribe to.
     ///
     /// # Returns
     ///
     /// A `PusherResult` indicating success or failure.
-    pub async fn trigger_encrypted(
+    pub async fn force_resubscribe(&self, channel_name: &str) -> PusherResult<()> {
+        self.channels.write().await.remove(channel_name);
+        self.subscribe(channel_name).await
+    }
+
+    /// The subscribe path every `subscribe*` method shares. See `subscribe` for how channels
+    /// are authorized and repeated subscriptions skipped.
+    async fn subscribe_channel(
         &self,
-        channel: &str,
-        event: &str,
-        data: &str,
+        channel_name: &str,
+        options: SubscribeOptions,
     ) -> PusherResult<()> {
-        let shared_secret = {
-            let encrypted_channels = self.encrypted_channels.read().await;
-            encrypted_channels
-                .get(channel)
-                .ok_or_else(|| {
-                    PusherError::ChannelError(
-                        "Channel is not subscribed or is not encrypted".to_string(),
+        self.require_realtime()?;
+        Channel::validate_name(channel_name)?;
+        if self.websocket_command_tx.read().await.is_some() {
+            let state = self
+                .channels
+                .read()
+                .await
+                .get(channel_name)
+                .map(Channel::subscription_state);
+            match state {
+                Some(SubscriptionState::Pending) => return Ok(()),
+                // Nothing will confirm it again, so anyone waiting for that is answered here.
+                Some(SubscriptionState::Subscribed) => {
+                    let pending = self.pending_subscriptions.lock().unwrap().remove(channel_name);
+                    if let Some(tx) = pending {
+                        let _ = tx.send(Ok(()));
+                    }
+                    return Ok(());
+                }
+                Some(SubscriptionState::Failed) | None => {}
+            }
+        }
+        let authorize = authorizes_subscription(&self.config, channel_name)
+            || (options.always_authorize
+                && ChannelType::from_name(channel_name) != ChannelType::Public);
+        let auth = match options.auth {
+            Some(auth) => Some(AuthResponse {
+                auth,
+                channel_data: options.channel_data,
+            }),
+            None if authorize => {
+                let socket_id = self.socket_id.read().await.clone().ok_or_else(|| {
+                    PusherError::ConnectionError(
+                        "Connect before subscribing to an authenticated channel".into(),
                     )
-                })?
-                .clone()
+                })?;
+                record_field!("socket_id", &socket_id);
+                let auth = authorize_subscription(
+                    &self.config,
+                    &self.auth,
+                    &self.auth_cache,
+                    &self.http_client,
+                    channel_name,
+                    &socket_id,
+                    options.presence.as_ref(),
+                )
+                .await?;
+                Some(auth)
+            }
+            None => None,
         };
 
-        let encrypted_data = self.encrypt_data(data, &shared_secret)?;
-        self.trigger(channel, event, &encrypted_data).await
+        let mut channel = Channel::new(channel_name);
+        if auth.is_some() {
+            channel.set_authorized();
+        }
+        if options.subscription_count {
+            channel.set_counts_requested();
+        }
+        if let Some(member) = options.presence {
+            channel.set_presence_user(member);
+        }
+        // The member data an auth endpoint signed is the one the server knows us by.
+        let channel_data = auth.as_ref().and_then(|auth| auth.channel_data.as_deref());
+        if let Some(member) =
+            channel_data.and_then(|data| serde_json::from_str::<PresenceMember>(data).ok())
+        {
+            channel.set_user_id(member.user_id);
+        }
+        let mut channels = self.channels.write().await;
+        channels.insert(channel_name.to_string(), channel);
+
+        let data = subscribe_frame(
+            channel_name,
+            auth.as_ref().map(|auth| auth.auth.as_str()),
+            channel_data,
+            options.subscription_count,
+        );
+        self.send(serde_json::to_string(&data)?).await
     }
 
-    /// Triggers multiple events in a single API call.
+    /// Subscribes to a channel with handlers for its events already in place.
+    ///
+    /// The handlers are registered before the subscribe frame is sent, so events arriving
+    /// right after the subscription succeeds can't be missed, as they can when binding after
+    /// `subscribe`. Unlike `bind`, each handler only runs for events on this channel.
     ///
     /// # Arguments
     ///
-    /// * `batch_events` - A vector of `BatchEvent` structs, each containing channel, event, and data.
+    /// * `channel_name` - The name of the channel to subscribe to.
+    /// * `handlers` - Callbacks keyed by the event name they handle.
     ///
     /// # Returns
     ///
     /// A `PusherResult` indicating success or failure.
-    pub async fn trigger_batch(&self, batch_events: Vec<BatchEvent>) -> PusherResult<()> {
-        let url = format!(
-            "https://api-{}.pusher.com/apps/{}/batch_events",
-            self.config.cluster, self.config.app_id
-        );
-
-        let events: Vec<serde_json::Value> = batch_events
-            .into_iter()
-            .map(|event| {
-                json!({
-                    "channel": event.channel,
-                    "name": event.event,
-                    "data": event.data
-                })
-            })
-            .collect();
-
-        let body = json!({ "batch": events });
-        let path = format!("/apps/{}/batch_events", self.config.app_id);
-        let auth_params = self.auth.authenticate_request("POST", &path, &body)?;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&body)
-            .query(&auth_params)
-            .send()
-            .await?;
+    pub async fn subscribe_with_handlers(
+        &self,
+        channel_name: &str,
+        handlers: HashMap<String, EventCallback>,
+    ) -> PusherResult<()> {
+        self.require_realtime()?;
+        Channel::validate_name(channel_name)?;
+        self.register_channel_handlers(channel_name, handlers).await;
+        self.subscribe_channel(channel_name, SubscribeOptions::default()).await
+    }
 
-        let response_status = response.status();
-        if response_status.is_success() {
-            Ok(())
-        } else {
-            let error_body = response.text().await?;
-            Err(PusherError::ApiError(format!(
-                "Failed to trigger batch events: {} - {}",
-                response_status, error_body
-            )))
+    /// Binds `handlers` so that each only runs for events on `channel_name`.
+    async fn register_channel_handlers(
+        &self,
+        channel_name: &str,
+        handlers: impl IntoIterator<Item = (String, EventCallback)>,
+    ) {
+        self.ensure_dispatcher();
+        let mut event_handlers = self.event_handlers.write().await;
+        for (event_name, callback) in handlers {
+            event_handlers
+                .entry(event_name)
+                .or_default()
+                .push(EventHandler::for_channel(channel_name, callback));
         }
     }
 
-    /// Binds a callback to an event.
+    /// Starts composing a subscription to a channel with all its options.
+    ///
+    /// The builder covers what the other `subscribe*` methods do separately: presence data,
+    /// encryption, an auth token, handlers bound before the frame is sent, subscription
+    /// counts and waiting for the server's confirmation. The combination is checked when
+    /// `SubscribeBuilder::subscribe` is awaited, before anything is sent.
     ///
     /// # Arguments
     ///
-    /// * `event_name` - The name of the event to bind to.
-    /// * `callback` - The callback function to execute when the event occurs.
+    /// * `channel_name` - The name of the channel to subscribe to.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    ///
-    pub async fn bind<F>(&self, event_name: &str, callback: F) -> PusherResult<()>
-    where
-        F: Fn(Event) + Send + Sync + 'static,
-    {
-        let mut handlers = self.event_handlers.write().await;
-        handlers
-            .entry(event_name.to_string())
-            .or_insert_with(Vec::new)
-            .push(Box::new(callback));
-        Ok(())
+    /// A `SubscribeBuilder` for the channel.
+    pub fn subscribe_builder(&self, channel_name: &str) -> SubscribeBuilder<'_> {
+        SubscribeBuilder::new(self, channel_name)
     }
 
-    fn get_websocket_url(&self) -> PusherResult<Url> {
-        let scheme = if self.config.use_tls { "wss" } else { "ws" };
-        info!("Connecting to Pusher using scheme: {}", scheme);
-
-        let default_host = format!("ws-{}.pusher.com", self.config.cluster);
-        let host = self.config.host.as_deref().unwrap_or(&default_host);
+    /// Subscribes to a channel and waits for the server to confirm it, unlike `subscribe`,
+    /// which returns once the frame is sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel to subscribe to.
+    /// * `timeout` - How long to wait for the confirmation.
+    ///
+    /// # Returns
+    ///
+    /// `Ok` once `subscription_succeeded` arrives. A `subscription_error` gives a
+    /// `PusherError::SubscriptionError` carrying its status, e.g. 401 or 403, and no answer
+    /// within `timeout` a `PusherError::TimeoutError`.
+    pub async fn subscribe_and_wait(
+        &self,
+        channel_name: &str,
+        timeout: Duration,
+    ) -> PusherResult<()> {
+        let confirmation = self.expect_confirmation(channel_name);
+        if let Err(e) = self.subscribe(channel_name).await {
+            self.pending_subscriptions.lock().unwrap().remove(channel_name);
+            return Err(e);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        self.await_confirmation(channel_name, confirmation, deadline, timeout)
+            .await
+    }
 
-        let url = format!(
-            "{}://{}/app/{}?protocol=7",
-            scheme, host, self.config.app_key
-        );
+    /// Subscribes to several channels and waits for the server to confirm each one.
+    ///
+    /// All subscribe frames are sent back to back before waiting, and the confirmations are
+    /// then awaited together, so subscribing to many channels takes about one round trip.
+    /// Each channel's result reflects what the server answered: `Ok` on
+    /// `subscription_succeeded`, the `PusherError::SubscriptionError` from a
+    /// `subscription_error` (carrying its status, e.g. 401 or 403), or a
+    /// `PusherError::TimeoutError` if neither arrived in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_names` - The names of the channels to subscribe to.
+    /// * `timeout` - How long to wait for all confirmations, measured from when the frames
+    ///   were sent.
+    ///
+    /// # Returns
+    ///
+    /// The result for each channel, in the order they were given.
+    pub async fn subscribe_many(
+        &self,
+        channel_names: &[&str],
+        timeout: Duration,
+    ) -> Vec<(String, PusherResult<()>)> {
+        let mut pending = Vec::with_capacity(channel_names.len());
+        for &channel_name in channel_names {
+            let rx = self.expect_confirmation(channel_name);
+            let sent = self.subscribe(channel_name).await;
+            if sent.is_err() {
+                self.pending_subscriptions.lock().unwrap().remove(channel_name);
+            }
+            pending.push((channel_name, sent.map(|_| rx)));
+        }
 
-        info!("WebSocket URL: {}", url);
-        Url::parse(&url).map_err(PusherError::from)
+        let deadline = tokio::time::Instant::now() + timeout;
+        let confirmations = pending.into_iter().map(|(channel_name, confirmation)| async move {
+            let result = match confirmation {
+                Ok(rx) => self.await_confirmation(channel_name, rx, deadline, timeout).await,
+                Err(e) => Err(e),
+            };
+            (channel_name.to_string(), result)
+        });
+        futures_util::future::join_all(confirmations).await
     }
 
-    fn generate_shared_secret(&self, channel_name: &str) -> Vec<u8> {
-        let mut hmac = Hmac::<Sha256>::new_from_slice(self.config.app_secret.as_bytes())
-            .expect("HMAC can take key of any size");
-        hmac.update(channel_name.as_bytes());
-        hmac.finalize().into_bytes().to_vec()
+    /// Registers a pending subscription, to be resolved when the server confirms or rejects
+    /// it.
+    fn expect_confirmation(
+        &self,
+        channel_name: &str,
+    ) -> tokio::sync::oneshot::Receiver<PusherResult<()>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.pending_subscriptions
+            .lock()
+            .unwrap()
+            .insert(channel_name.to_string(), tx);
+        rx
     }
 
-    fn encrypt_data(&self, data: &str, shared_secret: &[u8]) -> PusherResult<String> {
-        let iv = rand::thread_rng().gen::<[u8; 16]>();
-        let cipher = Encryptor::<Aes256>::new(shared_secret.into(), &iv.into());
-
-        let plaintext = data.as_bytes();
-        let mut buffer = vec![0u8; plaintext.len() + 16]; // Add space for padding
-        buffer[..plaintext.len()].copy_from_slice(plaintext);
-
-        let ciphertext_len = cipher
-            .encrypt_padded_mut::<Pkcs7>(&mut buffer, plaintext.len())
-            .map_err(|e| PusherError::EncryptionError(e.to_string()))?
-            .len();
-
-        let mut result = iv.to_vec();
-        result.extend_from_slice(&buffer[..ciphertext_len]);
-
-        Ok(STANDARD.encode(result))
+    /// Waits for the answer to a pending subscription until `deadline`. `timeout` is only
+    /// used to describe the failure.
+    async fn await_confirmation(
+        &self,
+        channel_name: &str,
+        confirmation: tokio::sync::oneshot::Receiver<PusherResult<()>>,
+        deadline: tokio::time::Instant,
+        timeout: Duration,
+    ) -> PusherResult<()> {
+        match tokio::time::timeout_at(deadline, confirmation).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(PusherError::ConnectionError(format!(
+                "Connection closed before subscription to '{}' was confirmed",
+                channel_name
+            ))),
+            Err(_) => {
+                self.pending_subscriptions.lock().unwrap().remove(channel_name);
+                Err(PusherError::TimeoutError(format!(
+                    "Subscription to '{}' was not confirmed within {:?}",
+                    channel_name, timeout
+                )))
+            }
+        }
     }
 
-
-    /// Gets the current connection state.
+    /// Subscribes to a channel and asks the server to send subscription counts for it.
+    ///
+    /// Pusher Channels sends `pusher_internal:subscription_count` events automatically once
+    /// subscription counting is enabled in the app settings, so a plain `subscribe` is
+    /// enough there. Some self-hosted servers only send counts to subscriptions that opt in
+    /// with `"subscription_count": true`, which this method includes in the subscribe frame.
+    /// Otherwise it subscribes as `subscribe` does, and the opt-in is sent again when
+    /// resubscribing after a reconnect.
+    ///
+    /// Counts are exposed through `subscription_count` and delivered to handlers bound to
+    /// `pusher:subscription_count`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel to subscribe to.
     ///
     /// # Returns
     ///
-    /// The current `ConnectionState`.
-    pub async fn get_connection_state(&self) -> ConnectionState {
-        self.state.read().await.clone()
+    /// A `PusherResult` indicating success or failure.
+    pub async fn subscribe_with_count(&self, channel_name: &str) -> PusherResult<()> {
+        let options = SubscribeOptions {
+            subscription_count: true,
+            ..Default::default()
+        };
+        self.subscribe_channel(channel_name, options).await
     }
 
-    /// Gets a list of currently subscribed channels.
+    /// Checks whether the server has confirmed the subscription to a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel.
     ///
     /// # Returns
     ///
-    /// A vector of channel names.
-    pub async fn get_subscribed_channels(&self) -> Vec<String> {
-        self.channels.read().await.keys().cloned().collect()
+    /// `true` once `subscription_succeeded` was received for the channel on the current
+    /// connection, `false` otherwise.
+    pub async fn is_subscribed(&self, channel_name: &str) -> bool {
+        self.subscription_state(channel_name).await == Some(SubscriptionState::Subscribed)
     }
 
-    /// Sends a test event through the client.
+    /// Gets the state of the subscription to a channel.
+    ///
+    /// Every channel type is confirmed the same way, so this is accurate for public and
+    /// private channels as well as presence ones.
     ///
     /// # Arguments
     ///
-    /// * `event` - The event to send.
+    /// * `channel_name` - The name of the channel.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn send_test_event(&self, event: Event) -> PusherResult<()> {
-        self.event_tx
-            .send(event)
+    /// The `SubscriptionState`, or `None` if the channel isn't subscribed.
+    pub async fn subscription_state(&self, channel_name: &str) -> Option<SubscriptionState> {
+        self.channels
+            .read()
             .await
-            .map_err(|e| PusherError::WebSocketError(e.to_string()))
+            .get(channel_name)
+            .map(Channel::subscription_state)
     }
 
-    /// Gets the current socket ID if connected, or None if not connected.
+    /// Gets the latest subscriber count reported by the server for a channel.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// A `PusherResult` containing the socket ID string if connected, or None if not connected.
-    pub async fn get_socket_id(&self) -> PusherResult<Option<String>> {
-        Ok(self.socket_id.read().await.clone())
+    /// * `channel_name` - The name of the channel.
+    ///
+    /// # Returns
+    ///
+    /// The count, or `None` if the channel isn't subscribed or no count was received yet.
+    pub async fn subscription_count(&self, channel_name: &str) -> Option<usize> {
+        self.channels
+            .read()
+            .await
+            .get(channel_name)
+            .and_then(Channel::subscription_count)
     }
 
-    /// Binds a callback to be executed when the client connects to Pusher.
+    /// Binds a callback to the subscriber counts the server pushes for one channel, as
+    /// enabled by `subscribe_with_count`, for showing live "N people watching" counts.
     ///
     /// # Arguments
     ///
-    /// * `callback` - A function to be called when the connection is established
+    /// * `channel_name` - The channel to watch.
+    /// * `callback` - The callback function to execute with each new count.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn on_connect<F>(&self, callback: F) -> PusherResult<()>
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn on_subscription_count<F>(
+        &self,
+        channel_name: &str,
+        callback: F,
+    ) -> PusherResult<BindingId>
     where
-        F: Fn() + Send + Sync + 'static,
+        F: Fn(usize) + Send + Sync + 'static,
     {
-        self.bind("pusher:connection_established", move |_| {
-            callback();
+        self.bind_channel(channel_name, "pusher:subscription_count", move |event| {
+            let data = SystemEvent::from_event(&event).map(|event| event.data);
+            if let Some(SystemEventData::SubscriptionCount { subscription_count }) = data {
+                callback(subscription_count as usize);
+            }
         })
         .await
     }
 
-    /// Binds a callback to be executed when the client disconnects from Pusher.
+    /// Gets the members currently present on a presence channel.
+    ///
+    /// The roster comes from the channel's `subscription_succeeded` and is kept up to date
+    /// by `member_added` and `member_removed`, keyed by user id with each member's user info.
+    /// A member announced twice is only listed once, with the latest info.
     ///
     /// # Arguments
     ///
-    /// * `callback` - A function to be called when the connection is lost
+    /// * `channel_name` - The name of the presence channel.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure.
-    pub async fn on_disconnect<F>(&self, callback: F) -> PusherResult<()>
-    where
-        F: Fn() + Send + Sync + 'static,
-    {
-        self.bind("pusher:disconnected", move |_| {
-            callback();
-        })
-        .await
+    /// The members, or `None` if the channel isn't subscribed or isn't a presence channel.
+    pub async fn members(&self, channel_name: &str) -> Option<HashMap<String, serde_json::Value>> {
+        self.channels
+            .read()
+            .await
+            .get(channel_name)
+            .and_then(|channel| channel.members().cloned())
     }
 
-    /// Checks if the client is currently connected to Pusher.
+    /// Gets this client's own member of a presence channel, such as to show "you" apart from
+    /// the other members. See `Channel::me`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the presence channel.
     ///
     /// # Returns
     ///
-    /// `true` if the client is connected, `false` otherwise.
-    pub async fn is_connected(&self) -> bool {
-        matches!(self.get_connection_state().await, ConnectionState::Connected)
+    /// The member this client subscribed as, or `None` until the subscription succeeds.
+    pub async fn me(&self, channel_name: &str) -> Option<PresenceMember> {
+        self.channels.read().await.get(channel_name).and_then(Channel::me)
     }
 
-    /// Authenticates a presence channel subscription.
+    /// Gets the number of members on a presence channel, which Pusher caps at
+    /// `MAX_PRESENCE_MEMBERS`.
     ///
     /// # Arguments
     ///
-    /// * `socket_id` - The socket ID obtained from the connection
-    /// * `channel` - The name of the presence channel
-    /// * `user_id` - The user ID to authenticate with
-    /// * `user_info` - Optional user information
+    /// * `channel_name` - The name of the presence channel.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` containing the authentication signature
-    pub fn authenticate_presence_channel(
-        &self,
-        socket_id: &str,
-        channel: &str,
-        user_id: &str,
-        user_info: Option<&serde_json::Value>,
-    ) -> PusherResult<String> {
-        self.auth.authenticate_presence_channel(socket_id, channel, user_id, user_info)
+    /// The member count, or `None` if the channel isn't a presence channel or isn't
+    /// subscribed, including while the server has yet to confirm the subscription and after
+    /// it rejected it.
+    pub async fn member_count(&self, channel_name: &str) -> Option<usize> {
+        let channels = self.channels.read().await;
+        let channel = channels.get(channel_name)?;
+        if channel.subscription_state() != SubscriptionState::Subscribed {
+            return None;
+        }
+        channel.members()?;
+        Some(channel.member_count())
     }
 
-    /// Subscribes to a channel with authentication.
+    /// Gets the most recent event received on a channel.
+    ///
+    /// Only available when `PusherConfig::track_last_event` is enabled. At most one event is
+    /// kept per subscribed channel, and it is dropped when the channel is unsubscribed.
     ///
     /// # Arguments
     ///
-    /// * `channel_name` - The name of the channel to subscribe to
-    /// * `auth` - The authentication signature obtained from authenticate_presence_channel
-    /// * `channel_data` - Optional channel data for presence channels
+    /// * `channel_name` - The name of the channel.
     ///
     /// # Returns
     ///
-    /// A `PusherResult` indicating success or failure
-    pub async fn subscribe_with_auth(
-        &mut self,
-        channel_name: &str,
-        auth: &str,
-        channel_data: Option<&str>,
-    ) -> PusherResult<()> {
-        let channel = Channel::new(channel_name);
-        let mut channels = self.channels.write().await;
-        channels.insert(channel_name.to_string(), channel);
+    /// The last event, or `None` if tracking is disabled or nothing was received yet.
+    pub async fn last_event(&self, channel_name: &str) -> Option<Event> {
+        self.channels
+            .read()
+            .await
+            .get(channel_name)
+            .and_then(|channel| channel.last_event().cloned())
+    }
+
+    /// Subscribes to an encrypted channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the encrypted channel to subscribe to.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn subscribe_encrypted(&self, channel_name: &str) -> PusherResult<()> {
+        self.require_realtime()?;
+        if !channel_name.starts_with("private-encrypted-") {
+            return Err(PusherError::ChannelError(
+                "Encrypted channels must start with 'private-encrypted-'".to_string(),
+            ));
+        }
+
+        let shared_secret = self.generate_shared_secret(channel_name)?;
+
+        {
+            let mut encrypted_channels = self.encrypted_channels.write().await;
+            encrypted_channels.insert(channel_name.to_string(), shared_secret);
+        }
+
+        self.subscribe(channel_name).await
+    }
+
+    /// Unsubscribes from a channel and removes its callbacks, as `unsubscribe` followed by
+    /// `unbind_channel`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel to unsubscribe from.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure. The callbacks are removed either way.
+    pub async fn unsubscribe_and_unbind(&self, channel_name: &str) -> PusherResult<()> {
+        self.unbind_channel(channel_name).await;
+        self.unsubscribe(channel_name).await
+    }
+
+    /// Unsubscribes from a channel.
+    ///
+    /// Callbacks stay bound. Those bound with `bind` and `bind_global` run for events on any
+    /// channel, and those bound to this channel, with `bind_channel` or
+    /// `subscribe_with_handlers` for example, run again if it is subscribed to later. Use
+    /// `unsubscribe_and_unbind` to remove the latter as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel to unsubscribe from.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    ///
+    pub async fn unsubscribe(&self, channel_name: &str) -> PusherResult<()> {
+        {
+            let mut channels = self.channels.write().await;
+            channels.remove(channel_name);
+        }
+
+        {
+            let mut encrypted_channels = self.encrypted_channels.write().await;
+            encrypted_channels.remove(channel_name);
+        }
 
-        let mut data = json!({
-            "event": "pusher:subscribe",
+        let data = json!({
+            "event": "pusher:unsubscribe",
             "data": {
-                "channel": channel_name,
-                "auth": auth
+                "channel": channel_name
             }
         });
 
-        if let Some(channel_data) = channel_data {
-            data["data"]["channel_data"] = serde_json::Value::String(channel_data.to_string());
-        }
-
         self.send(serde_json::to_string(&data)?).await
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Triggers an event on a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the channel to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure. With
+    /// `PusherConfig::offline_queue_size` set, `Ok` may also mean that the REST API couldn't
+    /// be reached and the event was queued; `pending_triggers` counts those still waiting.
+    pub async fn trigger(&self, channel: &str, event: &str, data: &str) -> PusherResult<()> {
+        let idempotency_key = self.auto_idempotency_key();
+        self.trigger_internal(channel, event, data, idempotency_key.as_deref(), None, &[])
+            .await
+            .map(drop)
+    }
 
-    #[tokio::test]
-    async fn test_client_creation() {
-        let config =
-            PusherConfig::from_env().expect("Failed to load Pusher configuration from environment");
-        let client = PusherClient::new(config).unwrap();
-        assert_eq!(*client.state.read().await, ConnectionState::Disconnected);
+    /// Triggers an event on a channel with data that is already a `serde_json::Value`.
+    ///
+    /// Unlike `trigger`, the data is serialized once and not parsed again to check that it
+    /// is valid JSON, which saves work on hot paths triggering many events.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the channel to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn trigger_value(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &serde_json::Value,
+    ) -> PusherResult<()> {
+        Channel::validate_name(channel)?;
+        validate_event_name(event)?;
+        let data = serde_json::to_string(data)?;
+        self.check_payload_size(&data)?;
+        let idempotency_key = self.auto_idempotency_key();
+        let trigger = self.triggers.track(self.trigger_or_queue(
+            channel,
+            event,
+            &data,
+            idempotency_key.as_deref(),
+            None,
+            &[],
+        ));
+        traced!(trigger, "pusher.trigger", channel = %channel, event = %event)
+            .await
+            .map(drop)
     }
 
-    #[tokio::test]
-    #[ignore]
-    async fn test_generate_shared_secret() {
-        let config =
-            PusherConfig::from_env().expect("Failed to load Pusher configuration from environment");
-        let client = PusherClient::new(config).unwrap();
-        let secret = client.generate_shared_secret("test-channel");
-        assert!(!secret.is_empty());
+    /// Triggers an event on a channel and returns the attributes the server reports for it,
+    /// such as whether anyone was subscribed when the event was published.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the channel to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    /// * `info` - Attributes to return: `subscription_count`, and `user_count` for presence
+    ///   channels.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `TriggerResponse`.
+    pub async fn trigger_with_info(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+        info: &[&str],
+    ) -> PusherResult<TriggerResponse> {
+        let idempotency_key = self.auto_idempotency_key();
+        self.trigger_internal(channel, event, data, idempotency_key.as_deref(), None, info)
+            .await
     }
 
-    #[tokio::test]
-    async fn test_trigger_batch() {
-        let config =
-            PusherConfig::from_env().expect("Failed to load Pusher configuration from environment");
-        let client = PusherClient::new(config).unwrap();
+    /// Triggers the same event on several channels with a single request.
+    ///
+    /// This is cheaper than one `trigger` per channel, or a `trigger_batch`, when the same
+    /// data fans out to many channels. Pusher accepts at most `MAX_TRIGGER_CHANNELS`
+    /// channels per request. The event isn't queued behind other triggers to these channels
+    /// when `ordered_publish` is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `channels` - The names of the channels to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure, with a `PusherError::ChannelError`
+    /// for an empty list or one with more than `MAX_TRIGGER_CHANNELS` channels.
+    pub async fn trigger_multi(
+        &self,
+        channels: &[&str],
+        event: &str,
+        data: &str,
+    ) -> PusherResult<()> {
+        if channels.is_empty() || channels.len() > MAX_TRIGGER_CHANNELS {
+            return Err(PusherError::ChannelError(format!(
+                "An event can be triggered on 1 to {} channels, not {}",
+                MAX_TRIGGER_CHANNELS,
+                channels.len()
+            )));
+        }
+        for channel in channels {
+            Channel::validate_name(channel)?;
+        }
+        validate_event_name(event)?;
+        self.check_payload_size(data)?;
+        let idempotency_key = self.auto_idempotency_key();
+        let trigger = self.triggers.track(async {
+            validate_json(data)?;
+            self.publish(channels, event, data, idempotency_key.as_deref(), None, &[])
+                .await
+        });
+        traced!(trigger, "pusher.trigger", channels = ?channels, event = %event)
+            .await
+            .map(drop)
+    }
 
-        let batch_events = vec![
-            BatchEvent {
-                channel: "test-channel-1".to_string(),
-                event: "test-event-1".to_string(),
-                data: "{\"message\": \"Hello from event 1\"}".to_string(),
-            },
-            BatchEvent {
-                channel: "test-channel-2".to_string(),
-                event: "test-event-2".to_string(),
-                data: "{\"message\": \"Hello from event 2\"}".to_string(),
-            },
-        ];
+    /// Sends an event to every connection signed in as a user, through the user's
+    /// `#server-to-user-` channel. Only connections that called `signin` receive it.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user to send the event to.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn send_to_user(&self, user_id: &str, event: &str, data: &str) -> PusherResult<()> {
+        validate_user_id(user_id)?;
+        validate_event_name(event)?;
+        self.check_payload_size(data)?;
+        // `#` isn't allowed in the channel names apps choose, so this skips `validate_name`.
+        let channel = format!("#server-to-user-{}", user_id);
+        let idempotency_key = self.auto_idempotency_key();
+        self.triggers
+            .track(async {
+                validate_json(data)?;
+                self.publish(&[&channel], event, data, idempotency_key.as_deref(), None, &[])
+                    .await
+            })
+            .await
+            .map(drop)
+    }
 
-        let result = client.trigger_batch(batch_events).await;
-        assert!(result.is_ok());
+    /// Disconnects every connection signed in as a user, for example when the user logs
+    /// out or is banned. Clients may reconnect, but have to sign in again.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user whose connections are terminated.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn terminate_user_connections(&self, user_id: &str) -> PusherResult<()> {
+        validate_user_id(user_id)?;
+        let path = format!(
+            "/apps/{}/users/{}/terminate_connections",
+            self.config.app_id, user_id
+        );
+        let request = self
+            .build_post_request(&path, &json!({}), None, self.config.request_timeout)
+            .await?;
+        let response = self.send_rest_request(request).await?;
+        let response_status = response.status();
+        if response_status.is_success() {
+            Ok(())
+        } else {
+            let error_body = response.text().await?;
+            let context = self.rest_context("Failed to terminate user connections");
+            Err(api_error(&context, response_status, &error_body))
+        }
+    }
+
+    /// Triggers an event on a channel for every subscriber except one connection.
+    ///
+    /// The server doesn't deliver the event to `socket_id`, so the client whose action
+    /// caused the event doesn't handle it a second time.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the channel to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    /// * `socket_id` - The socket ID of the connection to exclude, such as `1234.5678`. A
+    ///   malformed one gives `InvalidArgument` without sending anything.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn trigger_excluding(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+        socket_id: &str,
+    ) -> PusherResult<()> {
+        let idempotency_key = self.auto_idempotency_key();
+        let socket_id = Some(socket_id);
+        self.trigger_internal(channel, event, data, idempotency_key.as_deref(), socket_id, &[])
+            .await
+            .map(drop)
+    }
+
+    /// Triggers an event on a channel, tagging the request with an idempotency key.
+    ///
+    /// The key is sent in the `X-Pusher-Idempotency-Key` header so that servers supporting
+    /// deduplication publish the event at most once, even if the request is retried.
+    /// Servers that don't support it simply ignore the header.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the channel to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    /// * `idempotency_key` - A key identifying this logical trigger.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn trigger_with_idempotency_key(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+        idempotency_key: &str,
+    ) -> PusherResult<()> {
+        self.trigger_internal(channel, event, data, Some(idempotency_key), None, &[])
+            .await
+            .map(drop)
+    }
+
+    async fn trigger_internal(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+        idempotency_key: Option<&str>,
+        socket_id: Option<&str>,
+        info: &[&str],
+    ) -> PusherResult<TriggerResponse> {
+        Channel::validate_name(channel)?;
+        validate_event_name(event)?;
+        self.check_payload_size(data)?;
+        if let Some(socket_id) = socket_id {
+            validate_excluded_socket_id(socket_id)?;
+        }
+        let trigger = self.triggers.track(async {
+            validate_json(data)?;
+            self.trigger_or_queue(channel, event, data, idempotency_key, socket_id, info)
+                .await
+        });
+        traced!(trigger, "pusher.trigger", channel = %channel, event = %event).await
+    }
+
+    fn check_payload_size(&self, data: &str) -> PusherResult<()> {
+        if data.len() > self.config.max_payload_size {
+            return Err(PusherError::PayloadTooLarge {
+                size: data.len(),
+                limit: self.config.max_payload_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Publishes a single event like `trigger_in_order`, but with
+    /// `PusherConfig::offline_queue_size` set, queues it if the REST API can't be reached.
+    /// While triggers are queued, new ones join the queue behind them, which a background
+    /// task sends once the API is back. A full queue is sent before anything is dropped.
+    async fn trigger_or_queue(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+        idempotency_key: Option<&str>,
+        socket_id: Option<&str>,
+        info: &[&str],
+    ) -> PusherResult<TriggerResponse> {
+        // The attributes asked for with `info` are of no use once the outage is over.
+        if !self.offline_queue.is_enabled() || !info.is_empty() {
+            return self
+                .trigger_in_order(channel, event, data, idempotency_key, socket_id, info)
+                .await;
+        }
+        let trigger = QueuedTrigger {
+            channel: channel.to_string(),
+            event: event.to_string(),
+            data: data.to_string(),
+            idempotency_key: idempotency_key.map(str::to_string),
+            socket_id: socket_id.map(str::to_string),
+        };
+        if !self.offline_queue.is_empty() {
+            // Making room by sending the queue beats dropping its oldest trigger.
+            if !self.offline_queue.is_full() || self.send_queued_triggers().await.is_err() {
+                self.queue_trigger(trigger);
+                return Ok(TriggerResponse::default());
+            }
+        }
+        match self
+            .trigger_in_order(channel, event, data, idempotency_key, socket_id, &[])
+            .await
+        {
+            Err(e) if is_network_error(&e) => {
+                warn!("Queueing event '{}' on '{}': {}", event, channel, e);
+                self.queue_trigger(trigger);
+                Ok(TriggerResponse::default())
+            }
+            result => result,
+        }
+    }
+
+    fn queue_trigger(&self, trigger: QueuedTrigger) {
+        if let Some(dropped) = self.offline_queue.push_back(trigger) {
+            self.drop_queued_trigger(&dropped);
+        }
+        self.retry_queued_triggers();
+    }
+
+    /// Starts a task that sends the queued triggers, backing off like reconnections do
+    /// while the REST API still can't be reached, until the queue is empty. Does nothing if
+    /// the task is already running.
+    fn retry_queued_triggers(&self) {
+        if !self.offline_queue.start_retrying() {
+            return;
+        }
+        let client = self.clone();
+        self.runtime.spawn(async move {
+            let policy = client.reconnect_policy();
+            let mut attempt = 0;
+            loop {
+                tokio::time::sleep(policy.delay(attempt, false)).await;
+                if client.send_queued_triggers().await.is_err() {
+                    attempt = attempt.saturating_add(1);
+                    continue;
+                }
+                client.offline_queue.stop_retrying();
+                // A trigger queued just now may have seen this task still running.
+                if client.offline_queue.is_empty() || !client.offline_queue.start_retrying() {
+                    return;
+                }
+                attempt = 0;
+            }
+        });
+    }
+
+    fn drop_queued_trigger(&self, trigger: &QueuedTrigger) {
+        warn!(
+            "Offline queue is full, dropping event '{}' on '{}'",
+            trigger.event, trigger.channel
+        );
+        self.triggers.record_failure(format!(
+            "Dropped queued event '{}' on '{}': the offline queue is full",
+            trigger.event, trigger.channel
+        ));
+    }
+
+    /// Sends the triggers queued while the REST API couldn't be reached, oldest first,
+    /// stopping with the error of the first that still can't reach it. Triggers failing for
+    /// other reasons are dropped, and reported by `flush_triggers`.
+    async fn send_queued_triggers(&self) -> PusherResult<()> {
+        let _sending = self.offline_queue.lock_sending().await;
+        while let Some(trigger) = self.offline_queue.pop_front() {
+            let result = self
+                .trigger_in_order(
+                    &trigger.channel,
+                    &trigger.event,
+                    &trigger.data,
+                    trigger.idempotency_key.as_deref(),
+                    trigger.socket_id.as_deref(),
+                    &[],
+                )
+                .await;
+            match result {
+                Ok(_) => {}
+                Err(e) if is_network_error(&e) => {
+                    if let Some(dropped) = self.offline_queue.push_front(trigger) {
+                        self.drop_queued_trigger(&dropped);
+                    }
+                    return Err(e);
+                }
+                Err(e) => self.triggers.record_failure(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn trigger_in_order(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+        idempotency_key: Option<&str>,
+        socket_id: Option<&str>,
+        info: &[&str],
+    ) -> PusherResult<TriggerResponse> {
+        if !self.config.ordered_publish {
+            return self
+                .publish(&[channel], event, data, idempotency_key, socket_id, info)
+                .await;
+        }
+
+        let queue = Arc::clone(
+            self.publish_queues
+                .lock()
+                .unwrap()
+                .entry(channel.to_string())
+                .or_default(),
+        );
+        let result = {
+            let _turn = queue.lock().await;
+            self.publish(&[channel], event, data, idempotency_key, socket_id, info)
+                .await
+        };
+        drop(queue);
+
+        // Forget the channel's queue once no other trigger is waiting on it.
+        let mut queues = self.publish_queues.lock().unwrap();
+        if queues
+            .get(channel)
+            .is_some_and(|queue| Arc::strong_count(queue) == 1)
+        {
+            queues.remove(channel);
+        }
+        result
+    }
+
+    async fn publish(
+        &self,
+        channels: &[&str],
+        event: &str,
+        data: &str,
+        idempotency_key: Option<&str>,
+        socket_id: Option<&str>,
+        info: &[&str],
+    ) -> PusherResult<TriggerResponse> {
+        let mut body = json!({
+            "name": event,
+            "data": data, // Keep data as a string
+        });
+        match channels {
+            [channel] => body["channel"] = json!(channel),
+            _ => body["channels"] = json!(channels),
+        }
+        if let Some(socket_id) = socket_id {
+            body["socket_id"] = json!(socket_id);
+        }
+        if !info.is_empty() {
+            body["info"] = json!(info.join(","));
+        }
+        let path = format!("/apps/{}/events", self.config.app_id);
+
+        let request = self
+            .build_post_request(&path, &body, idempotency_key, self.config.trigger_timeout)
+            .await?;
+        let response = self.send_rest_request(request).await?;
+        let response_status = response.status();
+        if response_status.is_success() {
+            // Without `info` the body is empty or `{}`, so don't insist on parsing it.
+            if info.is_empty() {
+                return Ok(TriggerResponse::default());
+            }
+            Ok(response.json().await?)
+        } else {
+            let error_body = response.text().await?;
+            let context = self.rest_context("Failed to trigger event");
+            Err(api_error(&context, response_status, &error_body))
+        }
+    }
+
+    /// Triggers an event on an encrypted channel.
+    ///
+    /// The ciphertext is published as a JSON string, which subscribers of the channel
+    /// decrypt before their handlers see it.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the encrypted channel to trigger the event on.
+    /// * `event` - The name of the event to trigger.
+    /// * `data` - The data to send with the event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn trigger_encrypted(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+    ) -> PusherResult<()> {
+        let shared_secret = {
+            let encrypted_channels = self.encrypted_channels.read().await;
+            encrypted_channels
+                .get(channel)
+                .ok_or_else(|| {
+                    PusherError::ChannelError(
+                        "Channel is not subscribed or is not encrypted".to_string(),
+                    )
+                })?
+                .clone()
+        };
+
+        let encrypted_data = self.encrypt_data(data, &shared_secret)?;
+        self.trigger(channel, event, &serde_json::to_string(&encrypted_data)?)
+            .await
+    }
+
+    /// Triggers a client event over the websocket, like `channel.trigger` in pusher-js.
+    ///
+    /// Client events go straight to the other subscribers without passing through the REST
+    /// API. Pusher only accepts them on subscribed private and presence channels, and only
+    /// when client events are enabled for the app.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The name of the private or presence channel.
+    /// * `event` - The name of the event, which must start with `client-`.
+    /// * `data` - The event data, as JSON.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn trigger_client_event(
+        &self,
+        channel: &str,
+        event: &str,
+        data: &str,
+    ) -> PusherResult<()> {
+        self.require_realtime()?;
+        Channel::validate_name(channel)?;
+        validate_event_name(event)?;
+        if !event.starts_with("client-") {
+            return Err(PusherError::EventError(format!(
+                "Client event '{}' must start with 'client-'",
+                event
+            )));
+        }
+        if !matches!(
+            ChannelType::from_name(channel),
+            ChannelType::Private | ChannelType::Presence
+        ) {
+            return Err(PusherError::ChannelError(format!(
+                "Client events can only be triggered on private and presence channels, not '{}'",
+                channel
+            )));
+        }
+        if !self.is_subscribed(channel).await {
+            return Err(PusherError::ChannelError(format!(
+                "Not subscribed to '{}'",
+                channel
+            )));
+        }
+        let data: serde_json::Value = serde_json::from_str(data)?;
+        let frame = json!({
+            "event": event,
+            "channel": channel,
+            "data": data,
+        });
+        self.send(serde_json::to_string(&frame)?).await
+    }
+
+    /// Signs this connection in as a user by sending `pusher:signin`, which server-to-user
+    /// events and `#server-to-user-` channels depend on. The sign-in is signed with the app
+    /// secret, and the server answers with `pusher:signin_success`, or a `pusher:error` if
+    /// it rejects it.
+    ///
+    /// The sign-in is bound to the socket ID, so it has to be repeated after reconnecting.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_data` - The user to sign in as.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating whether the frame was sent.
+    pub async fn signin(&self, user_data: &UserData) -> PusherResult<()> {
+        self.require_realtime()?;
+        let Some(socket_id) = self.socket_id.read().await.clone() else {
+            return Err(PusherError::ConnectionError("Not connected".into()));
+        };
+        let response = self.auth.authenticate_user(&socket_id, user_data)?;
+        let frame = json!({
+            "event": "pusher:signin",
+            "data": {
+                "auth": response.auth,
+                "user_data": response.user_data,
+            },
+        });
+        self.send(serde_json::to_string(&frame)?).await
+    }
+
+    /// Triggers multiple events in a single API call.
+    ///
+    /// Batches of more than `MAX_BATCH_EVENTS` events are split into several requests, a few
+    /// of which are sent at a time. The events of a request that fails are listed in
+    /// `BatchResult::failures` along with those the server rejected, so they can be retried
+    /// on their own. The call only fails if no request got through at all.
+    ///
+    /// Unlike earlier versions, a batch that was only partly published still returns `Ok`,
+    /// so check `BatchResult::failures` rather than relying on `?`. These failures are also
+    /// reported by `flush_triggers` and `shutdown`.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_events` - A vector of `BatchEvent` structs, each containing channel, event, and data.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing a `BatchResult` that reports, for each event in the order
+    /// given, whether it was accepted and any info attributes the server returned, and which
+    /// events failed by index.
+    pub async fn trigger_batch(&self, batch_events: Vec<BatchEvent>) -> PusherResult<BatchResult> {
+        for event in &batch_events {
+            Channel::validate_name(&event.channel)?;
+            validate_event_name(&event.event)?;
+            self.check_payload_size(&event.data)?;
+            if let Some(socket_id) = &event.socket_id {
+                validate_excluded_socket_id(socket_id)?;
+            }
+        }
+        traced!(
+            self.triggers.track(async {
+                let result = self.publish_batches(batch_events).await?;
+                self.record_batch_failures(&result);
+                Ok(result)
+            }),
+            "pusher.trigger_batch",
+            events = batch_events.len(),
+        )
+        .await
+    }
+
+    /// Remembers the events of `result` that weren't published for `flush_triggers`, as a
+    /// caller only checking for `Err` wouldn't notice them.
+    fn record_batch_failures(&self, result: &BatchResult) {
+        for (index, error) in &result.failures {
+            let event = &result.events[*index];
+            self.triggers.record_failure(format!(
+                "batch event {} ('{}' on '{}'): {}",
+                index, event.event, event.channel, error
+            ));
+        }
+    }
+
+    /// Publishes `batch_events` in requests of at most `MAX_BATCH_EVENTS` events.
+    async fn publish_batches(&self, batch_events: Vec<BatchEvent>) -> PusherResult<BatchResult> {
+        if batch_events.len() <= MAX_BATCH_EVENTS {
+            return self.publish_batch(batch_events).await;
+        }
+        let results: Vec<_> = futures_util::stream::iter(batch_events.chunks(MAX_BATCH_EVENTS))
+            .map(|chunk| self.publish_batch(chunk.to_vec()))
+            .buffered(BATCH_CONCURRENCY)
+            .collect()
+            .await;
+
+        let all_failed = results.iter().all(Result::is_err);
+        let mut combined = BatchResult::default();
+        for (chunk, result) in batch_events.chunks(MAX_BATCH_EVENTS).zip(results) {
+            match result {
+                Ok(result) => combined.append(result),
+                // Nothing was published, so there's nothing partial to report.
+                Err(e) if all_failed => return Err(e),
+                Err(e) => combined.append(BatchResult::failed(chunk, e)),
+            }
+        }
+        Ok(combined)
+    }
+
+    async fn publish_batch(&self, batch_events: Vec<BatchEvent>) -> PusherResult<BatchResult> {
+        let events: Vec<serde_json::Value> = batch_events
+            .iter()
+            .map(|event| {
+                let mut body = json!({
+                    "channel": event.channel,
+                    "name": event.event,
+                    "data": event.data
+                });
+                if let Some(socket_id) = &event.socket_id {
+                    body["socket_id"] = json!(socket_id);
+                }
+                body
+            })
+            .collect();
+
+        let body = json!({ "batch": events });
+        let path = format!("/apps/{}/batch_events", self.config.app_id);
+        let idempotency_key = self.auto_idempotency_key();
+
+        let request = self
+            .build_post_request(
+                &path,
+                &body,
+                idempotency_key.as_deref(),
+                self.config.batch_timeout,
+            )
+            .await?;
+        let response = self.send_rest_request(request).await?;
+
+        let response_status = response.status();
+        if response_status.is_success() {
+            let response_body = response.text().await?;
+            let response_json = serde_json::from_str(&response_body).unwrap_or_default();
+            Ok(BatchResult::from_response(&batch_events, &response_json))
+        } else {
+            let error_body = response.text().await?;
+            let context = self.rest_context("Failed to trigger batch events");
+            Err(api_error(&context, response_status, &error_body))
+        }
+    }
+
+    /// Lists the occupied channels of the app through the REST API.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter_prefix` - Only list channels whose names start with this, such as `presence-`.
+    /// * `info` - Attributes to include for each channel. Pusher only allows `user_count`,
+    ///   for presence channels.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `ChannelList`, with every listed channel `occupied`.
+    pub async fn get_channels(
+        &self,
+        filter_prefix: Option<&str>,
+        info: &[&str],
+    ) -> PusherResult<ChannelList> {
+        let mut query = BTreeMap::new();
+        if let Some(prefix) = filter_prefix {
+            query.insert("filter_by_prefix".to_string(), prefix.to_string());
+        }
+        if !info.is_empty() {
+            query.insert("info".to_string(), info.join(","));
+        }
+        let path = format!("/apps/{}/channels", self.config.app_id);
+        let mut list: ChannelList = self.get_channel_resource(&path, query).await?;
+        for channel in list.channels.values_mut() {
+            channel.occupied = true;
+        }
+        Ok(list)
+    }
+
+    /// Fetches the state of one channel through the REST API.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel.
+    /// * `info` - Attributes to include: `user_count` (presence channels only) and
+    ///   `subscription_count`.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `ChannelInfo`, or a `PusherError::ChannelError` if the
+    /// channel name is invalid.
+    pub async fn get_channel(
+        &self,
+        channel_name: &str,
+        info: &[&str],
+    ) -> PusherResult<ChannelInfo> {
+        Channel::validate_name(channel_name)?;
+        let mut query = BTreeMap::new();
+        if !info.is_empty() {
+            query.insert("info".to_string(), info.join(","));
+        }
+        let path = format!("/apps/{}/channels/{}", self.config.app_id, channel_name);
+        self.get_channel_resource(&path, query).await
+    }
+
+    /// Fetches the ids of the users on a presence channel through the REST API.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the presence channel.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the user ids, or a `PusherError::ChannelError` if the
+    /// channel name is invalid or not that of a presence channel.
+    pub async fn get_channel_users(&self, channel_name: &str) -> PusherResult<Vec<String>> {
+        Channel::validate_name(channel_name)?;
+        Self::require_presence(channel_name)?;
+        let path = format!("/apps/{}/channels/{}/users", self.config.app_id, channel_name);
+        let users: ChannelUsers = self.get_channel_resource(&path, BTreeMap::new()).await?;
+        Ok(users.users.into_iter().map(|user| user.id).collect())
+    }
+
+    /// Binds a callback to an event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_name` - The name of the event to bind to.
+    /// * `callback` - The callback function to execute when the event occurs.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    ///
+    pub async fn bind<F>(&self, event_name: &str, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        let handler = EventHandler::new(callback);
+        let id = handler.id;
+        let mut handlers = self.event_handlers.write().await;
+        handlers
+            .entry(event_name.to_string())
+            .or_insert_with(Vec::new)
+            .push(handler);
+        Ok(id)
+    }
+
+    /// Binds an async callback to an event. The dispatcher awaits the returned future
+    /// before handling the next event, so slow handlers hold back the events after them
+    /// rather than piling up as spawned tasks.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_name` - The name of the event to bind to.
+    /// * `callback` - The callback function to execute when the event occurs.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_async<F, Fut>(
+        &self,
+        event_name: &str,
+        callback: F,
+    ) -> PusherResult<BindingId>
+    where
+        F: Fn(Event) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        let handler = EventHandler::new_async(callback);
+        let id = handler.id;
+        self.event_handlers
+            .write()
+            .await
+            .entry(event_name.to_string())
+            .or_default()
+            .push(handler);
+        Ok(id)
+    }
+
+    /// Gets a stream of every incoming event, for consuming events in a loop instead of
+    /// binding callbacks. Streams see the same events as `bind_global` handlers, after
+    /// decryption, and any number of them can be open at once.
+    ///
+    /// A stream that falls too far behind skips the events it missed and logs how many.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the stream, which ends once the client and its connection
+    /// are gone.
+    pub fn events(&self) -> PusherResult<impl Stream<Item = Event> + Send + 'static> {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        let rx = self.event_stream_tx.subscribe();
+        Ok(futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Event stream fell behind, skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Binds a callback to an event on one channel only, like `channel.bind` in pusher-js.
+    ///
+    /// Events with the same name on other channels, or without a channel, are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The channel the event must arrive on.
+    /// * `event_name` - The name of the event to bind to.
+    /// * `callback` - The callback function to execute when the event occurs.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_channel<F>(
+        &self,
+        channel_name: &str,
+        event_name: &str,
+        callback: F,
+    ) -> PusherResult<BindingId>
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        let handler = EventHandler::for_channel(channel_name, callback);
+        let id = handler.id;
+        self.event_handlers
+            .write()
+            .await
+            .entry(event_name.to_string())
+            .or_default()
+            .push(handler);
+        Ok(id)
+    }
+
+    /// Removes every callback bound to an event, including the ones registered for it by
+    /// `subscribe_with_handlers`. Unbinding an event without callbacks does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_name` - The name of the event to unbind.
+    pub async fn unbind(&self, event_name: &str) {
+        self.event_handlers.write().await.remove(event_name);
+    }
+
+    /// Removes every event callback, including the ones bound with `bind_global`,
+    /// `bind_system`, `on_connect` and `on_disconnect`. Error handlers registered with
+    /// `on_error` are kept.
+    pub async fn unbind_all(&self) {
+        self.event_handlers.write().await.clear();
+        self.global_handlers.write().await.clear();
+    }
+
+    /// Removes a single callback returned by one of the `bind*` methods.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id returned when the callback was bound.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the callback was found and removed, `false` if it had already been removed.
+    pub async fn unbind_handler(&self, id: BindingId) -> bool {
+        let mut handlers = self.event_handlers.write().await;
+        for (event_name, callbacks) in handlers.iter_mut() {
+            if let Some(index) = callbacks.iter().position(|handler| handler.id == id) {
+                callbacks.remove(index);
+                if callbacks.is_empty() {
+                    let event_name = event_name.clone();
+                    handlers.remove(&event_name);
+                }
+                return true;
+            }
+        }
+        drop(handlers);
+
+        let mut global_handlers = self.global_handlers.write().await;
+        let count = global_handlers.len();
+        global_handlers.retain(|handler| handler.id != id);
+        global_handlers.len() != count
+    }
+
+    /// Removes every callback bound to one channel, with `bind_channel`,
+    /// `subscribe_with_handlers`, `on_member_added` and the like. Callbacks bound with `bind`
+    /// or `bind_global` are kept, as they aren't tied to a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The channel whose callbacks to remove.
+    ///
+    /// # Returns
+    ///
+    /// How many callbacks were removed.
+    pub async fn unbind_channel(&self, channel_name: &str) -> usize {
+        let mut removed = 0;
+        self.event_handlers.write().await.retain(|_, callbacks| {
+            let count = callbacks.len();
+            callbacks.retain(|handler| handler.channel.as_deref() != Some(channel_name));
+            removed += count - callbacks.len();
+            !callbacks.is_empty()
+        });
+        removed
+    }
+
+    /// Binds a callback that receives the event data deserialized into `T`.
+    ///
+    /// If the data doesn't match `T`, the callback is skipped and a
+    /// `PusherError::DeserializeError` naming the event is sent to the `on_error` handlers.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_name` - The name of the event to bind to.
+    /// * `callback` - The callback function to execute with the parsed data.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_typed<T, F>(&self, event_name: &str, callback: F) -> PusherResult<BindingId>
+    where
+        T: DeserializeOwned,
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let error_handlers = Arc::clone(&self.error_handlers);
+        self.bind(event_name, move |event| match event.parse_data::<T>() {
+            Ok(data) => callback(data),
+            Err(e) => report_error(&error_handlers, e),
+        })
+        .await
+    }
+
+    /// Binds a callback to every system event (`pusher:*` and `pusher_internal:*`), parsed
+    /// into a `SystemEvent` so it can be matched on its `SystemEventData` payload.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - The callback function to execute for each system event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_system<F>(&self, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn(SystemEvent) + Send + Sync + 'static,
+    {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        let handler = EventHandler::new(move |event| {
+            if let Some(system_event) = SystemEvent::from_event(&event) {
+                callback(system_event);
+            }
+        });
+        let id = handler.id;
+        self.global_handlers.write().await.push(handler);
+        Ok(id)
+    }
+
+    /// Binds a callback to every incoming event, whatever its name or channel, like
+    /// `bind_global` in pusher-js. System events such as `pusher:connection_established` are
+    /// included, so the connection lifecycle can be observed too. Global callbacks run after
+    /// the ones bound to the event's name.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - The callback function to execute for each event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_global<F>(&self, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        let handler = EventHandler::new(callback);
+        let id = handler.id;
+        self.global_handlers.write().await.push(handler);
+        Ok(id)
+    }
+
+    /// Binds a callback to the member changes of presence channels.
+    ///
+    /// The callback receives the channel name and a `MemberDiff` each time members join or
+    /// leave. After reconnecting, the fresh roster from `subscription_succeeded` is compared
+    /// with the one from before, so only members that actually changed are reported.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - The callback function to execute with each diff.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_member_diff<F>(&self, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn(String, MemberDiff) + Send + Sync + 'static,
+    {
+        let error_handlers = Arc::clone(&self.error_handlers);
+        self.bind(MEMBER_DIFF_EVENT, move |event| {
+            match event.parse_data::<MemberDiff>() {
+                Ok(diff) => callback(event.channel.unwrap_or_default(), diff),
+                Err(e) => report_error(&error_handlers, e),
+            }
+        })
+        .await
+    }
+
+    /// Binds a callback to members joining a presence channel, like binding
+    /// `pusher:member_added` on the channel in pusher-js. The callback receives the user id
+    /// and user info from each `pusher_internal:member_added` on that channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The presence channel to watch.
+    /// * `callback` - The callback function to execute with each new member.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`, or a
+    /// `ChannelError` if the channel is not a presence channel.
+    pub async fn on_member_added<F>(
+        &self,
+        channel_name: &str,
+        callback: F,
+    ) -> PusherResult<BindingId>
+    where
+        F: Fn(String, serde_json::Value) + Send + Sync + 'static,
+    {
+        Self::require_presence(channel_name)?;
+        self.bind_channel(channel_name, "pusher_internal:member_added", move |event| {
+            if let Some(SystemEventData::MemberAdded { user_id, user_info }) =
+                SystemEvent::from_event(&event).map(|event| event.data)
+            {
+                callback(user_id, user_info);
+            }
+        })
+        .await
+    }
+
+    /// Binds a callback to members leaving a presence channel, like binding
+    /// `pusher:member_removed` on the channel in pusher-js. The callback receives the user id
+    /// from each `pusher_internal:member_removed` on that channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The presence channel to watch.
+    /// * `callback` - The callback function to execute with each departed member's id.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`, or a
+    /// `ChannelError` if the channel is not a presence channel.
+    pub async fn on_member_removed<F>(
+        &self,
+        channel_name: &str,
+        callback: F,
+    ) -> PusherResult<BindingId>
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        Self::require_presence(channel_name)?;
+        self.bind_channel(channel_name, "pusher_internal:member_removed", move |event| {
+            if let Some(SystemEventData::MemberRemoved { user_id }) =
+                SystemEvent::from_event(&event).map(|event| event.data)
+            {
+                callback(user_id);
+            }
+        })
+        .await
+    }
+
+    /// Registers a callback for errors that happen in the background, where there is no
+    /// caller to return them to, such as event data that fails to deserialize in `bind_typed`,
+    /// frames that fail to send while resubscribing or keeping the connection alive, or
+    /// malformed frames from the server, which are skipped as a `PusherError::ProtocolError`.
+    /// A `pusher:error` the server sends with a code is reported as a
+    /// `PusherError::PusherProtocolError`.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called with each error.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn on_error<F>(&self, callback: F) -> PusherResult<()>
+    where
+        F: Fn(&PusherError) + Send + Sync + 'static,
+    {
+        self.error_handlers.write().unwrap().push(Box::new(callback));
+        Ok(())
+    }
+
+    /// Registers a callback for the `pusher:error` events the server sends, for example for
+    /// an unknown app key or an exceeded rate limit. It is called with the error code and
+    /// message. Errors sent without a code are only seen by `bind_system`.
+    ///
+    /// Codes from 4000 to 4099 are fatal: the client closes the connection and moves to
+    /// `ConnectionState::Failed` instead of reconnecting.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called with the code and message of each error.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn on_pusher_error<F>(&self, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn(u16, String) + Send + Sync + 'static,
+    {
+        self.bind("pusher:error", move |event| {
+            let data: serde_json::Value = serde_json::from_str(&event.data).unwrap_or_default();
+            let Some(code) = data
+                .get("code")
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|code| u16::try_from(code).ok())
+            else {
+                return;
+            };
+            let message = data
+                .get("message")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            callback(code, message);
+        })
+        .await
+    }
+
+    /// Registers a callback for connection state changes, like binding `state_change` on
+    /// the connection in pusher-js. It is called with the previous and the new state, such
+    /// as `Connecting` and `Connected`, each time the state actually changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called with the previous and the new state.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn on_state_change<F>(&self, callback: F) -> PusherResult<()>
+    where
+        F: Fn(ConnectionState, ConnectionState) + Send + Sync + 'static,
+    {
+        self.state_handlers.write().unwrap().push(Box::new(callback));
+        Ok(())
+    }
+
+    /// Registers a callback for protocol debugging that sees every text frame the server
+    /// sends, exactly as received and before it is parsed, including frames that are then
+    /// dropped as malformed. It runs on the connection's task, so it should return quickly.
+    /// Callbacks registered from inside a callback see the frames after the current one.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called with each raw frame.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn on_raw_message<F>(&self, callback: F) -> PusherResult<()>
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.require_realtime()?;
+        self.raw_message_handlers.write().unwrap().push(Arc::new(callback));
+        Ok(())
+    }
+
+    async fn set_state(&self, new_state: ConnectionState) {
+        set_connection_state(&self.state, &self.state_handlers, new_state).await;
+    }
+
+    /// Binds a callback to an event for as long as `target` is alive.
+    ///
+    /// Only a weak reference to `target` is kept, so the handler does not prevent it from
+    /// being dropped. Once the target is gone the callback stops firing and is removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_name` - The name of the event to bind to.
+    /// * `target` - The object the callback operates on.
+    /// * `callback` - The callback function, invoked with the upgraded target and the event.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn bind_weak<T, F>(
+        &self,
+        event_name: &str,
+        target: &Arc<T>,
+        callback: F,
+    ) -> PusherResult<BindingId>
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Arc<T>, Event) + Send + Sync + 'static,
+    {
+        self.require_realtime()?;
+        let weak_target = Arc::downgrade(target);
+        let liveness: Weak<dyn Any + Send + Sync> = weak_target.clone();
+        let handler = EventHandler {
+            target: Some(liveness),
+            ..EventHandler::new(move |event| {
+                if let Some(target) = weak_target.upgrade() {
+                    callback(target, event);
+                }
+            })
+        };
+
+        let id = handler.id;
+        self.ensure_dispatcher();
+        let mut handlers = self.event_handlers.write().await;
+        handlers
+            .entry(event_name.to_string())
+            .or_default()
+            .push(handler);
+        Ok(id)
+    }
+
+    /// The REST API URL of `path`. Self-hosted servers, which serve the REST API from the
+    /// websocket host, are reached through `host` and `port` when no `rest_host` is set.
+    fn rest_url(&self, path: &str) -> String {
+        format!("{}{}", self.rest_base_url(), path)
+    }
+
+    /// The URL REST paths are appended to, including any `PusherConfig::rest_path_prefix`.
+    fn rest_base_url(&self) -> String {
+        let scheme = if self.config.use_tls { "https" } else { "http" };
+        let base = match (&self.config.rest_host, &self.config.host) {
+            (Some(host), _) => format!("{}://{}", scheme, host),
+            // Pusher's own websocket hosts don't serve the REST API.
+            (None, Some(host)) if !is_pusher_host(host) => {
+                let base = format!("{}://{}", scheme, host);
+                match (self.config.port, Url::parse(&base)) {
+                    (Some(port), Ok(mut url)) => match url.set_port(Some(port)) {
+                        Ok(()) => url.as_str().trim_end_matches('/').to_string(),
+                        Err(()) => base,
+                    },
+                    _ => base,
+                }
+            }
+            (None, _) => format!("https://api-{}.pusher.com", self.config.cluster),
+        };
+        let prefix = self
+            .config
+            .rest_path_prefix
+            .as_deref()
+            .unwrap_or("")
+            .trim_matches('/');
+        if prefix.is_empty() {
+            base
+        } else {
+            format!("{}/{}", base, prefix)
+        }
+    }
+
+    /// Describes a failed REST call, naming the API it went to so errors from different
+    /// clusters can be told apart.
+    fn rest_context(&self, action: &str) -> String {
+        format!("{} on {}", action, self.rest_base_url())
+    }
+
+    /// Builds a signed POST request against the REST API.
+    ///
+    /// `timeout` is the operation-specific timeout; without one, `PusherConfig::request_timeout`
+    /// applies.
+    ///
+    /// With `PusherConfig::tls_sni` set, the request is addressed to that name but sent to
+    /// the addresses of the configured REST host.
+    async fn build_post_request(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+        idempotency_key: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> PusherResult<reqwest::RequestBuilder> {
+        let auth_params = self.auth.authenticate_request("POST", path, body)?;
+
+        let mut url = Url::parse(&self.rest_url(path))?;
+        let client = self.rest_client(&mut url).await?;
+        let mut request = client
+            .post(url)
+            .json(body)
+            .query(&auth_params);
+        if let Some(key) = idempotency_key {
+            request = request.header(IDEMPOTENCY_KEY_HEADER, key);
+        }
+        if let Some(timeout) = timeout.or(self.config.request_timeout) {
+            request = request.timeout(timeout);
+        }
+        Ok(request)
+    }
+
+    /// Builds a signed GET request against the REST API, with `query` both sent and signed.
+    async fn build_get_request(
+        &self,
+        path: &str,
+        query: BTreeMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> PusherResult<reqwest::RequestBuilder> {
+        let params = self.auth.authenticate_query("GET", path, query, None)?;
+
+        let mut url = Url::parse(&self.rest_url(path))?;
+        let client = self.rest_client(&mut url).await?;
+        let mut request = client.get(url).query(&params);
+        if let Some(timeout) = timeout.or(self.config.request_timeout) {
+            request = request.timeout(timeout);
+        }
+        Ok(request)
+    }
+
+    /// Gets the shared HTTP client for a REST request to `url`. With `PusherConfig::tls_sni`
+    /// set, `url` is rewritten to that name, which the client resolves to the original host.
+    /// The host is resolved again after `SNI_RESOLVE_TTL`, or once connecting failed.
+    async fn rest_client(&self, url: &mut Url) -> PusherResult<reqwest::Client> {
+        let Some(sni) = &self.config.tls_sni else {
+            return Ok(self.http_client.clone());
+        };
+        let mut pinned = self.sni_http_client.lock().await;
+        let client = match &*pinned {
+            Some(pinned) if pinned.resolved_at.elapsed() < SNI_RESOLVE_TTL => {
+                pinned.client.clone()
+            }
+            _ => {
+                let host = url.host_str().unwrap_or_default().trim_matches(['[', ']']);
+                let port = url.port_or_known_default().unwrap_or(443);
+                let addrs: Vec<_> = tokio::net::lookup_host((host, port)).await?.collect();
+                let client = rest_client_builder(&self.config)?
+                    .resolve_to_addrs(sni, &addrs)
+                    .build()?;
+                *pinned = Some(PinnedClient {
+                    client: client.clone(),
+                    resolved_at: Instant::now(),
+                });
+                client
+            }
+        };
+        url.set_host(Some(sni))?;
+        Ok(client)
+    }
+
+    /// Sends a signed GET request for channel information and parses the JSON response.
+    async fn get_channel_resource<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: BTreeMap<String, String>,
+    ) -> PusherResult<T> {
+        let request = self
+            .build_get_request(path, query, self.config.channel_info_timeout)
+            .await?;
+        let response = self.send_rest_request(request).await?;
+        let response_status = response.status();
+        let response_body = response.text().await?;
+        if response_status.is_success() {
+            Ok(serde_json::from_str(&response_body)?)
+        } else {
+            let context = self.rest_context("Failed to get channel information");
+            Err(api_error(&context, response_status, &response_body))
+        }
+    }
+
+    /// Sends a REST request, retrying it as configured by `PusherConfig::http_retries`.
+    ///
+    /// Once the retries are used up, the last response or error is returned. A `429` response
+    /// becomes a `PusherError::RateLimited`, returned right away if it asks to wait longer
+    /// than `PusherConfig::http_max_retry_after`.
+    async fn send_rest_request(
+        &self,
+        mut request: reqwest::RequestBuilder,
+    ) -> PusherResult<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = if attempt < self.config.http_retries {
+                request.try_clone()
+            } else {
+                None
+            };
+            let result = self.send_rest_request_once(request).await.and_then(|response| {
+                if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    Err(PusherError::RateLimited {
+                        retry_after: retry_after(response.headers()),
+                    })
+                } else {
+                    Ok(response)
+                }
+            });
+            let Some(next_request) = retry else {
+                return result;
+            };
+            let retryable = match &result {
+                Ok(response) => self
+                    .config
+                    .http_retry_statuses
+                    .contains(&response.status().as_u16()),
+                Err(PusherError::RateLimited { retry_after }) => {
+                    *retry_after <= self.config.http_max_retry_after
+                }
+                Err(e) => is_retryable_error(e),
+            };
+            if !retryable {
+                return result;
+            }
+
+            let delay = match &result {
+                Err(PusherError::RateLimited { retry_after }) => *retry_after,
+                _ => self.config.http_retry_backoff * 2u32.saturating_pow(attempt),
+            };
+            attempt += 1;
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!("REST request failed ({}), retry {} in {:?}", reason, attempt, delay);
+            tokio::time::sleep(delay).await;
+            request = next_request;
+        }
+    }
+
+    /// Sends a REST request once, counting it and any failure in the metrics. A request that
+    /// couldn't connect drops the client pinned for `PusherConfig::tls_sni`.
+    async fn send_rest_request_once(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> PusherResult<reqwest::Response> {
+        MetricsCounters::add(&self.metrics.rest_requests, 1);
+        let result = self.send_through_breaker(request).await;
+        if !result.as_ref().is_ok_and(|response| response.status().is_success()) {
+            MetricsCounters::add(&self.metrics.rest_failures, 1);
+        }
+        if matches!(&result, Err(PusherError::HttpError(e)) if e.is_connect()) {
+            self.sni_http_client.lock().await.take();
+        }
+        result
+    }
+
+    /// Sends a REST request through the circuit breaker, if one is configured.
+    ///
+    /// Transport errors and 5xx responses count as failures; other responses are returned
+    /// to the caller as-is.
+    async fn send_through_breaker(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> PusherResult<reqwest::Response> {
+        let Some(breaker) = &self.circuit_breaker else {
+            return request.send().await.map_err(rest_error);
+        };
+
+        let permit = breaker.check()?;
+        match request.send().await.map_err(rest_error) {
+            Ok(response) if response.status().is_server_error() => {
+                permit.record_failure();
+                Ok(response)
+            }
+            Ok(response) => {
+                permit.record_success();
+                Ok(response)
+            }
+            Err(e) => {
+                permit.record_failure();
+                Err(e)
+            }
+        }
+    }
+
+    /// Gets the state of the REST circuit breaker.
+    ///
+    /// # Returns
+    ///
+    /// The current `CircuitState`, or `None` if no circuit breaker is configured.
+    pub fn circuit_breaker_state(&self) -> Option<CircuitState> {
+        self.circuit_breaker.as_ref().map(|breaker| breaker.state())
+    }
+
+    fn auto_idempotency_key(&self) -> Option<String> {
+        if self.config.idempotent_triggers {
+            Some(hex::encode(rand::thread_rng().gen::<[u8; 16]>()))
+        } else {
+            None
+        }
+    }
+
+    fn get_websocket_url(&self) -> PusherResult<Url> {
+        let scheme = if self.config.use_tls { "wss" } else { "ws" };
+        let default_host = format!("ws-{}.pusher.com", self.config.cluster);
+        let host = self.config.host.as_deref().unwrap_or(&default_host);
+        let path = self
+            .config
+            .ws_path
+            .as_deref()
+            .unwrap_or("/app/{key}")
+            .replace("{key}", &self.config.app_key);
+        let path = path.trim_start_matches('/');
+
+        let url = format!(
+            "{}://{}/{}?protocol={}",
+            scheme, host, path, self.config.protocol_version
+        );
+
+        let mut url = Url::parse(&url)?;
+        if let Some(port) = self.config.port {
+            url.set_port(Some(port)).map_err(|_| {
+                PusherError::ConfigError(format!("Can't set a port on host '{}'", host))
+            })?;
+        }
+        Ok(url)
+    }
+
+    fn generate_shared_secret(&self, channel_name: &str) -> PusherResult<Vec<u8>> {
+        if self.config.app_secret.is_empty() {
+            return Err(PusherError::ConfigError(
+                "An app secret is required for encrypted channels".to_string(),
+            ));
+        }
+        let mut hmac = Hmac::<Sha256>::new_from_slice(self.config.app_secret.as_bytes())
+            .map_err(|e| PusherError::EncryptionError(e.to_string()))?;
+        hmac.update(channel_name.as_bytes());
+        Ok(hmac.finalize().into_bytes().to_vec())
+    }
+
+    fn encrypt_data(&self, data: &str, shared_secret: &[u8]) -> PusherResult<String> {
+        let iv = rand::thread_rng().gen::<[u8; 16]>();
+        let cipher = Encryptor::<Aes256>::new_from_slices(shared_secret, &iv)
+            .map_err(|_| {
+                PusherError::EncryptionError(format!(
+                    "Shared secret must be 32 bytes, got {}",
+                    shared_secret.len()
+                ))
+            })?;
+
+        let plaintext = data.as_bytes();
+        let mut buffer = vec![0u8; plaintext.len() + 16]; // Add space for padding
+        buffer[..plaintext.len()].copy_from_slice(plaintext);
+
+        let ciphertext_len = cipher
+            .encrypt_padded_mut::<Pkcs7>(&mut buffer, plaintext.len())
+            .map_err(|e| PusherError::EncryptionError(e.to_string()))?
+            .len();
+
+        let mut result = iv.to_vec();
+        result.extend_from_slice(&buffer[..ciphertext_len]);
+
+        Ok(STANDARD.encode(result))
+    }
+
+
+    /// Gets the current connection state.
+    ///
+    /// # Returns
+    ///
+    /// The current `ConnectionState`.
+    pub async fn get_connection_state(&self) -> ConnectionState {
+        self.state.read().await.clone()
+    }
+
+    /// Gets a list of currently subscribed channels.
+    ///
+    /// This includes channels whose subscription the server hasn't confirmed yet, or may
+    /// still reject; use `is_subscribed` or `subscription_state` to tell them apart.
+    ///
+    /// # Returns
+    ///
+    /// A vector of channel names.
+    pub async fn get_subscribed_channels(&self) -> Vec<String> {
+        self.channels.read().await.keys().cloned().collect()
+    }
+
+    /// Gets the currently subscribed channels grouped by their type.
+    ///
+    /// # Returns
+    ///
+    /// A map from `ChannelType` to the names of the channels of that type.
+    /// Types with no subscribed channels are omitted.
+    pub async fn channels_by_type(&self) -> HashMap<ChannelType, Vec<String>> {
+        let mut grouped: HashMap<ChannelType, Vec<String>> = HashMap::new();
+        for name in self.channels.read().await.keys() {
+            grouped
+                .entry(ChannelType::from_name(name))
+                .or_default()
+                .push(name.clone());
+        }
+        grouped
+    }
+
+    /// Sends a test event through the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to send.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn send_test_event(&self, event: Event) -> PusherResult<()> {
+        self.require_realtime()?;
+        self.ensure_dispatcher();
+        self.event_tx
+            .send(event)
+            .await
+            .map_err(|e| PusherError::WebSocketError(e.to_string()))
+    }
+
+    /// Gets the metadata the server sent in the websocket handshake response headers, such
+    /// as a tenant id or region injected by a gateway.
+    ///
+    /// The standard upgrade headers are left out. Header names are lowercase.
+    ///
+    /// # Returns
+    ///
+    /// A map of header names to values, empty if not connected yet.
+    pub async fn connection_metadata(&self) -> HashMap<String, String> {
+        self.connection_metadata.read().await.clone()
+    }
+
+    /// Gets the current socket ID if connected, or None if not connected.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the socket ID string if connected, or None if not connected.
+    pub async fn get_socket_id(&self) -> PusherResult<Option<String>> {
+        Ok(self.socket_id.read().await.clone())
+    }
+
+    /// Binds a callback to be executed when the client connects to Pusher.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called when the connection is established
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn on_connect<F>(&self, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.bind("pusher:connection_established", move |_| {
+            callback();
+        })
+        .await
+    }
+
+    /// Binds a callback to be executed when the client disconnects from Pusher.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called when the connection is lost
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `BindingId` to pass to `unbind_handler`.
+    pub async fn on_disconnect<F>(&self, callback: F) -> PusherResult<BindingId>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.bind("pusher:disconnected", move |_| {
+            callback();
+        })
+        .await
+    }
+
+    /// Binds a callback to be executed when the client starts opening a connection with
+    /// `connect`. These state callbacks come from the client itself rather than an event the
+    /// server sends, and can't be unbound.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called when the state becomes `Connecting`
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn on_connecting<F>(&self, callback: F) -> PusherResult<()>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_state(ConnectionState::Connecting, callback).await
+    }
+
+    /// Binds a callback to be executed when a dropped connection starts being re-established.
+    /// It is called once per outage, not for every attempt, so it suits showing a
+    /// "reconnecting" indicator until the state is `Connected` again.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called when the state becomes `Reconnecting`
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn on_reconnecting<F>(&self, callback: F) -> PusherResult<()>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_state(ConnectionState::Reconnecting, callback).await
+    }
+
+    /// Binds a callback to be executed when the client gives up on the connection, because
+    /// reconnection attempts ran out or the server closed it with a fatal code.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function to be called when the state becomes `Failed`
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure.
+    pub async fn on_failed<F>(&self, callback: F) -> PusherResult<()>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_state(ConnectionState::Failed, callback).await
+    }
+
+    /// Calls `callback` each time the connection state changes to `state`.
+    async fn on_state<F>(&self, state: ConnectionState, callback: F) -> PusherResult<()>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_state_change(move |_, current| {
+            if current == state {
+                callback();
+            }
+        })
+        .await
+    }
+
+    /// Checks if the client is currently connected to Pusher.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the client is connected, `false` otherwise.
+    pub async fn is_connected(&self) -> bool {
+        matches!(self.get_connection_state().await, ConnectionState::Connected)
+    }
+
+    /// Authorizes a browser client's subscription to a private or presence channel, the way
+    /// an auth endpoint does. This is the equivalent of `authorizeChannel` in Pusher's server
+    /// libraries.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_id` - The socket ID of the client subscribing.
+    /// * `channel` - The name of the private or presence channel.
+    /// * `presence_data` - The member joining, required for presence channels.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `AuthResponse` to send back to the client as JSON.
+    pub fn authenticate_channel(
+        &self,
+        socket_id: &str,
+        channel: &str,
+        presence_data: Option<&PresenceMember>,
+    ) -> PusherResult<AuthResponse> {
+        self.auth.authenticate_channel(socket_id, channel, presence_data)
+    }
+
+    /// Signs in a browser client as a user, the server side of Pusher's user authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_id` - The socket ID of the client signing in.
+    /// * `user_data` - The user, including its `watchlist` if it has one.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `UserAuthResponse` the client sends in `pusher:signin`.
+    pub fn authenticate_user(
+        &self,
+        socket_id: &str,
+        user_data: &UserData,
+    ) -> PusherResult<UserAuthResponse> {
+        self.auth.authenticate_user(socket_id, user_data)
+    }
+
+    /// Verifies a webhook request from Pusher and parses its body.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_header` - The `X-Pusher-Key` header of the request.
+    /// * `signature_header` - The `X-Pusher-Signature` header of the request.
+    /// * `body` - The raw request body.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the `Webhook`, or a `PusherError::AuthError` if the
+    /// request isn't signed by this app.
+    pub fn parse_webhook(
+        &self,
+        key_header: &str,
+        signature_header: &str,
+        body: &[u8],
+    ) -> PusherResult<Webhook> {
+        if !self.auth.verify_webhook(key_header, signature_header, body)? {
+            return Err(PusherError::AuthError(
+                "Webhook signature does not match".to_string(),
+            ));
+        }
+        Webhook::from_slice(body)
+    }
+
+    /// Authenticates a presence channel subscription.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_id` - The socket ID obtained from the connection
+    /// * `channel` - The name of the presence channel
+    /// * `user_id` - The user ID to authenticate with
+    /// * `user_info` - Optional user information
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` containing the authentication signature
+    pub fn authenticate_presence_channel(
+        &self,
+        socket_id: &str,
+        channel: &str,
+        user_id: &str,
+        user_info: Option<&serde_json::Value>,
+    ) -> PusherResult<String> {
+        self.auth.authenticate_presence_channel(socket_id, channel, user_id, user_info)
+    }
+
+    /// Subscribes to a channel with authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_name` - The name of the channel to subscribe to
+    /// * `auth` - The authentication signature obtained from authenticate_presence_channel
+    /// * `channel_data` - Optional channel data for presence channels
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure
+    pub async fn subscribe_with_auth(
+        &self,
+        channel_name: &str,
+        auth: &str,
+        channel_data: Option<&str>,
+    ) -> PusherResult<()> {
+        let presence = match channel_data {
+            Some(data) if ChannelType::from_name(channel_name) == ChannelType::Presence => {
+                Some(serde_json::from_str(data)?)
+            }
+            _ => None,
+        };
+        let options = SubscribeOptions {
+            auth: Some(auth.to_string()),
+            channel_data: channel_data.map(str::to_string),
+            presence,
+            ..Default::default()
+        };
+        self.subscribe_channel(channel_name, options).await
+    }
+}
+
+/// The part of the client that replays subscriptions on a new connection. The websocket
+/// task holds one to resubscribe on its own after reconnecting.
+#[derive(Clone)]
+struct Resubscriber {
+    config: PusherConfig,
+    auth: PusherAuth,
+    auth_cache: Arc<AuthCache>,
+    http_client: reqwest::Client,
+    channels: Arc<RwLock<HashMap<String, Channel>>>,
+    error_handlers: ErrorHandlers,
+}
+
+impl Resubscriber {
+    /// See `PusherClient::resubscribe_all`.
+    async fn resubscribe_all(&self, command_tx: &mpsc::Sender<WebSocketCommand>, socket_id: &str) {
+        self.auth_cache.reset(socket_id);
+        let channels: Vec<Channel> = {
+            let mut channels = self.channels.write().await;
+            for channel in channels.values_mut() {
+                channel.set_subscription_state(SubscriptionState::Pending);
+            }
+            channels.values().cloned().collect()
+        };
+        for channel in channels {
+            log::debug!("Resubscribing to channel: {}", channel.name());
+            if let Err(e) = self.resubscribe(command_tx, &channel, socket_id).await {
+                report_error(&self.error_handlers, e);
+            }
+        }
+    }
+
+    async fn resubscribe(
+        &self,
+        command_tx: &mpsc::Sender<WebSocketCommand>,
+        channel: &Channel,
+        socket_id: &str,
+    ) -> PusherResult<()> {
+        // Tokens are bound to the old socket, so every channel subscribed with one is
+        // authorized again the way `subscribe` does, even if the first token was given. The
+        // presence member is only signed for locally, when there is no auth endpoint.
+        // Channels `subscribe` sent without a token, as `authorizes_subscription` was false
+        // for them, still go without.
+        let frame = if channel.is_authorized() {
+            let auth = authorize_subscription(
+                &self.config,
+                &self.auth,
+                &self.auth_cache,
+                &self.http_client,
+                channel.name(),
+                socket_id,
+                channel.presence_user(),
+            )
+            .await?;
+            subscribe_frame(
+                channel.name(),
+                Some(&auth.auth),
+                auth.channel_data.as_deref(),
+                channel.counts_requested(),
+            )
+        } else {
+            subscribe_frame(channel.name(), None, None, channel.counts_requested())
+        };
+        send_command(command_tx, serde_json::to_string(&frame)?).await
+    }
+}
+
+/// Checks that trigger data is valid JSON. It is still published as the string it was given.
+fn validate_json(data: &str) -> PusherResult<()> {
+    serde_json::from_str::<serde_json::Value>(data)?;
+    Ok(())
+}
+
+/// Checks the socket id a trigger excludes, so a malformed one fails before it is sent.
+fn validate_excluded_socket_id(socket_id: &str) -> PusherResult<()> {
+    if auth::is_valid_socket_id(socket_id) {
+        Ok(())
+    } else {
+        Err(PusherError::InvalidArgument(format!("Invalid socket id '{}'", socket_id)))
+    }
+}
+
+async fn send_command(tx: &mpsc::Sender<WebSocketCommand>, message: String) -> PusherResult<()> {
+    tx.send(WebSocketCommand::Send(message))
+        .await
+        .map_err(|e| PusherError::WebSocketError(format!("Failed to send command: {}", e)))
+}
+
+/// Whether `subscribe` authorizes subscriptions to `channel_name` itself.
+fn authorizes_subscription(config: &PusherConfig, channel_name: &str) -> bool {
+    (config.sign_auth_locally || config.auth_endpoint.is_some())
+        && ChannelType::from_name(channel_name) != ChannelType::Public
+}
+
+/// Authorizes the subscription of `socket_id` to a private or presence channel through the
+/// auth endpoint if there is one, and otherwise by signing it with the app secret, joining
+/// presence channels as `presence`. The endpoint decides the member data itself.
+/// Tokens from the endpoint are kept in `cache` unless `PusherConfig::cache_channel_auth` is
+/// off.
+async fn authorize_subscription(
+    config: &PusherConfig,
+    auth: &PusherAuth,
+    cache: &AuthCache,
+    http_client: &reqwest::Client,
+    channel_name: &str,
+    socket_id: &str,
+    presence: Option<&PresenceMember>,
+) -> PusherResult<AuthResponse> {
+    if config.auth_endpoint.is_some() {
+        if !config.cache_channel_auth {
+            return fetch_channel_auth(config, http_client, channel_name, socket_id).await;
+        }
+        if let Some(auth) = cache.get(socket_id, channel_name) {
+            log::debug!("Reusing the auth token for '{}'", channel_name);
+            return Ok(auth);
+        }
+        let auth = fetch_channel_auth(config, http_client, channel_name, socket_id).await?;
+        cache.insert(socket_id, channel_name, auth.clone());
+        return Ok(auth);
+    }
+    if config.app_secret.is_empty() {
+        return Err(PusherError::AuthError(format!(
+            "An auth endpoint or app secret is needed to authorize '{}'",
+            channel_name
+        )));
+    }
+    if ChannelType::from_name(channel_name) == ChannelType::Presence {
+        let Some(member) = presence else {
+            return Err(PusherError::AuthError(format!(
+                "Presence channel '{}' needs presence data to be signed locally",
+                channel_name
+            )));
+        };
+        return Ok(AuthResponse {
+            auth: auth.authenticate_presence_channel(
+                socket_id,
+                channel_name,
+                &member.user_id,
+                member.user_info.as_ref(),
+            )?,
+            channel_data: Some(presence_channel_data(member)?),
+        });
+    }
+    Ok(AuthResponse {
+        auth: auth.sign_subscription(socket_id, channel_name, None)?,
+        channel_data: None,
+    })
+}
+
+/// Asks `PusherConfig::auth_endpoint` to authorize the subscription of `socket_id` to
+/// `channel_name`.
+async fn fetch_channel_auth(
+    config: &PusherConfig,
+    http_client: &reqwest::Client,
+    channel_name: &str,
+    socket_id: &str,
+) -> PusherResult<AuthResponse> {
+    let endpoint = config
+        .auth_endpoint
+        .as_deref()
+        .ok_or_else(|| PusherError::ConfigError("No auth endpoint configured".into()))?;
+    let auth_error = |e: &dyn std::fmt::Display| {
+        PusherError::AuthError(format!("Auth endpoint failed for '{}': {}", channel_name, e))
+    };
+
+    let mut request = http_client
+        .post(endpoint)
+        .form(&[("socket_id", socket_id), ("channel_name", channel_name)]);
+    if let Some(timeout) = config.request_timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request.send().await.map_err(|e| auth_error(&e))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(auth_error(&format!("{} - {}", status, body)));
+    }
+    response.json().await.map_err(|e| auth_error(&e))
+}
+
+/// Whether `host`, which may carry a port, is one of Pusher's own, such as
+/// `ws-mt1.pusher.com`.
+fn is_pusher_host(host: &str) -> bool {
+    let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    name == "pusher.com" || name.ends_with(".pusher.com")
+}
+
+/// Whether a failed REST request may succeed when sent again. An open circuit breaker is
+/// not, as retrying would defeat it.
+fn is_retryable_error(error: &PusherError) -> bool {
+    is_network_error(error) || matches!(error, PusherError::RateLimited { .. })
+}
+
+/// Whether a REST request failed because the API couldn't be reached in time.
+fn is_network_error(error: &PusherError) -> bool {
+    match error {
+        PusherError::TimeoutError(_) => true,
+        PusherError::HttpError(e) => e.is_connect() || e.is_timeout(),
+        _ => false,
+    }
+}
+
+/// Starts a REST client with the TLS and proxy settings of `config`.
+fn rest_client_builder(config: &PusherConfig) -> PusherResult<reqwest::ClientBuilder> {
+    let mut builder = config.tls.configure(reqwest::Client::builder())?;
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    Ok(builder)
+}
+
+/// Checks a user ID for `send_to_user` and `terminate_user_connections`. It ends up in a
+/// channel name and a URL path, so it follows the rules for channel names.
+fn validate_user_id(user_id: &str) -> PusherResult<()> {
+    Channel::validate_name(user_id)
+        .map_err(|_| PusherError::ChannelError(format!("Invalid user ID '{}'", user_id)))
+}
+
+/// How long a `429` response asks to wait, from its `Retry-After` header in seconds.
+/// HTTP dates aren't supported; without a usable header, `DEFAULT_RETRY_AFTER` is assumed.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Duration {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs)
+}
+
+/// Converts an error response of the REST API into a `PusherError::ApiError`. The body is
+/// usually a plain-text message, but JSON bodies are unpacked for their `error` or `message`
+/// and `code` fields.
+fn api_error(context: &str, status: reqwest::StatusCode, body: &str) -> PusherError {
+    let json: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    let code = json
+        .get("code")
+        .and_then(serde_json::Value::as_u64)
+        .and_then(|code| u32::try_from(code).ok());
+    let detail = ["error", "message"]
+        .iter()
+        .find_map(|field| json.get(field).and_then(serde_json::Value::as_str))
+        .unwrap_or(body)
+        .trim();
+    let message = if detail.is_empty() {
+        format!("{}: {}", context, status)
+    } else {
+        format!("{}: {}", context, detail)
+    };
+    PusherError::ApiError {
+        status: status.as_u16(),
+        code,
+        message,
+    }
+}
+
+/// Converts a failed REST request into a `PusherError`, keeping timeouts distinguishable.
+fn rest_error(error: reqwest::Error) -> PusherError {
+    if error.is_timeout() {
+        PusherError::TimeoutError(format!("REST request timed out: {}", error))
+    } else {
+        error.into()
+    }
+}
+
+/// Decrypts data produced by `encrypt_data`: a base64 encoded 16-byte IV followed by the
+/// AES-256-CBC ciphertext, either bare or as the JSON string `trigger_encrypted` publishes.
+fn decrypt_data(data: &str, shared_secret: &[u8]) -> Result<String, String> {
+    let encoded = serde_json::from_str::<String>(data).unwrap_or_else(|_| data.to_string());
+    let payload = STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| format!("payload is not base64: {}", e))?;
+    if payload.len() < 32 || payload.len() % 16 != 0 {
+        return Err(format!("payload has invalid length {}", payload.len()));
+    }
+    let (iv, ciphertext) = payload.split_at(16);
+    let cipher = Decryptor::<Aes256>::new_from_slices(shared_secret, iv).map_err(|_| {
+        format!("shared secret must be 32 bytes, got {}", shared_secret.len())
+    })?;
+    let mut buffer = ciphertext.to_vec();
+    let plaintext = cipher
+        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
+        .map_err(|_| "invalid padding, the shared secret may not match".to_string())?;
+    String::from_utf8(plaintext.to_vec()).map_err(|_| "plaintext is not valid UTF-8".to_string())
+}
+
+/// What a subscription asks for besides the channel, see `PusherClient::subscribe_channel`.
+#[derive(Default)]
+pub(crate) struct SubscribeOptions {
+    /// A token to send as it is, instead of authorizing the subscription.
+    pub auth: Option<String>,
+    /// The member data `auth` was signed with.
+    pub channel_data: Option<String>,
+    /// The member to join a presence channel as, signed with the app secret unless there is
+    /// a token or an auth endpoint.
+    pub presence: Option<PresenceMember>,
+    /// Authorizes private and presence channels even when `authorizes_subscription` is
+    /// false, as `SubscribeBuilder` does.
+    pub always_authorize: bool,
+    /// Opts in to subscription counts, see `PusherClient::subscribe_with_count`.
+    pub subscription_count: bool,
+}
+
+/// The `pusher:subscribe` frame for `channel_name`. With `subscription_count`, it opts in to
+/// subscription counts, see `PusherClient::subscribe_with_count`.
+fn subscribe_frame(
+    channel_name: &str,
+    auth: Option<&str>,
+    channel_data: Option<&str>,
+    subscription_count: bool,
+) -> serde_json::Value {
+    let mut data = json!({
+        "event": "pusher:subscribe",
+        "data": {
+            "channel": channel_name
+        }
+    });
+    if let Some(auth) = auth {
+        data["data"]["auth"] = json!(auth);
+    }
+    if let Some(channel_data) = channel_data {
+        data["data"]["channel_data"] = json!(channel_data);
+    }
+    if subscription_count {
+        data["data"]["subscription_count"] = json!(true);
+    }
+    data
+}
+
+/// Serializes presence member data the same way `PusherAuth` signs it.
+fn presence_channel_data(member: &PresenceMember) -> PusherResult<String> {
+    let mut channel_data = json!({ "user_id": member.user_id });
+    if let Some(info) = &member.user_info {
+        channel_data["user_info"] = info.clone();
+    }
+    Ok(serde_json::to_string(&channel_data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{connect, connected_client, test_config, wait_until};
+
+    /// Routes the client's outbound frames into a channel the test can read from.
+    fn attach_command_channel(client: &PusherClient) -> mpsc::Receiver<WebSocketCommand> {
+        let (tx, rx) = mpsc::channel(100);
+        *client.websocket_command_tx.try_write().unwrap() = Some(tx);
+        rx
+    }
+
+    async fn next_frame(rx: &mut mpsc::Receiver<WebSocketCommand>) -> serde_json::Value {
+        match rx.recv().await {
+            Some(WebSocketCommand::Send(message)) => serde_json::from_str(&message).unwrap(),
+            _ => panic!("Expected a frame to be sent"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_creation() {
+        let config =
+            PusherConfig::from_env().expect("Failed to load Pusher configuration from environment");
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(*client.state.read().await, ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_generate_shared_secret() {
+        let config =
+            PusherConfig::from_env().expect("Failed to load Pusher configuration from environment");
+        let client = PusherClient::new(config).unwrap();
+        let secret = client.generate_shared_secret("test-channel").unwrap();
+        assert!(!secret.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_trigger_batch() {
+        let config =
+            PusherConfig::from_env().expect("Failed to load Pusher configuration from environment");
+        let client = PusherClient::new(config).unwrap();
+
+        let batch_events = vec![
+            BatchEvent {
+                channel: "test-channel-1".to_string(),
+                event: "test-event-1".to_string(),
+                data: "{\"message\": \"Hello from event 1\"}".to_string(),
+                socket_id: None,
+            },
+            BatchEvent {
+                channel: "test-channel-2".to_string(),
+                event: "test-event-2".to_string(),
+                data: "{\"message\": \"Hello from event 2\"}".to_string(),
+                socket_id: None,
+            },
+        ];
+
+        let result = client.trigger_batch(batch_events).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_restarts_after_handler_panic() {
+        let client = PusherClient::new(test_config()).unwrap();
+        assert!(client.is_dispatch_alive());
+
+        client
+            .bind("boom", |_| panic!("handler failure"))
+            .await
+            .unwrap();
+        client
+            .event_tx
+            .send(Event::new("boom".to_string(), None, "{}".to_string()))
+            .await
+            .unwrap();
+        wait_until(|| async { !client.is_dispatch_alive() }).await;
+
+        let received = Arc::new(RwLock::new(false));
+        let received_clone = received.clone();
+        client
+            .bind("ok", move |_| {
+                let received = received_clone.clone();
+                tokio::spawn(async move {
+                    *received.write().await = true;
+                });
+            })
+            .await
+            .unwrap();
+        assert!(client.is_dispatch_alive());
+
+        client
+            .send_test_event(Event::new("ok".to_string(), None, "{}".to_string()))
+            .await
+            .unwrap();
+        wait_until(|| async { *received.read().await }).await;
+        assert!(client.is_dispatch_alive());
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_header() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let body = json!({"name": "event", "channel": "channel", "data": "{}"});
+
+        let request = client
+            .build_post_request("/apps/123/events", &body, Some("key-1"), None)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.headers().get(IDEMPOTENCY_KEY_HEADER).unwrap(),
+            "key-1"
+        );
+
+        let request = client
+            .build_post_request("/apps/123/events", &body, None, None)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(request.headers().get(IDEMPOTENCY_KEY_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auto_idempotency_key() {
+        let client = PusherClient::new(test_config()).unwrap();
+        assert!(client.auto_idempotency_key().is_none());
+
+        let config = PusherConfig {
+            idempotent_triggers: true,
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        let first = client.auto_idempotency_key().unwrap();
+        let second = client.auto_idempotency_key().unwrap();
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_channels_by_type() {
+        let client = PusherClient::new(test_config()).unwrap();
+        {
+            let mut channels = client.channels.write().await;
+            for name in ["news", "private-inbox", "presence-room", "presence-lobby"] {
+                channels.insert(name.to_string(), Channel::new(name));
+            }
+        }
+
+        let grouped = client.channels_by_type().await;
+        assert_eq!(grouped[&ChannelType::Public], vec!["news".to_string()]);
+        assert_eq!(grouped[&ChannelType::Private], vec!["private-inbox".to_string()]);
+        let mut presence = grouped[&ChannelType::Presence].clone();
+        presence.sort();
+        assert_eq!(presence, vec!["presence-lobby", "presence-room"]);
+        assert!(!grouped.contains_key(&ChannelType::PrivateEncrypted));
+    }
+
+    #[tokio::test]
+    async fn test_bind_weak_prunes_dropped_target() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let client = PusherClient::new(test_config()).unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let target = Arc::new(String::from("subscriber"));
+
+        let hits_clone = hits.clone();
+        client
+            .bind_weak("update", &target, move |target, _| {
+                assert_eq!(target.as_str(), "subscriber");
+                hits_clone.fetch_add(1, Ordering::SeqCst);
+            })
+            .await
+            .unwrap();
+
+        let event = Event::new("update".to_string(), None, "{}".to_string());
+        client.send_test_event(event.clone()).await.unwrap();
+        wait_until(|| async { hits.load(Ordering::SeqCst) == 1 }).await;
+
+        drop(target);
+        client.send_test_event(event).await.unwrap();
+        wait_until(|| async { !client.event_handlers.read().await.contains_key("update") }).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_trigger_fails_fast_when_circuit_open() {
+        let client = PusherClient::new(test_config()).unwrap();
+        assert_eq!(client.circuit_breaker_state(), None);
+
+        let config = PusherConfig {
+            circuit_breaker: Some(CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown: Duration::from_secs(60),
+            }),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(client.circuit_breaker_state(), Some(CircuitState::Closed));
+
+        let breaker = client.circuit_breaker.as_ref().unwrap();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(client.circuit_breaker_state(), Some(CircuitState::Open));
+
+        let result = client.trigger("test-channel", "test-event", "{}").await;
+        assert!(matches!(result, Err(PusherError::CircuitOpen(_))));
+        let result = client.trigger_batch(vec![]).await;
+        assert!(matches!(result, Err(PusherError::CircuitOpen(_))));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_with_count() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let mut commands = attach_command_channel(&client);
+
+        client.subscribe_with_count("room").await.unwrap();
+        let frame = next_frame(&mut commands).await;
+        assert_eq!(frame["event"], "pusher:subscribe");
+        assert_eq!(frame["data"]["channel"], "room");
+        assert_eq!(frame["data"]["subscription_count"], true);
+        assert_eq!(client.subscription_count("room").await, None);
+
+        let counts = Arc::new(RwLock::new(Vec::new()));
+        let counts_clone = counts.clone();
+        client
+            .bind("pusher:subscription_count", move |event| {
+                let counts = counts_clone.clone();
+                tokio::spawn(async move {
+                    counts.write().await.push(event.data);
+                });
+            })
+            .await
+            .unwrap();
+        let room_counts = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&room_counts);
+        client
+            .on_subscription_count("room", move |count| sink.lock().unwrap().push(count))
+            .await
+            .unwrap();
+
+        for (channel, count) in [("room", 42), ("other-room", 7)] {
+            client
+                .send_test_event(Event::new(
+                    "pusher_internal:subscription_count".to_string(),
+                    Some(channel.to_string()),
+                    format!(r#"{{"subscription_count":{}}}"#, count),
+                ))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async { counts.read().await.len() == 2 }).await;
+
+        assert_eq!(client.subscription_count("room").await, Some(42));
+        assert_eq!(
+            *counts.read().await,
+            vec![r#"{"subscription_count":42}"#, r#"{"subscription_count":7}"#]
+        );
+        assert_eq!(*room_counts.lock().unwrap(), vec![42]);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_count_opt_in_survives_reconnect() {
+        let (server, client, mut connection) = connected_client(|config| {
+            config.backoff_interval = Duration::from_millis(10);
+            config.sign_auth_locally = true;
+        })
+        .await;
+
+        client.subscribe_with_count("private-room").await.unwrap();
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["subscription_count"], true);
+        assert_eq!(
+            frame["data"]["auth"],
+            client.auth.sign_subscription("1.1", "private-room", None).unwrap()
+        );
+        // Subscribing again sends nothing.
+        client.subscribe_with_count("private-room").await.unwrap();
+
+        drop(connection);
+        let mut connection = server.accept_established("2.2").await;
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["channel"], "private-room");
+        assert_eq!(frame["data"]["subscription_count"], true);
+        assert_eq!(
+            frame["data"]["auth"],
+            client.auth.sign_subscription("2.2", "private-room", None).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_event() {
+        let config = PusherConfig {
+            track_last_event: true,
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        let _commands = attach_command_channel(&client);
+        client.subscribe("news").await.unwrap();
+
+        for data in [r#"{"n":1}"#, r#"{"n":2}"#] {
+            client
+                .send_test_event(Event::new(
+                    "update".to_string(),
+                    Some("news".to_string()),
+                    data.to_string(),
+                ))
+                .await
+                .unwrap();
+        }
+        // Events on channels we aren't subscribed to are not kept.
+        client
+            .send_test_event(Event::new(
+                "update".to_string(),
+                Some("other".to_string()),
+                "{}".to_string(),
+            ))
+            .await
+            .unwrap();
+        wait_until(|| async { client.metrics().await.events_dispatched == 3 }).await;
+
+        assert_eq!(client.last_event("news").await.unwrap().data, r#"{"n":2}"#);
+        assert!(client.last_event("other").await.is_none());
+
+        client.unsubscribe("news").await.unwrap();
+        assert!(client.last_event("news").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_last_event_disabled_by_default() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let _commands = attach_command_channel(&client);
+        client.subscribe("news").await.unwrap();
+        client
+            .send_test_event(Event::new(
+                "update".to_string(),
+                Some("news".to_string()),
+                "{}".to_string(),
+            ))
+            .await
+            .unwrap();
+        wait_until(|| async { client.metrics().await.events_dispatched == 1 }).await;
+        assert!(client.last_event("news").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bind_typed_reports_deserialize_errors() {
+        #[derive(serde::Deserialize)]
+        struct Message {
+            text: String,
+        }
+
+        let client = PusherClient::new(test_config()).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+
+        let received_clone = received.clone();
+        client
+            .bind_typed("chat", move |message: Message| {
+                received_clone.lock().unwrap().push(message.text);
+            })
+            .await
+            .unwrap();
+        let errors_clone = errors.clone();
+        client
+            .on_error(move |error| {
+                if let PusherError::DeserializeError { event, data, .. } = error {
+                    errors_clone.lock().unwrap().push((event.clone(), data.clone()));
+                }
+            })
+            .await
+            .unwrap();
+
+        for data in [r#"{"text":"hi"}"#, r#"{"txt":"typo"}"#] {
+            client
+                .send_test_event(Event::new("chat".to_string(), None, data.to_string()))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async { !errors.lock().unwrap().is_empty() }).await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["hi"]);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![("chat".to_string(), r#"{"txt":"typo"}"#.to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_presence_reannounced_after_reconnect() {
+        let (server, client, mut connection) = connected_client(|_| {}).await;
+
+        let channel = "presence-room";
+        let user_info = json!({"name": "Alice"});
+        let channel_data = json!({"user_id": "alice", "user_info": user_info}).to_string();
+        let auth = client
+            .authenticate_presence_channel("1.1", channel, "alice", Some(&user_info))
+            .unwrap();
+        client
+            .subscribe_with_auth(channel, &auth, Some(&channel_data))
+            .await
+            .unwrap();
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["auth"], auth);
+
+        client.disconnect().await.unwrap();
+        assert_eq!(client.get_socket_id().await.unwrap(), None);
+
+        let mut connection = connect(&client, &server, "2.2").await;
+
+        let frame = connection.recv_json().await;
+        let expected_auth = client
+            .authenticate_presence_channel("2.2", channel, "alice", Some(&user_info))
+            .unwrap();
+        assert_eq!(frame["event"], "pusher:subscribe");
+        assert_eq!(frame["data"]["channel"], channel);
+        assert_eq!(frame["data"]["auth"], expected_auth);
+        assert_ne!(frame["data"]["auth"], auth);
+        let sent_data: serde_json::Value =
+            serde_json::from_str(frame["data"]["channel_data"].as_str().unwrap()).unwrap();
+        assert_eq!(sent_data, json!({"user_id": "alice", "user_info": {"name": "Alice"}}));
+    }
+
+    #[tokio::test]
+    async fn test_presence_reauthorized_by_endpoint_after_reconnect() {
+        let auth_server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |body| {
+                json!({
+                    "auth": format!("key:presence-{}", body["socket_id"].as_str().unwrap()),
+                    "channel_data": r#"{"user_id":"alice"}"#
+                })
+            },
+        )
+        .await;
+        let (server, client, mut connection) = connected_client(|config| {
+            config.app_secret = String::new();
+            config.backoff_interval = Duration::from_millis(10);
+            config.auth_endpoint = Some(format!(
+                "http://{}/pusher/auth",
+                auth_server.config().rest_host.unwrap()
+            ));
+        })
+        .await;
+
+        let channel_data = r#"{"user_id":"alice"}"#;
+        client
+            .subscribe_with_auth("presence-room", "key:presence-1.1", Some(channel_data))
+            .await
+            .unwrap();
+        connection.recv_json().await;
+
+        drop(connection);
+        let mut connection = server.accept_established("2.2").await;
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["channel"], "presence-room");
+        assert_eq!(frame["data"]["auth"], "key:presence-2.2");
+        assert_eq!(frame["data"]["channel_data"], channel_data);
+        assert_eq!(
+            auth_server.bodies(),
+            vec![json!({"socket_id": "2.2", "channel_name": "presence-room"})]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resubscribing_without_app_secret_reports_error() {
+        let (server, client, mut connection) = connected_client(|config| {
+            config.app_secret = String::new();
+            config.backoff_interval = Duration::from_millis(10);
+        })
+        .await;
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&errors);
+        client
+            .on_error(move |error| seen.lock().unwrap().push(error.to_string()))
+            .await
+            .unwrap();
+
+        client
+            .subscribe_with_auth("presence-room", "key:given", Some(r#"{"user_id":"alice"}"#))
+            .await
+            .unwrap();
+        connection.recv_json().await;
+
+        drop(connection);
+        let _connection = server.accept_established("2.2").await;
+        wait_until(|| async { !errors.lock().unwrap().is_empty() }).await;
+        assert!(errors.lock().unwrap()[0].contains("An auth endpoint or app secret is needed"));
+    }
+
+    #[tokio::test]
+    async fn test_crypto_with_degenerate_inputs() {
+        let config = PusherConfig {
+            app_secret: String::new(),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert!(matches!(
+            client.generate_shared_secret("private-encrypted-channel"),
+            Err(PusherError::ConfigError(_))
+        ));
+
+        let client = PusherClient::new(test_config()).unwrap();
+        let secret = client.generate_shared_secret("").unwrap();
+        assert_eq!(secret.len(), 32);
+        assert!(client.encrypt_data("{}", &secret).is_ok());
+
+        for bad_secret in [&b""[..], &b"short"[..], &[0u8; 64][..]] {
+            assert!(matches!(
+                client.encrypt_data("{}", bad_secret),
+                Err(PusherError::EncryptionError(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_on_dedicated_runtime() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("pusher-dispatch")
+            .enable_all()
+            .build()
+            .unwrap();
+        let client = PusherClient::new_with_runtime(test_config(), runtime.handle().clone()).unwrap();
+
+        let thread_names = Arc::new(Mutex::new(Vec::new()));
+        let thread_names_clone = thread_names.clone();
+        client
+            .bind("update", move |_| {
+                let name = std::thread::current().name().map(str::to_string);
+                thread_names_clone.lock().unwrap().push(name);
+            })
+            .await
+            .unwrap();
+        client
+            .send_test_event(Event::new("update".to_string(), None, "{}".to_string()))
+            .await
+            .unwrap();
+        wait_until(|| async { !thread_names.lock().unwrap().is_empty() }).await;
+
+        assert_eq!(
+            *thread_names.lock().unwrap(),
+            vec![Some("pusher-dispatch".to_string())]
+        );
+        runtime.shutdown_background();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_many_reports_per_channel_results() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let server_side = async {
+            for _ in 0..3 {
+                let frame = connection.recv_json().await;
+                let channel = frame["data"]["channel"].as_str().unwrap().to_string();
+                match channel.as_str() {
+                    "public" => {
+                        connection
+                            .send_event(
+                                "pusher_internal:subscription_succeeded",
+                                Some(&channel),
+                                &json!({}),
+                            )
+                            .await
+                    }
+                    "private-forbidden" => {
+                        connection
+                            .send_event(
+                                "pusher:subscription_error",
+                                Some(&channel),
+                                &json!({"type": "AuthError", "error": "Forbidden", "status": 403}),
+                            )
+                            .await
+                    }
+                    _ => {}
+                }
+            }
+        };
+        let (results, _) = tokio::join!(
+            client.subscribe_many(
+                &["public", "private-forbidden", "silent"],
+                Duration::from_millis(300)
+            ),
+            server_side
+        );
+
+        assert_eq!(results[0].0, "public");
+        assert!(results[0].1.is_ok());
+        match &results[1].1 {
+            Err(PusherError::SubscriptionError { channel, status, message }) => {
+                assert_eq!(channel, "private-forbidden");
+                assert_eq!(*status, Some(403));
+                assert_eq!(message, "Forbidden");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(matches!(results[2].1, Err(PusherError::TimeoutError(_))));
+        assert!(client.pending_subscriptions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_url() {
+        let client = PusherClient::new(test_config()).unwrap();
+        assert_eq!(
+            client.get_websocket_url().unwrap().as_str(),
+            "ws://ws-mt1.pusher.com/app/key?protocol=7"
+        );
+
+        let config = PusherConfig {
+            host: Some("localhost".to_string()),
+            port: Some(6001),
+            use_tls: false,
+            ws_path: Some("/ws/app/{key}".to_string()),
+            protocol_version: 8,
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(
+            client.get_websocket_url().unwrap().as_str(),
+            "ws://localhost:6001/ws/app/key?protocol=8"
+        );
+
+        let config = PusherConfig {
+            host: Some("soketi.example.com".to_string()),
+            port: Some(6001),
+            use_tls: true,
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(
+            client.get_websocket_url().unwrap().as_str(),
+            "wss://soketi.example.com:6001/app/key?protocol=7"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_info() {
+        let config = PusherConfig {
+            cluster: "eu".to_string(),
+            use_tls: true,
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(
+            client.endpoint_info().unwrap(),
+            EndpointInfo {
+                cluster: "eu".to_string(),
+                websocket_url: "wss://ws-eu.pusher.com/app/key?protocol=7".to_string(),
+                rest_url: "https://api-eu.pusher.com".to_string(),
+            }
+        );
+
+        // Errors name the endpoint they came from.
+        let server = test_server::MockRestServer::start_with_statuses(vec![403]).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+        let rest_url = client.endpoint_info().unwrap().rest_url;
+        let error = client.trigger("my-channel", "my-event", "{}").await.unwrap_err();
+        assert!(error.to_string().contains(&format!("Failed to trigger event on {}", rest_url)));
+
+        let config = PusherConfig {
+            host: Some("127.0.0.1:1".to_string()),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        let error = client.connect().await.unwrap_err();
+        assert!(error.to_string().contains("Failed to connect to 127.0.0.1:1"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_rest_url() {
+        let client = PusherClient::new(test_config()).unwrap();
+        assert_eq!(
+            client.rest_url("/apps/123/events"),
+            "https://api-mt1.pusher.com/apps/123/events"
+        );
+
+        for prefix in ["/pusher", "pusher/", "/pusher/"] {
+            let config = PusherConfig {
+                rest_path_prefix: Some(prefix.to_string()),
+                ..test_config()
+            };
+            let client = PusherClient::new(config).unwrap();
+            assert_eq!(
+                client.rest_url("/apps/123/events"),
+                "https://api-mt1.pusher.com/pusher/apps/123/events"
+            );
+        }
+
+        let config = PusherConfig {
+            host: Some("localhost".to_string()),
+            port: Some(6001),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(client.rest_url("/apps/123/events"), "http://localhost:6001/apps/123/events");
+
+        // `port` replaces a port given in `host` rather than being appended to it.
+        let config = PusherConfig {
+            host: Some("localhost:6001".to_string()),
+            port: Some(7000),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(client.rest_url("/apps/123/events"), "http://localhost:7000/apps/123/events");
+
+        // Pusher's websocket host doesn't serve REST, so the cluster's API host is used.
+        let config = PusherConfig {
+            host: Some("ws-mt1.pusher.com".to_string()),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(
+            client.rest_url("/apps/123/events"),
+            "https://api-mt1.pusher.com/apps/123/events"
+        );
+
+        let config = PusherConfig {
+            host: Some("ws.example.com".to_string()),
+            rest_host: Some("localhost:6001".to_string()),
+            rest_path_prefix: Some("/realtime/pusher".to_string()),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(
+            client.rest_url("/apps/123/batch_events"),
+            "http://localhost:6001/realtime/pusher/apps/123/batch_events"
+        );
+
+        // The prefix is added to the URL but not to the signed path.
+        let request = client
+            .build_post_request("/apps/123/events", &json!({}), None, None)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().path(), "/realtime/pusher/apps/123/events");
+    }
+
+    #[tokio::test]
+    async fn test_connection_metadata_from_handshake() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        assert!(client.connection_metadata().await.is_empty());
+
+        let accept = async {
+            let mut connection = server
+                .accept_with_headers(&[("x-tenant-id", "acme"), ("x-region", "eu")])
+                .await;
+            connection
+                .send_event(
+                    "pusher:connection_established",
+                    None,
+                    &json!({"socket_id": "1.1", "activity_timeout": 120}),
+                )
+                .await;
+            connection
+        };
+        let (result, _connection) = tokio::join!(client.connect(), accept);
+        result.unwrap();
+
+        let metadata = client.connection_metadata().await;
+        assert_eq!(metadata.get("x-tenant-id").map(String::as_str), Some("acme"));
+        assert_eq!(metadata.get("x-region").map(String::as_str), Some("eu"));
+        assert!(!metadata.contains_key("sec-websocket-accept"));
+    }
+
+    #[tokio::test]
+    async fn test_ws_headers_sent_with_handshake() {
+        let (_server, _client, connection) = connected_client(|config| {
+            config.ws_headers.insert("Authorization".to_string(), "Bearer token".to_string());
+            config.ws_headers.insert("X-Api-Key".to_string(), "abc".to_string());
+        })
+        .await;
+        let headers = connection.request_headers();
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(headers["x-api-key"], "abc");
+        assert_eq!(headers["upgrade"], "websocket");
+    }
+
+    #[tokio::test]
+    async fn test_bind_system() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        client
+            .bind_system(move |event| {
+                let summary = match event.data {
+                    SystemEventData::Error { code, .. } => format!("error {:?}", code),
+                    SystemEventData::Ping => "ping".to_string(),
+                    other => format!("{:?}", other),
+                };
+                received_clone.lock().unwrap().push(summary);
+            })
+            .await
+            .unwrap();
+
+        for (name, data) in [
+            ("pusher:error", r#"{"code":4200,"message":"Reconnect"}"#),
+            ("my-event", "{}"),
+            ("pusher:ping", "{}"),
+        ] {
+            client
+                .send_test_event(Event::new(name.to_string(), None, data.to_string()))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async { received.lock().unwrap().len() == 2 }).await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["error Some(4200)", "ping"]);
+    }
+
+    #[tokio::test]
+    async fn test_rest_only_client() {
+        let client = PusherClient::new_rest_only(test_config()).unwrap();
+        assert!(!client.is_dispatch_alive());
+        assert!(!client.ensure_dispatcher());
+
+        assert!(matches!(client.connect().await, Err(PusherError::RealtimeDisabled)));
+        assert!(matches!(
+            client.subscribe("my-channel").await,
+            Err(PusherError::RealtimeDisabled)
+        ));
+        assert!(matches!(
+            client.bind("my-event", |_| {}).await,
+            Err(PusherError::RealtimeDisabled)
+        ));
+        assert!(matches!(
+            client.on_raw_message(|_| {}).await,
+            Err(PusherError::RealtimeDisabled)
+        ));
+        assert!(client.get_subscribed_channels().await.is_empty());
+        assert!(!client.is_dispatch_alive());
+
+        // REST requests are still built as usual.
+        let request = client
+            .build_post_request("/apps/123/events", &json!({}), None, None)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().host_str(), Some("api-mt1.pusher.com"));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_publish() {
+        // Earlier events get slower responses, so unordered triggers would be recorded in
+        // reverse.
+        let server = test_server::MockRestServer::start(|body| {
+            let data: serde_json::Value =
+                serde_json::from_str(body["data"].as_str().unwrap_or("{}")).unwrap();
+            Duration::from_millis(5 * (5 - data["seq"].as_u64().unwrap_or(5)))
+        })
+        .await;
+        let mut config = server.config();
+        config.ordered_publish = true;
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        let triggers = (0..5).flat_map(|seq| {
+            let data = json!({ "seq": seq }).to_string();
+            ["channel-a", "channel-b"].map(|channel| {
+                let data = data.clone();
+                let client = &client;
+                async move { client.trigger(channel, "my-event", &data).await }
+            })
+        });
+        for result in futures_util::future::join_all(triggers).await {
+            result.unwrap();
+        }
+
+        let bodies = server.bodies();
+        assert_eq!(bodies.len(), 10);
+        for channel in ["channel-a", "channel-b"] {
+            let sequence: Vec<String> = bodies
+                .iter()
+                .filter(|body| body["channel"] == channel)
+                .map(|body| body["data"].as_str().unwrap().to_string())
+                .collect();
+            let expected: Vec<String> = (0..5).map(|seq| json!({ "seq": seq }).to_string()).collect();
+            assert_eq!(sequence, expected);
+        }
+        assert!(client.publish_queues.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_background_send_failures_are_reported() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_clone = errors.clone();
+        client
+            .on_error(move |error| errors_clone.lock().unwrap().push(error.to_string()))
+            .await
+            .unwrap();
+
+        let mut rx = attach_command_channel(&client);
+        client.subscribe("channel-a").await.unwrap();
+        client.subscribe("channel-b").await.unwrap();
+        next_frame(&mut rx).await;
+        next_frame(&mut rx).await;
+
+        // The socket task is gone, so nothing receives the resubscribe frames.
+        drop(rx);
+        client.resubscribe_all("1.1").await.unwrap();
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|error| error.contains("Failed to send command")));
+    }
+
+    #[tokio::test]
+    async fn test_deeply_nested_frame_is_dropped() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.max_json_depth = 8;
+        let client = PusherClient::new(config).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_clone = errors.clone();
+        client
+            .on_error(move |error| {
+                errors_clone
+                    .lock()
+                    .unwrap()
+                    .push(matches!(error, PusherError::ProtocolError(_)))
+            })
+            .await
+            .unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        client
+            .bind("my-event", move |event| received_clone.lock().unwrap().push(event.data))
+            .await
+            .unwrap();
+
+        let mut conn = connect(&client, &server, "1.1").await;
+
+        let nested = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        conn.send_text(&nested).await;
+        conn.send_text(&json!({"event": "my-event", "data": nested}).to_string())
+            .await;
+        conn.send_event("my-event", None, &json!({"ok": true})).await;
+        wait_until(|| async { !received.lock().unwrap().is_empty() }).await;
+
+        assert_eq!(*errors.lock().unwrap(), vec![true, true]);
+        assert_eq!(*received.lock().unwrap(), vec![r#"{"ok":true}"#.to_string()]);
+        assert!(client.is_connected().await);
+    }
+
+    #[tokio::test]
+    async fn test_trigger_batch_result() {
+        let server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |_| {
+                json!({"batch": [
+                    {"subscription_count": 3},
+                    {"error": "Quota exceeded"},
+                    {}
+                ]})
+            },
+        )
+        .await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+        let event = |channel: &str| BatchEvent {
+            channel: channel.to_string(),
+            event: "my-event".to_string(),
+            data: "{}".to_string(),
+            socket_id: None,
+        };
+
+        let result = client
+            .trigger_batch(vec![event("channel-a"), event("channel-b"), event("channel-c")])
+            .await
+            .unwrap();
+
+        assert!(!result.all_accepted());
+        assert_eq!(result.events.len(), 3);
+        assert_eq!(result.events[0].channel, "channel-a");
+        assert!(result.events[0].is_accepted());
+        assert_eq!(result.events[0].info["subscription_count"], json!(3));
+        assert_eq!(result.events[1].error.as_deref(), Some("Quota exceeded"));
+        assert!(result.events[1].info.is_empty());
+        assert!(result.events[2].is_accepted());
+        assert_eq!(result.succeeded, 2);
+        assert_eq!(result.failures.len(), 1);
+        assert_eq!(result.failures[0].0, 1);
+        assert!(result.failures[0].1.to_string().contains("Quota exceeded"));
+        assert_eq!(server.bodies()[0]["batch"].as_array().unwrap().len(), 3);
+        assert!(matches!(
+            client.flush_triggers().await,
+            Err(PusherError::TriggersFailed(failures))
+                if failures.len() == 1 && failures[0].starts_with("batch event 1 ")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_trigger_batch_result_without_details() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let result = client
+            .trigger_batch(vec![BatchEvent {
+                channel: "channel-a".to_string(),
+                event: "my-event".to_string(),
+                data: "{}".to_string(),
+                socket_id: None,
+            }])
+            .await
+            .unwrap();
+
+        assert!(result.all_accepted());
+        assert_eq!(result.events.len(), 1);
+        assert!(result.events[0].info.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_trigger_batch_is_chunked() {
+        let events = |count: usize| -> Vec<BatchEvent> {
+            (0..count)
+                .map(|index| BatchEvent {
+                    channel: "channel-a".to_string(),
+                    event: format!("event-{}", index),
+                    data: "{}".to_string(),
+                    socket_id: None,
+                })
+                .collect()
+        };
+        // The first request is answered last, yet its events still come first.
+        let server = test_server::MockRestServer::start(|body| {
+            if body["batch"][0]["name"] == "event-0" {
+                Duration::from_millis(50)
+            } else {
+                Duration::ZERO
+            }
+        })
+        .await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let result = client.trigger_batch(events(25)).await.unwrap();
+        let names: Vec<_> = result.events.iter().map(|event| event.event.clone()).collect();
+        assert_eq!(names, events(25).into_iter().map(|event| event.event).collect::<Vec<_>>());
+        let mut sizes: Vec<_> = server
+            .bodies()
+            .iter()
+            .map(|body| body["batch"].as_array().unwrap().len())
+            .collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![5, 10, 10]);
+
+        // Either request may be the one answered with the error, but its events are the
+        // ones reported, by their index in the whole batch.
+        let server = test_server::MockRestServer::start_with_statuses(vec![400]).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+        let result = client.trigger_batch(events(15)).await.unwrap();
+        let failed: Vec<_> = result.failures.iter().map(|(index, _)| *index).collect();
+        assert!(
+            failed == (0..10).collect::<Vec<_>>() || failed == (10..15).collect::<Vec<_>>(),
+            "{:?}",
+            failed
+        );
+        assert_eq!(result.succeeded, 15 - failed.len());
+        assert!(matches!(*result.failures[0].1, PusherError::ApiError { status: 400, .. }));
+        assert!(result.events[failed[0]].error.is_some());
+        assert_eq!(server.bodies().len(), 2);
+
+        let server = test_server::MockRestServer::start_with_statuses(vec![400, 400]).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+        assert!(matches!(
+            client.trigger_batch(events(15)).await,
+            Err(PusherError::ApiError { status: 400, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tls_sni() {
+        let mut config = test_config();
+        config.tls_sni = Some("not a host".to_string());
+        assert!(matches!(
+            PusherClient::new(config),
+            Err(PusherError::ConfigError(_))
+        ));
+
+        // The SNI name doesn't resolve, so these only succeed if the connection still goes
+        // to the configured host.
+        let rest_server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let mut config = rest_server.config();
+        config.tls_sni = Some("pusher.invalid".to_string());
+        let client = PusherClient::new_rest_only(config).unwrap();
+        let request = client
+            .build_post_request("/apps/123/events", &json!({}), None, None)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().host_str(), Some("pusher.invalid"));
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        assert_eq!(rest_server.bodies().len(), 1);
+
+        connected_client(|config| config.tls_sni = Some("pusher.invalid".to_string())).await;
+    }
+
+    #[tokio::test]
+    async fn test_tls_sni_host_is_resolved_again() {
+        let rest_server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let mut config = rest_server.config();
+        config.tls_sni = Some("pusher.invalid".to_string());
+        let client = PusherClient::new_rest_only(config.clone()).unwrap();
+        // As if the host used to resolve to an address nothing listens on any more.
+        let pin_moved_host = |resolved_at: Instant| {
+            let moved: std::net::SocketAddr = "127.0.0.2:0".parse().unwrap();
+            let client = rest_client_builder(&config)
+                .unwrap()
+                .resolve("pusher.invalid", moved)
+                .build()
+                .unwrap();
+            Some(PinnedClient { client, resolved_at })
+        };
+
+        *client.sni_http_client.lock().await = pin_moved_host(Instant::now());
+        let error = client.trigger("my-channel", "my-event", "{}").await.unwrap_err();
+        assert!(is_network_error(&error), "Unexpected error: {}", error);
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        assert_eq!(rest_server.bodies().len(), 1);
+
+        // Stale addresses are replaced before they are used.
+        *client.sni_http_client.lock().await = pin_moved_host(Instant::now() - SNI_RESOLVE_TTL);
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        assert_eq!(rest_server.bodies().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_flush_triggers() {
+        let server = test_server::MockRestServer::start(|_| Duration::from_millis(50)).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let triggers = futures_util::future::join_all(
+            (0..5).map(|_| client.trigger("my-channel", "my-event", "{}")),
+        );
+        let flush = async {
+            client.flush_triggers().await.unwrap();
+            server.bodies().len()
+        };
+        // Polled in order, so the triggers are in flight before flushing starts.
+        let (results, delivered_at_flush) = futures_util::future::join(triggers, flush).await;
+
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(delivered_at_flush, 5);
+
+        // The caller was given this error, so flushing doesn't report it again.
+        client.trigger("my-channel", "my-event", "not json").await.unwrap_err();
+        assert!(client.shutdown().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_offline_trigger_queue() {
+        // The socket refuses connections until it starts listening below.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let config = PusherConfig {
+            rest_host: Some(socket.local_addr().unwrap().to_string()),
+            offline_queue_size: 2,
+            // Keeps the background retry out of the way.
+            backoff_interval: Duration::from_secs(60),
+            ..test_config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        for event in ["event-a", "event-b", "event-c"] {
+            client.trigger("my-channel", event, "{}").await.unwrap();
+        }
+        assert_eq!(client.pending_triggers(), 2);
+        match client.flush_triggers().await {
+            Err(PusherError::TriggersFailed(failures)) => {
+                assert_eq!(failures.len(), 2);
+                assert!(failures[0].contains("'event-a'"), "{}", failures[0]);
+                assert!(failures[1].starts_with("2 queued trigger(s) not sent yet"));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(client.pending_triggers(), 2);
+
+        // The queue is full, so the new trigger sends it before going out itself.
+        let server = test_server::MockRestServer::start_on(socket.listen(16).unwrap());
+        client.trigger("my-channel", "event-d", "{}").await.unwrap();
+        assert_eq!(client.pending_triggers(), 0);
+        let names: Vec<_> = server.bodies().iter().map(|body| body["name"].clone()).collect();
+        assert_eq!(names, vec!["event-b", "event-c", "event-d"]);
+        assert!(client.flush_triggers().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_offline_trigger_queue_is_retried() {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let config = PusherConfig {
+            rest_host: Some(socket.local_addr().unwrap().to_string()),
+            offline_queue_size: 10,
+            backoff_interval: Duration::from_millis(10),
+            max_reconnect_interval: Duration::from_millis(20),
+            ..test_config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        client.trigger("my-channel", "event-a", "{}").await.unwrap();
+        client.trigger("my-channel", "event-b", "{}").await.unwrap();
+        assert_eq!(client.pending_triggers(), 2);
+
+        // Nothing else is triggered, yet the queue goes out once the API is reachable.
+        let server = test_server::MockRestServer::start_on(socket.listen(16).unwrap());
+        wait_until(|| async { client.pending_triggers() == 0 && server.bodies().len() == 2 }).await;
+        let names: Vec<_> = server.bodies().iter().map(|body| body["name"].clone()).collect();
+        assert_eq!(names, vec!["event-a", "event-b"]);
+        assert!(client.flush_triggers().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_over_capacity() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_clone = errors.clone();
+        client
+            .on_error(move |error| {
+                if let PusherError::OverCapacity(message) = error {
+                    errors_clone.lock().unwrap().push(message.clone());
+                }
+            })
+            .await
+            .unwrap();
+        let signalled = Arc::new(Mutex::new(0));
+        let signalled_clone = signalled.clone();
+        client
+            .bind(OVER_CAPACITY_EVENT, move |_| *signalled_clone.lock().unwrap() += 1)
+            .await
+            .unwrap();
+
+        let mut conn = connect(&client, &server, "1.1").await;
+        let normal_delay = client.reconnect_delay(1);
+        assert_eq!(normal_delay, Duration::from_secs(2));
+
+        conn.send_event(
+            "pusher:error",
+            None,
+            &json!({"code": 4100, "message": "Over capacity"}),
+        )
+        .await;
+        wait_until(|| async {
+            !errors.lock().unwrap().is_empty() && *signalled.lock().unwrap() > 0
+        })
+        .await;
+
+        assert_eq!(*errors.lock().unwrap(), vec!["Over capacity".to_string()]);
+        assert_eq!(*signalled.lock().unwrap(), 1);
+        assert_eq!(client.reconnect_delay(1), normal_delay * 10);
+
+        // A new connection clears the signal.
+        conn.send_event(
+            "pusher:connection_established",
+            None,
+            &json!({"socket_id": "1.2", "activity_timeout": 120}),
+        )
+        .await;
+        wait_until(|| async { client.reconnect_delay(1) == normal_delay }).await;
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_with_handlers() {
+        let (_server, client, mut conn) = connected_client(|_| {}).await;
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let mut handlers: HashMap<String, EventCallback> = HashMap::new();
+        handlers.insert(
+            "my-event".to_string(),
+            Box::new(move |event| received_clone.lock().unwrap().push(event.data)),
+        );
+        client
+            .subscribe_with_handlers("my-channel", handlers)
+            .await
+            .unwrap();
+
+        // The server publishes as soon as the subscription arrives.
+        assert_eq!(conn.recv_json().await["data"]["channel"], "my-channel");
+        conn.send_event("my-event", Some("my-channel"), &json!({"n": 1}))
+            .await;
+        conn.send_event("my-event", Some("other-channel"), &json!({"n": 2}))
+            .await;
+        conn.send_event("my-event", Some("my-channel"), &json!({"n": 3}))
+            .await;
+        wait_until(|| async { received.lock().unwrap().len() == 2 }).await;
+
+        assert_eq!(*received.lock().unwrap(), vec![r#"{"n":1}"#, r#"{"n":3}"#]);
+    }
+
+    #[tokio::test]
+    async fn test_rest_timeouts_per_operation() {
+        let server = test_server::MockRestServer::start(|_| Duration::from_millis(200)).await;
+        let mut config = server.config();
+        config.request_timeout = Some(Duration::from_millis(50));
+        config.batch_timeout = Some(Duration::from_secs(2));
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        let result = client.trigger("my-channel", "my-event", "{}").await;
+        assert!(matches!(result, Err(PusherError::TimeoutError(_))));
+
+        let result = client
+            .trigger_batch(vec![BatchEvent {
+                channel: "my-channel".to_string(),
+                event: "my-event".to_string(),
+                data: "{}".to_string(),
+                socket_id: None,
+            }])
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_wait_accepts_public_confirmation_name() {
+        let (_server, client, mut conn) = connected_client(|_| {}).await;
+        let server_side = async {
+            conn.recv_json().await;
+            conn.send_event("pusher:subscription_succeeded", Some("news"), &json!({}))
+                .await;
+        };
+        let (result, _) = tokio::join!(
+            client.subscribe_and_wait("news", Duration::from_secs(5)),
+            server_side
+        );
+        result.unwrap();
+        assert!(client.is_subscribed("news").await);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_confirmation_for_all_channel_types() {
+        let (_server, client, mut conn) = connected_client(|_| {}).await;
+
+        client.subscribe("public-room").await.unwrap();
+        client
+            .subscribe_with_auth("private-room", "key:signature", None)
+            .await
+            .unwrap();
+        client.subscribe("private-denied").await.unwrap();
+        for _ in 0..3 {
+            conn.recv_json().await;
+        }
+        assert_eq!(
+            client.subscription_state("public-room").await,
+            Some(SubscriptionState::Pending)
+        );
+        assert!(!client.is_subscribed("public-room").await);
+
+        // Non-presence confirmations carry empty data.
+        conn.send_event("pusher_internal:subscription_succeeded", Some("public-room"), &json!({}))
+            .await;
+        conn.send_event("pusher_internal:subscription_succeeded", Some("private-room"), &json!({}))
+            .await;
+        conn.send_event(
+            "pusher:subscription_error",
+            Some("private-denied"),
+            &json!({"type": "AuthError", "status": 403}),
+        )
+        .await;
+        wait_until(|| async {
+            client.subscription_state("private-denied").await == Some(SubscriptionState::Failed)
+        })
+        .await;
+
+        assert!(client.is_subscribed("public-room").await);
+        assert!(client.is_subscribed("private-room").await);
+        assert_eq!(client.subscription_state("unknown").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_reports_dns_failures() {
+        let config = PusherConfig {
+            host: Some("pusher.invalid".to_string()),
+            backoff_interval: Duration::from_millis(1),
+            ..test_config()
+        };
+        let mut client = PusherClient::new(config).unwrap();
+        assert!(client.last_error().is_none());
+
+        let error = client.connect().await.unwrap_err();
+        assert!(error.is_dns_failure(), "Unexpected error: {}", error);
+        match &*client.last_error().unwrap() {
+            PusherError::DnsError { host, .. } => assert_eq!(host, "pusher.invalid"),
+            other => panic!("Unexpected last error: {:?}", other),
+        }
+        assert_eq!(client.last_error().unwrap().to_string(), error.to_string());
+
+        // Other connection failures aren't flagged as DNS failures.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        client.config.host = Some(addr.to_string());
+        client.config.use_tls = false;
+        let error = client.connect().await.unwrap_err();
+        assert!(!error.is_dns_failure(), "Unexpected error: {}", error);
+        assert!(!client.last_error().unwrap().is_dns_failure());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_many_waits_concurrently() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let mut rx = attach_command_channel(&client);
+        let channels: Vec<String> = (0..20).map(|i| format!("channel-{}", i)).collect();
+        let channel_names: Vec<&str> = channels.iter().map(String::as_str).collect();
+
+        // Nothing confirms, so every channel times out. Waiting one channel at a time would
+        // take 20 timeouts.
+        let timeout = Duration::from_millis(100);
+        let started = std::time::Instant::now();
+        let results = client.subscribe_many(&channel_names, timeout).await;
+        let elapsed = started.elapsed();
+
+        assert_eq!(results.len(), 20);
+        assert!(results
+            .iter()
+            .all(|(_, result)| matches!(result, Err(PusherError::TimeoutError(_)))));
+        assert!(elapsed < timeout * 3, "took {:?}", elapsed);
+        for _ in 0..20 {
+            next_frame(&mut rx).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.handshake_timeout = Duration::from_millis(100);
+        let client = PusherClient::new(config).unwrap();
+
+        // The server accepts the socket but never sends connection_established.
+        let (result, _conn) = tokio::join!(client.connect(), server.accept());
+        match result {
+            Err(PusherError::ConnectionError(message)) => assert_eq!(message, "handshake timed out"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(client.get_connection_state().await, ConnectionState::Disconnected);
+        assert!(client.last_error().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connect_waits_for_connection_established() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+
+        let server_side = async {
+            let mut conn = server.accept().await;
+            // The socket is open, but the connection isn't usable yet.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(client.get_connection_state().await, ConnectionState::Connecting);
+            conn.send_event(
+                "pusher:connection_established",
+                None,
+                &json!({"socket_id": "1.1", "activity_timeout": 120}),
+            )
+            .await;
+            conn
+        };
+        let (result, _conn) = tokio::join!(client.connect(), server_side);
+        result.unwrap();
+        assert_eq!(client.get_connection_state().await, ConnectionState::Connected);
+        assert_eq!(client.get_socket_id().await.unwrap().as_deref(), Some("1.1"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.backoff_interval = Duration::from_millis(10);
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(client.metrics().await, Metrics::default());
+
+        let mut conn = connect(&client, &server, "1.1").await;
+        client.subscribe("a").await.unwrap();
+        client.subscribe("b").await.unwrap();
+        conn.recv_json().await;
+        conn.recv_json().await;
+        conn.send_event("pusher_internal:subscription_succeeded", Some("a"), &json!({}))
+            .await;
+        conn.send_event("pusher:subscription_error", Some("b"), &json!({"status": 403}))
+            .await;
+        conn.send_event("my-event", Some("a"), &json!({})).await;
+        wait_until(|| async { client.metrics().await.events_dispatched == 4 }).await;
+
+        let metrics = client.metrics().await;
+        assert_eq!(metrics.events_received, 4);
+        assert_eq!(metrics.events_dispatched, 4);
+        assert_eq!(metrics.subscribe_failures, 1);
+        assert_eq!(metrics.subscribed_channels, 1);
+        assert!(metrics.bytes_sent > 0 && metrics.bytes_received > 0);
+        assert_eq!(metrics.reconnects, 0);
+
+        drop(conn);
+        let _conn = server.accept_established("1.2").await;
+        wait_for_state(&client, ConnectionState::Connected).await;
+        assert_eq!(client.metrics().await.reconnects, 1);
+
+        let server = test_server::MockRestServer::start_with_statuses(vec![500]).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+        client.trigger("my-channel", "my-event", "{}").await.unwrap_err();
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        let metrics = client.metrics().await;
+        assert_eq!((metrics.rest_requests, metrics.rest_failures), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let server = test_server::MockServer::start().await;
+        let proxy = test_server::MockProxy::start().await;
+        let mut config = server.config();
+        config.proxy = Some(proxy.url());
+        let ws_host = config.host.clone().unwrap();
+        let client = PusherClient::new(config).unwrap();
+
+        let _conn = connect(&client, &server, "1.1").await;
+        let requests = proxy.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with(&format!("CONNECT {} HTTP/1.1\r\n", ws_host)));
+        // "user:secret" in base64.
+        assert!(requests[0].contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+
+        // Plain HTTP REST calls are sent to the proxy with the full URL.
+        let rest_proxy = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let config = PusherConfig {
+            rest_host: Some("pusher.invalid".to_string()),
+            proxy: Some(format!("http://{}", rest_proxy.config().rest_host.unwrap())),
+            ..rest_proxy.config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        assert!(rest_proxy.request_lines()[0].starts_with("POST http://pusher.invalid/apps/123/"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.connect_timeout = Duration::from_millis(100);
+        let client = PusherClient::new(config).unwrap();
+
+        // The TCP connection is queued by the listener, but the websocket handshake is never
+        // answered.
+        let started = tokio::time::Instant::now();
+        match client.connect().await {
+            Err(PusherError::ConnectionError(message)) => assert_eq!(message, "timed out"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(client.get_connection_state().await, ConnectionState::Disconnected);
+
+        // The timeout also bounds the wait for connection_established.
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.connect_timeout = Duration::from_millis(100);
+        let client = PusherClient::new(config).unwrap();
+        let (result, _conn) = tokio::join!(client.connect(), server.accept());
+        match result {
+            Err(PusherError::ConnectionError(message)) => assert_eq!(message, "timed out"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lenient_utf8() {
+        let frame = |text: &str| {
+            let mut frame = format!(r#"{{"event":"my-event","data":"{}"}}"#, text).into_bytes();
+            // An invalid UTF-8 byte inside the data string.
+            frame.insert(frame.len() - 3, 0xff);
+            frame
+        };
+
+        for lenient_utf8 in [false, true] {
+            let server = test_server::MockServer::start().await;
+            let mut config = server.config();
+            config.lenient_utf8 = lenient_utf8;
+            let client = PusherClient::new(config).unwrap();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let received_clone = received.clone();
+            client
+                .bind("my-event", move |event| received_clone.lock().unwrap().push(event.data))
+                .await
+                .unwrap();
+            let mut conn = connect(&client, &server, "1.1").await;
+
+            conn.send_binary(frame("abc")).await;
+            conn.send_event("my-event", None, &json!("next")).await;
+            if lenient_utf8 {
+                // Text frames are decoded lossily too, rather than closing the connection.
+                conn.send_text_bytes(frame("def")).await;
+                conn.send_event("my-event", None, &json!("last")).await;
+            }
+
+            let expected = if lenient_utf8 {
+                vec!["ab\u{fffd}c", r#""next""#, "de\u{fffd}f", r#""last""#]
+            } else {
+                vec![r#""next""#]
+            };
+            wait_until(|| async { received.lock().unwrap().len() == expected.len() }).await;
+            assert_eq!(*received.lock().unwrap(), expected);
+            assert!(client.is_connected().await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lenient_utf8_fragmented_text() {
+        let (_server, client, mut conn) =
+            connected_client(|config| config.lenient_utf8 = true).await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&received);
+        client
+            .bind("my-event", move |event| sink.lock().unwrap().push(event.data))
+            .await
+            .unwrap();
+
+        // A two byte character split between fragments, and an invalid byte in the last.
+        let message = r#"{"event":"my-event","data":"café!"}"#.as_bytes();
+        let split = message.iter().position(|&byte| byte == 0xc3).unwrap() + 1;
+        let mut last = message[split + 1..].to_vec();
+        last.insert(1, 0xff);
+        let parts = vec![message[..split].to_vec(), message[split..split + 1].to_vec(), last];
+        conn.send_text_fragments(parts).await;
+        conn.send_event("my-event", None, &json!("next")).await;
+
+        wait_until(|| async { received.lock().unwrap().len() == 2 }).await;
+        assert_eq!(*received.lock().unwrap(), vec!["café!\u{fffd}", r#""next""#]);
+        assert!(client.is_connected().await);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_subscriptions() {
+        let (_server, client, mut conn) = connected_client(|_| {}).await;
+        let channel = |frame: serde_json::Value| frame["data"]["channel"].clone();
+
+        client.subscribe("a").await.unwrap();
+        client.subscribe("a").await.unwrap();
+        client.subscribe("b").await.unwrap();
+        assert_eq!(channel(conn.recv_json().await), "a");
+        assert_eq!(channel(conn.recv_json().await), "b");
+
+        conn.send_event("pusher_internal:subscription_succeeded", Some("a"), &json!({}))
+            .await;
+        conn.send_event("pusher:subscription_error", Some("b"), &json!({"status": 403}))
+            .await;
+        wait_until(|| async {
+            client.subscription_state("b").await == Some(SubscriptionState::Failed)
+        })
+        .await;
+        client.subscribe_and_wait("a", Duration::from_secs(1)).await.unwrap();
+        client.subscribe("b").await.unwrap();
+        assert_eq!(channel(conn.recv_json().await), "b");
+        client.force_resubscribe("a").await.unwrap();
+        assert_eq!(channel(conn.recv_json().await), "a");
+    }
+
+    #[tokio::test]
+    async fn test_presence_member_limit() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&errors);
+        client
+            .on_error(move |e| sink.lock().unwrap().push(e.to_string()))
+            .await
+            .unwrap();
+        let mut conn = connect(&client, &server, "1.1").await;
+        for channel in ["presence-small", "presence-full"] {
+            let auth = client.authenticate_presence_channel("1.1", channel, "me", None).unwrap();
+            client
+                .subscribe_with_auth(channel, &auth, Some(r#"{"user_id":"me"}"#))
+                .await
+                .unwrap();
+            conn.recv_json().await;
+        }
+        client.subscribe("public").await.unwrap();
+
+        let presence = json!({"count": 2, "ids": ["me", "you"], "hash": {"me": {}, "you": {}}});
+        conn.send_event(
+            "pusher_internal:subscription_succeeded",
+            Some("presence-small"),
+            &json!({ "presence": presence }),
+        )
+        .await;
+        conn.send_event(
+            "pusher:subscription_error",
+            Some("presence-full"),
+            &json!({"type": "LimitReached", "error": "Too many members"}),
+        )
+        .await;
+        wait_until(|| async { !errors.lock().unwrap().is_empty() }).await;
+
+        assert_eq!(client.member_count("presence-small").await, Some(2));
+        assert_eq!(client.member_count("presence-full").await, None);
+        assert_eq!(client.member_count("public").await, None);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec!["Presence channel 'presence-full' is full: Too many members".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frames_are_reported_and_skipped() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&errors);
+        client
+            .on_error(move |e| sink.lock().unwrap().push(e.to_string()))
+            .await
+            .unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        client
+            .bind("my-event", move |event| received_clone.lock().unwrap().push(event.data))
+            .await
+            .unwrap();
+        let mut conn = connect(&client, &server, "1.1").await;
+
+        conn.send_text("{\"event\": garbage").await;
+        conn.send_text(r#"{"data": "no event name"}"#).await;
+        conn.send_event("my-event", None, &json!("ok")).await;
+        wait_until(|| async { !received.lock().unwrap().is_empty() }).await;
+
+        assert!(client.is_connected().await);
+        assert_eq!(*received.lock().unwrap(), vec![r#""ok""#.to_string()]);
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Protocol error: Malformed frame"), "{}", errors[0]);
+        assert!(errors[0].contains("garbage"));
+    }
+
+    #[tokio::test]
+    async fn test_on_raw_message() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let frames_clone = frames.clone();
+        client
+            .on_raw_message(move |frame| frames_clone.lock().unwrap().push(frame.to_string()))
+            .await
+            .unwrap();
+        let mut conn = connect(&client, &server, "1.1").await;
+
+        conn.send_text("not json").await;
+        conn.send_event("my-event", Some("a"), &json!({"n": 1})).await;
+        wait_until(|| async { frames.lock().unwrap().len() == 3 }).await;
+
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].contains("pusher:connection_established"));
+        assert_eq!(frames[1], "not json");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&frames[2]).unwrap(),
+            json!({"event": "my-event", "channel": "a", "data": r#"{"n":1}"#})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_raw_message_from_a_callback() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let registering = client.clone();
+        let frames_clone = frames.clone();
+        client
+            .on_raw_message(move |_| {
+                let frames = frames_clone.clone();
+                // Registering only takes the lock, so the future is ready straight away.
+                registering
+                    .on_raw_message(move |frame| frames.lock().unwrap().push(frame.to_string()))
+                    .now_or_never()
+                    .unwrap()
+                    .unwrap();
+            })
+            .await
+            .unwrap();
+        let mut conn = connect(&client, &server, "1.1").await;
+
+        // Each frame registers another callback, which only sees the frames after it.
+        conn.send_text("first").await;
+        conn.send_text("second").await;
+        wait_until(|| async { frames.lock().unwrap().len() == 3 }).await;
+        assert_eq!(*frames.lock().unwrap(), vec!["first", "second", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_member_diff_across_reconnect() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let diffs = Arc::new(Mutex::new(Vec::new()));
+        let diffs_clone = diffs.clone();
+        client
+            .bind_member_diff(move |channel, diff| diffs_clone.lock().unwrap().push((channel, diff)))
+            .await
+            .unwrap();
+        let roster = |ids: &[&str]| {
+            let hash: serde_json::Map<String, serde_json::Value> = ids
+                .iter()
+                .map(|id| (id.to_string(), json!({ "name": id })))
+                .collect();
+            json!({"presence": {"count": ids.len(), "ids": ids, "hash": hash}})
+        };
+        let member = |id: &str| PresenceMember {
+            user_id: id.to_string(),
+            user_info: Some(json!({ "name": id })),
+        };
+
+        let mut conn = connect(&client, &server, "1.1").await;
+        let auth = client
+            .authenticate_presence_channel("1.1", "presence-room", "me", None)
+            .unwrap();
+        client
+            .subscribe_with_auth("presence-room", &auth, Some(r#"{"user_id":"me"}"#))
+            .await
+            .unwrap();
+        conn.recv_json().await;
+        conn.send_event(
+            "pusher_internal:subscription_succeeded",
+            Some("presence-room"),
+            &roster(&["me", "alice", "bob"]),
+        )
+        .await;
+        conn.send_event(
+            "pusher_internal:member_removed",
+            Some("presence-room"),
+            &json!({"user_id": "bob"}),
+        )
+        .await;
+
+        // Reconnect while alice leaves and carol joins.
+        drop(conn);
+        wait_until(|| async { !client.is_connected().await }).await;
+        let mut conn = connect(&client, &server, "1.2").await;
+        conn.recv_json().await;
+        conn.send_event(
+            "pusher_internal:subscription_succeeded",
+            Some("presence-room"),
+            &roster(&["me", "carol"]),
+        )
+        .await;
+        wait_until(|| async { diffs.lock().unwrap().len() == 3 }).await;
+        assert_eq!(client.me("presence-room").await, Some(member("me")));
+
+        let diffs = diffs.lock().unwrap();
+        assert_eq!(diffs.len(), 3);
+        assert!(diffs.iter().all(|(channel, _)| channel == "presence-room"));
+        assert_eq!(diffs[0].1.added, vec![member("alice"), member("bob"), member("me")]);
+        assert_eq!(diffs[1].1.removed, vec!["bob".to_string()]);
+        assert_eq!(diffs[2].1.added, vec![member("carol")]);
+        assert_eq!(diffs[2].1.removed, vec!["alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_events_are_decrypted_for_handlers() {
+        let rest = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.rest_host = rest.config().rest_host;
+        let client = PusherClient::new(config).unwrap();
+        let mut connection = connect(&client, &server, "1.1").await;
+
+        let channel = "private-encrypted-room";
+        client.subscribe_encrypted(channel).await.unwrap();
+        connection.recv_json().await;
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&received);
+        client
+            .bind("message", move |event| sink.lock().unwrap().push(event.data))
+            .await
+            .unwrap();
+
+        let plaintext = r#"{"text":"hello"}"#;
+        client
+            .trigger_encrypted(channel, "message", plaintext)
+            .await
+            .unwrap();
+        let published = rest.bodies()[0]["data"].as_str().unwrap().to_string();
+        assert!(!published.contains("hello"));
+
+        let frame = json!({"event": "message", "channel": channel, "data": published});
+        connection.send_text(&frame.to_string()).await;
+
+        wait_until(|| async { !received.lock().unwrap().is_empty() }).await;
+        assert_eq!(*received.lock().unwrap(), vec![plaintext.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_undecryptable_events_are_reported_and_dropped() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let channel = "private-encrypted-room";
+        client.subscribe_encrypted(channel).await.unwrap();
+        connection.recv_json().await;
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let error_sink = Arc::clone(&errors);
+        client
+            .on_error(move |e| error_sink.lock().unwrap().push(e.to_string()))
+            .await
+            .unwrap();
+        let received = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&received);
+        client
+            .bind("message", move |_| flag.store(true, Ordering::SeqCst))
+            .await
+            .unwrap();
+
+        // Encrypted with another channel's secret, so the padding check fails.
+        let wrong_secret = client.generate_shared_secret("private-encrypted-other").unwrap();
+        let forged = client.encrypt_data("{}", &wrong_secret).unwrap();
+        for data in ["not base64!", forged.as_str()] {
+            let frame = json!({"event": "message", "channel": channel, "data": data});
+            connection.send_text(&frame.to_string()).await;
+        }
+
+        wait_until(|| async { errors.lock().unwrap().len() == 2 }).await;
+        assert!(errors
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|e| e.starts_with("Decryption error")));
+        assert!(!received.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_data_round_trip() {
+        let client = PusherClient::new_rest_only(test_config()).unwrap();
+        let secret = client.generate_shared_secret("private-encrypted-a").unwrap();
+        for plaintext in ["", "{}", &"x".repeat(100)] {
+            let encrypted = client.encrypt_data(plaintext, &secret).unwrap();
+            assert_eq!(decrypt_data(&encrypted, &secret).unwrap(), plaintext);
+            let quoted = serde_json::to_string(&encrypted).unwrap();
+            assert_eq!(decrypt_data(&quoted, &secret).unwrap(), plaintext);
+        }
+        assert!(decrypt_data(&STANDARD.encode([0u8; 8]), &secret).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_presence_members_are_tracked() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let channel = "presence-room";
+        client
+            .subscribe_builder(channel)
+            .presence("alice", None)
+            .subscribe()
+            .await
+            .unwrap();
+        connection.recv_json().await;
+        assert_eq!(client.members(channel).await, Some(HashMap::new()));
+
+        // Joining a channel that already has members, with a duplicated id.
+        connection
+            .send_event(
+                "pusher_internal:subscription_succeeded",
+                Some(channel),
+                &json!({"presence": {
+                    "count": 2,
+                    "ids": ["alice", "bob", "bob"],
+                    "hash": {"alice": null, "bob": {"name": "Bob"}}
+                }}),
+            )
+            .await;
+        // Bob connecting again only updates his info.
+        connection
+            .send_event(
+                "pusher_internal:member_added",
+                Some(channel),
+                &json!({"user_id": "bob", "user_info": {"name": "Robert"}}),
+            )
+            .await;
+        connection
+            .send_event(
+                "pusher_internal:member_removed",
+                Some(channel),
+                &json!({"user_id": "alice"}),
+            )
+            .await;
+        connection
+            .send_event(
+                "pusher_internal:member_removed",
+                Some(channel),
+                &json!({"user_id": "carol"}),
+            )
+            .await;
+
+        let expected = HashMap::from([("bob".to_string(), json!({"name": "Robert"}))]);
+        wait_until(|| async { client.members(channel).await.as_ref() == Some(&expected) }).await;
+        assert_eq!(client.members("public").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_uses_auth_endpoint() {
+        let auth_server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |body| match body["channel_name"].as_str() {
+                Some("presence-room") => json!({
+                    "auth": format!("key:presence-{}", body["socket_id"].as_str().unwrap()),
+                    "channel_data": r#"{"user_id":"alice"}"#
+                }),
+                _ => json!({"auth": format!("key:{}", body["socket_id"].as_str().unwrap())}),
+            },
+        )
+        .await;
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.auth_endpoint = Some(format!(
+            "http://{}/pusher/auth",
+            auth_server.config().rest_host.unwrap()
+        ));
+        let client = PusherClient::new(config).unwrap();
+        let mut connection = connect(&client, &server, "1.1").await;
+
+        client.subscribe("public").await.unwrap();
+        let frame = connection.recv_json().await;
+        assert!(frame["data"].get("auth").is_none());
+
+        client.subscribe("private-room").await.unwrap();
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["auth"], "key:1.1");
+        assert!(frame["data"].get("channel_data").is_none());
+
+        client.subscribe("presence-room").await.unwrap();
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["auth"], "key:presence-1.1");
+        assert_eq!(frame["data"]["channel_data"], r#"{"user_id":"alice"}"#);
+
+        assert_eq!(
+            auth_server.bodies(),
+            vec![
+                json!({"socket_id": "1.1", "channel_name": "private-room"}),
+                json!({"socket_id": "1.1", "channel_name": "presence-room"}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_tokens_are_cached_per_socket() {
+        for cache_channel_auth in [true, false] {
+            let auth_server = test_server::MockRestServer::start_with_response(
+                |_| Duration::ZERO,
+                |body| json!({"auth": format!("key:{}", body["socket_id"].as_str().unwrap())}),
+            )
+            .await;
+            let server = test_server::MockServer::start().await;
+            let mut config = server.config();
+            config.auth_endpoint =
+                Some(format!("http://{}/auth", auth_server.config().rest_host.unwrap()));
+            config.cache_channel_auth = cache_channel_auth;
+            let client = PusherClient::new(config).unwrap();
+            let mut connection = connect(&client, &server, "1.1").await;
+
+            client.subscribe("private-room").await.unwrap();
+            client.force_resubscribe("private-room").await.unwrap();
+            for _ in 0..2 {
+                assert_eq!(connection.recv_json().await["data"]["auth"], "key:1.1");
+            }
+            let mut requests = if cache_channel_auth { 1 } else { 2 };
+            assert_eq!(auth_server.bodies().len(), requests);
+
+            // A rejected token isn't tried again.
+            connection
+                .send_event(
+                    "pusher:subscription_error",
+                    Some("private-room"),
+                    &json!({"type": "AuthError", "error": "Invalid signature", "status": 401}),
+                )
+                .await;
+            wait_until(|| async {
+                client.subscription_state("private-room").await == Some(SubscriptionState::Failed)
+            })
+            .await;
+            client.force_resubscribe("private-room").await.unwrap();
+            connection.recv_json().await;
+            requests += 1;
+            assert_eq!(auth_server.bodies().len(), requests);
+
+            // A new socket needs a new token.
+            drop(connection);
+            wait_until(|| async { !client.is_connected().await }).await;
+            let mut connection = connect(&client, &server, "1.2").await;
+            assert_eq!(connection.recv_json().await["data"]["auth"], "key:1.2");
+            assert_eq!(auth_server.bodies().len(), requests + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_endpoint_failures_are_auth_errors() {
+        let auth_server =
+            test_server::MockRestServer::start_with_response(|_| Duration::ZERO, |_| json!({}))
+                .await;
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.auth_endpoint =
+            Some(format!("http://{}/auth", auth_server.config().rest_host.unwrap()));
+        let mut client = PusherClient::new(config).unwrap();
+        let _connection = connect(&client, &server, "1.1").await;
+
+        // The response has no `auth` token.
+        let result = client.subscribe("private-room").await;
+        assert!(matches!(result, Err(PusherError::AuthError(_))));
+        assert!(client.get_subscribed_channels().await.is_empty());
+
+        let unused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        client.config.auth_endpoint =
+            Some(format!("http://{}/auth", unused.local_addr().unwrap()));
+        drop(unused);
+        let result = client.subscribe("private-room").await;
+        assert!(matches!(result, Err(PusherError::AuthError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_signs_locally() {
+        let (server, client, mut connection) =
+            connected_client(|config| config.sign_auth_locally = true).await;
+
+        client.subscribe("private-room").await.unwrap();
+        let frame = connection.recv_json().await;
+        assert_eq!(
+            frame["data"]["auth"],
+            client.auth.sign_subscription("1.1", "private-room", None).unwrap()
+        );
+        assert!(matches!(
+            client.subscribe("presence-room").await,
+            Err(PusherError::AuthError(_))
+        ));
+
+        // Resubscribing after a reconnect signs for the new socket.
+        client.disconnect().await.unwrap();
+        let mut connection = connect(&client, &server, "2.2").await;
+        let frame = connection.recv_json().await;
+        assert_eq!(
+            frame["data"]["auth"],
+            client.auth.sign_subscription("2.2", "private-room", None).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_triggers_can_exclude_a_socket() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        client
+            .trigger_excluding("my-channel", "my-event", "{}", "1.1")
+            .await
+            .unwrap();
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        client
+            .trigger_batch(vec![
+                BatchEvent {
+                    channel: "channel-a".to_string(),
+                    event: "my-event".to_string(),
+                    data: "{}".to_string(),
+                    socket_id: Some("2.2".to_string()),
+                },
+                BatchEvent {
+                    channel: "channel-b".to_string(),
+                    event: "my-event".to_string(),
+                    data: "{}".to_string(),
+                    socket_id: None,
+                },
+            ])
+            .await
+            .unwrap();
+
+        for socket_id in ["", "1", "1.", "a.1", "1.1.1"] {
+            let result = client
+                .trigger_excluding("my-channel", "my-event", "{}", socket_id)
+                .await;
+            assert!(matches!(result, Err(PusherError::InvalidArgument(_))));
+        }
+        let result = client
+            .trigger_batch(vec![BatchEvent {
+                channel: "channel-a".to_string(),
+                event: "my-event".to_string(),
+                data: "{}".to_string(),
+                socket_id: Some("2".to_string()),
+            }])
+            .await;
+        assert!(matches!(result, Err(PusherError::InvalidArgument(_))));
+
+        let bodies = server.bodies();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0]["socket_id"], "1.1");
+        assert!(bodies[1].get("socket_id").is_none());
+        assert_eq!(bodies[2]["batch"][0]["socket_id"], "2.2");
+        assert!(bodies[2]["batch"][1].get("socket_id").is_none());
+    }
+
+    async fn wait_for_state(client: &PusherClient, state: ConnectionState) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while client.get_connection_state().await != state {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("State never became {:?}", state));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_connection_drops() {
+        let (server, client, mut connection) =
+            connected_client(|config| config.backoff_interval = Duration::from_millis(10)).await;
+        client.subscribe("news").await.unwrap();
+        connection.recv_json().await;
+        // Without an auth endpoint or local signing, private channels aren't authorized by
+        // `subscribe`, and neither are they when resubscribing.
+        client.subscribe("private-news").await.unwrap();
+        assert_eq!(connection.recv_json().await["data"].get("auth"), None);
+
+        drop(connection);
+        let mut connection = server.accept_established("2.2").await;
+        let mut frames = [connection.recv_json().await, connection.recv_json().await];
+        frames.sort_by_key(|frame| frame["data"]["channel"].to_string());
+        assert_eq!(frames[0]["event"], "pusher:subscribe");
+        assert_eq!(frames[0]["data"]["channel"], "news");
+        assert_eq!(frames[1]["data"]["channel"], "private-news");
+        assert_eq!(frames[1]["data"].get("auth"), None);
+        assert_eq!(client.get_connection_state().await, ConnectionState::Connected);
+        assert_eq!(client.get_socket_id().await.unwrap().as_deref(), Some("2.2"));
+
+        // Frames sent after reconnecting reach the new connection.
+        client.subscribe("sport").await.unwrap();
+        assert_eq!(connection.recv_json().await["data"]["channel"], "sport");
+
+        client.disconnect().await.unwrap();
+        wait_for_state(&client, ConnectionState::Disconnected).await;
+    }
+
+    #[tokio::test]
+    async fn test_reconnection_gives_up_after_max_attempts() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.backoff_interval = Duration::from_millis(5);
+        config.max_reconnection_attempts = 2;
+        let client = PusherClient::new(config).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&errors);
+        client
+            .on_error(move |e| sink.lock().unwrap().push(e.to_string()))
+            .await
+            .unwrap();
+        let connection = connect(&client, &server, "1.1").await;
+
+        // Nothing is listening any more, so every attempt is refused.
+        drop(server);
+        drop(connection);
+        wait_for_state(&client, ConnectionState::Failed).await;
+        assert_eq!(client.get_socket_id().await.unwrap(), None);
+        assert!(errors
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| e.contains("Gave up reconnecting after 2 attempts")));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_delay_is_capped() {
+        let config = PusherConfig {
+            backoff_interval: Duration::from_secs(1),
+            max_reconnect_interval: Duration::from_secs(5),
+            ..test_config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        assert_eq!(client.reconnect_delay(0), Duration::from_secs(1));
+        assert_eq!(client.reconnect_delay(2), Duration::from_secs(4));
+        assert_eq!(client.reconnect_delay(3), Duration::from_secs(5));
+        assert_eq!(client.reconnect_delay(40), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_channels_are_reauthorized_after_reconnect() {
+        let (server, client, mut connection) =
+            connected_client(|config| config.backoff_interval = Duration::from_millis(10)).await;
+
+        client.subscribe("public").await.unwrap();
+        client
+            .subscribe_with_auth("private-room", "key:token-for-1.1", None)
+            .await
+            .unwrap();
+        client
+            .subscribe_builder("private-encrypted-vault")
+            .encrypted()
+            .subscribe()
+            .await
+            .unwrap();
+        client
+            .subscribe_builder("presence-lobby")
+            .presence("alice", None)
+            .subscribe()
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            connection.recv_json().await;
+        }
+        let mut channels_before = client.get_subscribed_channels().await;
+        channels_before.sort();
+
+        drop(connection);
+        let mut connection = server.accept_established("2.2").await;
+        let mut auths = HashMap::new();
+        for _ in 0..4 {
+            let frame = connection.recv_json().await;
+            auths.insert(
+                frame["data"]["channel"].as_str().unwrap().to_string(),
+                frame["data"].get("auth").cloned(),
+            );
+        }
+
+        assert_eq!(auths["public"], None);
+        for channel in ["private-room", "private-encrypted-vault"] {
+            assert_eq!(
+                auths[channel],
+                Some(json!(client.auth.authenticate_socket("2.2", channel).unwrap()))
+            );
+        }
+        assert_eq!(
+            auths["presence-lobby"],
+            Some(json!(client
+                .authenticate_presence_channel("2.2", "presence-lobby", "alice", None)
+                .unwrap()))
+        );
+        let mut channels_after = client.get_subscribed_channels().await;
+        channels_after.sort();
+        assert_eq!(channels_after, channels_before);
+        assert!(client
+            .encrypted_channels
+            .read()
+            .await
+            .contains_key("private-encrypted-vault"));
+        assert_eq!(
+            client.subscription_state("private-room").await,
+            Some(SubscriptionState::Pending)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_pinged() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.activity_timeout = Duration::from_millis(100);
+        config.pong_timeout = Duration::from_millis(100);
+        config.max_reconnection_attempts = 0;
+        let client = PusherClient::new(config).unwrap();
+        let server_side = async {
+            let mut connection = server.accept().await;
+            connection
+                .send_event(
+                    "pusher:connection_established",
+                    None,
+                    &json!({"socket_id": "1.1", "activity_timeout": 120}),
+                )
+                .await;
+            connection
+        };
+        let (result, mut connection) = tokio::join!(client.connect(), server_side);
+        result.unwrap();
+        assert_eq!(client.activity_timeout(), Some(Duration::from_millis(100)));
+
+        // Answering keeps the connection up.
+        for _ in 0..2 {
+            assert_eq!(connection.recv_json().await["event"], "pusher:ping");
+            connection.send_event("pusher:pong", None, &json!({})).await;
+        }
+        assert_eq!(client.get_connection_state().await, ConnectionState::Connected);
+
+        // Without an answer, the connection is dropped.
+        assert_eq!(connection.recv_json().await["event"], "pusher:ping");
+        wait_for_state(&client, ConnectionState::Failed).await;
+        assert_eq!(client.activity_timeout(), None);
+    }
+
+    #[tokio::test]
+    async fn test_shorter_server_activity_timeout_is_used() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let server_side = async {
+            let mut connection = server.accept().await;
+            connection
+                .send_event(
+                    "pusher:connection_established",
+                    None,
+                    &json!({"socket_id": "1.1", "activity_timeout": 1}),
+                )
+                .await;
+            connection
+        };
+        let (result, mut connection) = tokio::join!(client.connect(), server_side);
+        result.unwrap();
+        assert_eq!(client.activity_timeout(), Some(Duration::from_secs(1)));
+        assert_eq!(connection.recv_json().await["event"], "pusher:ping");
+    }
+
+    #[tokio::test]
+    async fn test_configured_activity_timeout_is_used_without_server_one() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let server_side = async {
+            let mut connection = server.accept().await;
+            connection
+                .send_event("pusher:connection_established", None, &json!({"socket_id": "1.1"}))
+                .await;
+            connection
+        };
+        let (result, _connection) = tokio::join!(client.connect(), server_side);
+        result.unwrap();
+        assert_eq!(client.activity_timeout(), Some(Duration::from_secs(120)));
+        assert_eq!(client.get_connection_info().unwrap().activity_timeout, None);
+    }
+
+    #[tokio::test]
+    async fn test_server_ping_is_answered() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        for name in ["pusher:ping", "my-event"] {
+            let sink = Arc::clone(&received);
+            client
+                .bind(name, move |event| sink.lock().unwrap().push(event.event))
+                .await
+                .unwrap();
+        }
+
+        connection.send_event("pusher:ping", None, &json!({})).await;
+        assert_eq!(connection.recv_json().await["event"], "pusher:pong");
+        // Events are dispatched in order, so the ping has been by the time this one is.
+        connection.send_event("my-event", None, &json!({})).await;
+        wait_until(|| async { !received.lock().unwrap().is_empty() }).await;
+        assert_eq!(*received.lock().unwrap(), vec!["my-event"]);
+    }
+
+    #[tokio::test]
+    async fn test_unbind() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let client = PusherClient::new(test_config()).unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = |hits: &Arc<AtomicUsize>| {
+            let hits = Arc::clone(hits);
+            move |_| {
+                hits.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let first = client.bind("update", counter(&hits)).await.unwrap();
+        let second = client.bind("update", counter(&hits)).await.unwrap();
+        client.bind("other", counter(&hits)).await.unwrap();
+        let system = client.bind_system(|_| {}).await.unwrap();
+        assert_ne!(first, second);
+
+        let update = Event::new("update".to_string(), None, "{}".to_string());
+        let other = Event::new("other".to_string(), None, "{}".to_string());
+        client.send_test_event(update.clone()).await.unwrap();
+        wait_until(|| async { hits.load(Ordering::SeqCst) == 2 }).await;
+
+        assert!(client.unbind_handler(first).await);
+        assert!(!client.unbind_handler(first).await);
+        client.send_test_event(update.clone()).await.unwrap();
+        wait_until(|| async { hits.load(Ordering::SeqCst) == 3 }).await;
+
+        assert!(client.unbind_handler(second).await);
+        assert!(!client.event_handlers.read().await.contains_key("update"));
+        client.unbind("update").await;
+        assert!(client.unbind_handler(system).await);
+        assert!(client.global_handlers.read().await.is_empty());
+
+        client.bind_system(|_| {}).await.unwrap();
+        client.unbind_all().await;
+        client.send_test_event(other).await.unwrap();
+        wait_until(|| async { client.metrics().await.events_dispatched == 3 }).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert!(client.event_handlers.read().await.is_empty());
+        assert!(client.global_handlers.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bind_channel() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&received);
+        let id = client
+            .bind_channel("room-a", "update", move |event| {
+                seen.lock().unwrap().push(event.channel.unwrap());
+            })
+            .await
+            .unwrap();
+
+        for channel in [Some("room-a"), Some("room-b"), None, Some("room-a")] {
+            let event = Event::new("update".to_string(), channel.map(str::to_string), "{}".into());
+            client.send_test_event(event).await.unwrap();
+        }
+        wait_until(|| async { received.lock().unwrap().len() == 2 }).await;
+        assert_eq!(*received.lock().unwrap(), vec!["room-a", "room-a"]);
+        assert!(client.unbind_handler(id).await);
+    }
+
+    #[tokio::test]
+    async fn test_bind_global_runs_after_named_handlers() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let global_calls = Arc::clone(&calls);
+        client
+            .bind_global(move |event| {
+                global_calls.lock().unwrap().push(format!("global:{}", event.event));
+            })
+            .await
+            .unwrap();
+        let named_calls = Arc::clone(&calls);
+        client
+            .bind("update", move |event| {
+                named_calls.lock().unwrap().push(format!("named:{}", event.event));
+            })
+            .await
+            .unwrap();
+
+        for name in ["update", "pusher:connection_established"] {
+            let event = Event::new(name.to_string(), None, "{}".to_string());
+            client.send_test_event(event).await.unwrap();
+        }
+        wait_until(|| async { calls.lock().unwrap().len() == 3 }).await;
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "named:update",
+                "global:update",
+                "global:pusher:connection_established"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_channels() {
+        let server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |_| json!({"channels": {"presence-a": {"user_count": 2}, "presence-b": {}}}),
+        )
+        .await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let list = client.get_channels(Some("presence-"), &["user_count"]).await.unwrap();
+        assert_eq!(list.channels.len(), 2);
+        assert_eq!(list.channels["presence-a"].user_count, Some(2));
+        assert!(list.channels["presence-b"].occupied);
+
+        let request_line = &server.request_lines()[0];
+        assert!(request_line.starts_with("GET /apps/123/channels?"));
+        assert!(request_line.contains("filter_by_prefix=presence-"));
+        assert!(request_line.contains("info=user_count"));
+        assert!(request_line.contains("auth_signature="));
+        assert!(!request_line.contains("body_md5"));
+    }
+
+    #[tokio::test]
+    async fn test_get_channel() {
+        let server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |_| json!({"occupied": true, "user_count": 3, "subscription_count": 4}),
+        )
+        .await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let info = client
+            .get_channel("presence-a", &["user_count", "subscription_count"])
+            .await
+            .unwrap();
+        assert_eq!(
+            info,
+            ChannelInfo {
+                occupied: true,
+                user_count: Some(3),
+                subscription_count: Some(4),
+            }
+        );
+        let request_line = &server.request_lines()[0];
+        assert!(request_line.starts_with("GET /apps/123/channels/presence-a?"));
+        assert!(request_line.contains("info=user_count%2Csubscription_count"));
+
+        // Names that would change the signed path are rejected before the request.
+        for name in ["", "a/../../users", "a?info=x", "a b"] {
+            let result = client.get_channel(name, &[]).await;
+            assert!(matches!(result, Err(PusherError::ChannelError(_))), "{:?}", name);
+        }
+        assert_eq!(server.request_lines().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_channel_users() {
+        let server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |_| json!({"users": [{"id": "alice"}, {"id": "bob"}]}),
+        )
+        .await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let users = client.get_channel_users("presence-room").await.unwrap();
+        assert_eq!(users, vec!["alice", "bob"]);
+        let request_line = &server.request_lines()[0];
+        assert!(request_line.starts_with("GET /apps/123/channels/presence-room/users?"));
+
+        for name in ["private-room", "room", "presence-../../channels", "presence-a?b"] {
+            let result = client.get_channel_users(name).await;
+            assert!(matches!(result, Err(PusherError::ChannelError(_))), "{:?}", name);
+        }
+        assert_eq!(server.request_lines().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_signin() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let user = UserData {
+            id: "alice".to_string(),
+            user_info: None,
+            watchlist: None,
+        };
+        assert!(matches!(
+            client.signin(&user).await,
+            Err(PusherError::ConnectionError(_))
+        ));
+
+        let mut conn = connect(&client, &server, "1.1").await;
+        client.signin(&user).await.unwrap();
+        let frame = conn.recv_json().await;
+        let expected = client.authenticate_user("1.1", &user).unwrap();
+        assert_eq!(frame["event"], "pusher:signin");
+        assert_eq!(frame["data"]["auth"], expected.auth);
+        assert_eq!(frame["data"]["user_data"], r#"{"id":"alice"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_trigger_client_event() {
+        let (_server, client, mut conn) = connected_client(|_| {}).await;
+
+        client
+            .subscribe_with_auth("private-room", "key:signature", None)
+            .await
+            .unwrap();
+        client.subscribe("public-room").await.unwrap();
+        conn.recv_json().await;
+        conn.recv_json().await;
+        let result = client.trigger_client_event("private-room", "client-typing", "{}").await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+
+        for channel in ["private-room", "public-room"] {
+            conn.send_event("pusher_internal:subscription_succeeded", Some(channel), &json!({}))
+                .await;
+        }
+        wait_until(|| async { client.is_subscribed("public-room").await }).await;
+
+        client
+            .trigger_client_event("private-room", "client-typing", r#"{"user": "alice"}"#)
+            .await
+            .unwrap();
+        let frame = conn.recv_json().await;
+        assert_eq!(frame["event"], "client-typing");
+        assert_eq!(frame["channel"], "private-room");
+        assert_eq!(frame["data"]["user"], "alice");
+
+        let result = client.trigger_client_event("public-room", "client-typing", "{}").await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+        let result = client.trigger_client_event("private-room", "typing", "{}").await;
+        assert!(matches!(result, Err(PusherError::EventError(_))));
+        let result = client.trigger_client_event("private-room", "client-typing", "{").await;
+        assert!(matches!(result, Err(PusherError::JsonError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_custom_http_client_is_used() {
+        let server = test_server::MockRestServer::start(|_| Duration::from_secs(5)).await;
+        let config = PusherConfig {
+            http_client: Some(
+                reqwest::Client::builder()
+                    .timeout(Duration::from_millis(50))
+                    .build()
+                    .unwrap(),
+            ),
+            ..server.config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        let result = client.trigger("my-channel", "my-event", "{}").await;
+        assert!(matches!(result, Err(PusherError::TimeoutError(_))));
+    }
+
+    #[test]
+    fn test_api_error() {
+        let error = api_error(
+            "Failed to trigger event",
+            reqwest::StatusCode::FORBIDDEN,
+            r#"{"error": "Channel not allowed", "code": 4009}"#,
+        );
+        match error {
+            PusherError::ApiError {
+                status,
+                code,
+                message,
+            } => {
+                assert_eq!(status, 403);
+                assert_eq!(code, Some(4009));
+                assert_eq!(message, "Failed to trigger event: Channel not allowed");
+            }
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        let error = api_error("Failed", reqwest::StatusCode::UNAUTHORIZED, "Invalid signature\n");
+        assert_eq!(error.to_string(), "API error (status 401): Failed: Invalid signature");
+        let error = api_error("Failed", reqwest::StatusCode::BAD_GATEWAY, "");
+        assert_eq!(error.to_string(), "API error (status 502): Failed: 502 Bad Gateway");
+    }
+
+    #[tokio::test]
+    async fn test_user_rest_calls() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        client
+            .send_to_user("alice", "notification", r#"{"text":"hi"}"#)
+            .await
+            .unwrap();
+        client.terminate_user_connections("alice").await.unwrap();
+        let result = client.terminate_user_connections("a/b").await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+
+        let bodies = server.bodies();
+        assert_eq!(bodies[0]["channel"], "#server-to-user-alice");
+        assert_eq!(bodies[0]["name"], "notification");
+        let lines = server.request_lines();
+        assert!(lines[0].starts_with("POST /apps/123/events?"));
+        assert!(lines[1].starts_with("POST /apps/123/users/alice/terminate_connections?"));
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_requests() {
+        let server = test_server::MockRestServer::start_rate_limited(1).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+        let result = client.trigger("my-channel", "my-event", "{}").await;
+        match result {
+            Err(PusherError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_secs(1))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // With retries enabled, the request is sent again once the server allows it.
+        let server = test_server::MockRestServer::start_rate_limited(0).await;
+        let config = PusherConfig {
+            http_retries: 1,
+            ..server.config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        assert_eq!(server.bodies().len(), 2);
+
+        // Unless it asks to wait longer than allowed.
+        let server = test_server::MockRestServer::start_rate_limited(3600).await;
+        let config = PusherConfig {
+            http_retries: 1,
+            ..server.config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+        match client.trigger("my-channel", "my-event", "{}").await {
+            Err(PusherError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_secs(3600))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(server.bodies().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rest_requests_are_retried() {
+        let config = |server: &test_server::MockRestServer| PusherConfig {
+            http_retries: 2,
+            http_retry_backoff: Duration::from_millis(1),
+            ..server.config()
+        };
+
+        let server = test_server::MockRestServer::start_with_statuses(vec![503, 502]).await;
+        let client = PusherClient::new_rest_only(config(&server)).unwrap();
+        client.trigger("my-channel", "my-event", "{}").await.unwrap();
+        assert_eq!(server.bodies().len(), 3);
+
+        // Auth failures aren't retried.
+        let server = test_server::MockRestServer::start_with_statuses(vec![401]).await;
+        let client = PusherClient::new_rest_only(config(&server)).unwrap();
+        let result = client.trigger("my-channel", "my-event", "{}").await;
+        assert!(matches!(result, Err(PusherError::ApiError { status: 401, .. })));
+        assert_eq!(server.bodies().len(), 1);
+
+        let server = test_server::MockRestServer::start_with_statuses(vec![500; 3]).await;
+        let client = PusherClient::new_rest_only(config(&server)).unwrap();
+        let result = client.get_channel("my-channel", &[]).await;
+        assert!(matches!(result, Err(PusherError::ApiError { status: 500, .. })));
+        assert_eq!(server.request_lines().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rest_timeouts_are_retried() {
+        let server = test_server::MockRestServer::start(|_| Duration::from_secs(5)).await;
+        let config = PusherConfig {
+            http_retries: 1,
+            http_retry_backoff: Duration::from_millis(1),
+            request_timeout: Some(Duration::from_millis(50)),
+            ..server.config()
+        };
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        let started = tokio::time::Instant::now();
+        let result = client.trigger("my-channel", "my-event", "{}").await;
+        assert!(matches!(result, Err(PusherError::TimeoutError(_))));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_callbacks() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.backoff_interval = Duration::from_millis(10);
+        let client = PusherClient::new(config).unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for (name, state) in [
+            ("connecting", ConnectionState::Connecting),
+            ("reconnecting", ConnectionState::Reconnecting),
+            ("failed", ConnectionState::Failed),
+        ] {
+            let calls = Arc::clone(&calls);
+            let callback = move || calls.lock().unwrap().push(name);
+            match state {
+                ConnectionState::Connecting => client.on_connecting(callback).await,
+                ConnectionState::Reconnecting => client.on_reconnecting(callback).await,
+                _ => client.on_failed(callback).await,
+            }
+            .unwrap();
+        }
+
+        let connection = connect(&client, &server, "1.1").await;
+        drop(connection);
+        let mut connection = server.accept_established("1.2").await;
+        wait_for_state(&client, ConnectionState::Connected).await;
+        assert_eq!(*calls.lock().unwrap(), vec!["connecting", "reconnecting"]);
+
+        // 4000-4099 close codes are fatal.
+        connection.close(4001, "App disabled").await;
+        wait_for_state(&client, ConnectionState::Failed).await;
+        assert_eq!(*calls.lock().unwrap(), vec!["connecting", "reconnecting", "failed"]);
+    }
+
+    #[tokio::test]
+    async fn test_on_state_change() {
+        use ConnectionState::*;
+
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&changes);
+        client
+            .on_state_change(move |previous, current| {
+                recorded.lock().unwrap().push((previous, current));
+            })
+            .await
+            .unwrap();
+
+        let _connection = connect(&client, &server, "1.1").await;
+        client.disconnect().await.unwrap();
+        wait_until(|| async { changes.lock().unwrap().len() == 3 }).await;
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                (Disconnected, Connecting),
+                (Connecting, Connected),
+                (Connected, Disconnected)
+            ]
+        );
+
+        let config = PusherConfig {
+            host: Some("127.0.0.1:1".to_string()),
+            ..server.config()
+        };
+        let client = PusherClient::new(config).unwrap();
+        let recorded = Arc::clone(&changes);
+        client
+            .on_state_change(move |previous, current| {
+                recorded.lock().unwrap().push((previous, current));
+            })
+            .await
+            .unwrap();
+        changes.lock().unwrap().clear();
+        assert!(client.connect().await.is_err());
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(Disconnected, Connecting), (Connecting, Disconnected)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_wait() {
+        let (_server, client, mut conn) = connected_client(|_| {}).await;
+
+        let timeout = Duration::from_secs(5);
+        let server_side = async {
+            assert_eq!(conn.recv_json().await["data"]["channel"], "room");
+            conn.send_event("pusher_internal:subscription_succeeded", Some("room"), &json!({}))
+                .await;
+        };
+        let (result, _) = tokio::join!(client.subscribe_and_wait("room", timeout), server_side);
+        result.unwrap();
+        assert!(client.is_subscribed("room").await);
+
+        let server_side = async {
+            conn.recv_json().await;
+            conn.send_event(
+                "pusher:subscription_error",
+                Some("private-denied"),
+                &json!({"type": "AuthError", "error": "Forbidden", "status": 403}),
+            )
+            .await;
+        };
+        let (result, _) =
+            tokio::join!(client.subscribe_and_wait("private-denied", timeout), server_side);
+        match result {
+            Err(PusherError::SubscriptionError { channel, status, .. }) => {
+                assert_eq!(channel, "private-denied");
+                assert_eq!(status, Some(403));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let result = client
+            .subscribe_and_wait("silent", Duration::from_millis(50))
+            .await;
+        assert!(matches!(result, Err(PusherError::TimeoutError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_flushes_queued_frames() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        for channel in ["a", "b", "c"] {
+            client.subscribe(channel).await.unwrap();
+        }
+        client.disconnect().await.unwrap();
+        assert!(client.websocket_task.lock().unwrap().is_none());
+        assert_eq!(client.get_connection_state().await, ConnectionState::Disconnected);
+
+        for channel in ["a", "b", "c"] {
+            assert_eq!(connection.recv_json().await["data"]["channel"], channel);
+        }
+        connection.expect_closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_stops_stuck_reconnection() {
+        let (_server, client, connection) = connected_client(|config| {
+            config.backoff_interval = Duration::from_millis(5);
+            config.close_timeout = Duration::from_millis(100);
+        })
+        .await;
+
+        // The server stops accepting, so the reconnection hangs in the websocket handshake.
+        drop(connection);
+        wait_for_state(&client, ConnectionState::Reconnecting).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = tokio::time::Instant::now();
+        client.disconnect().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        wait_for_state(&client, ConnectionState::Disconnected).await;
+    }
+
+    #[tokio::test]
+    async fn test_event_streams() {
+        use futures_util::StreamExt;
+
+        let client = PusherClient::new(test_config()).unwrap();
+        let mut first = Box::pin(client.events().unwrap());
+        let mut second = Box::pin(client.events().unwrap());
+
+        let event = Event::new("update".to_string(), Some("a".to_string()), "{}".to_string());
+        client.send_test_event(event).await.unwrap();
+        for stream in [&mut first, &mut second] {
+            let received = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received.event, "update");
+            assert_eq!(received.channel.as_deref(), Some("a"));
+        }
+
+        // A stream that falls behind skips to the oldest event it still has.
+        for i in 0..EVENT_STREAM_CAPACITY + 10 {
+            let event = Event::new(format!("event-{}", i), None, "{}".to_string());
+            client.send_test_event(event).await.unwrap();
+        }
+        let sent = 1 + EVENT_STREAM_CAPACITY as u64 + 10;
+        wait_until(|| async { client.metrics().await.events_dispatched == sent }).await;
+        let received = first.next().await.unwrap();
+        assert_eq!(received.event, "event-10");
+
+        let rest_only = PusherClient::new_rest_only(test_config()).unwrap();
+        assert!(matches!(rest_only.events(), Err(PusherError::RealtimeDisabled)));
+    }
+
+    #[tokio::test]
+    async fn test_close_codes_drive_reconnection() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        // Long enough that only an immediate reconnect makes it within the test.
+        config.backoff_interval = Duration::from_secs(30);
+        let client = PusherClient::new(config).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&errors);
+        client
+            .on_error(move |e| {
+                if let PusherError::ConnectionClosed { code, reason } = e {
+                    sink.lock().unwrap().push((*code, reason.clone()));
+                }
+            })
+            .await
+            .unwrap();
+        let mut connection = connect(&client, &server, "1.1").await;
+
+        connection.close(4200, "Generic reconnect immediately").await;
+        let mut connection = tokio::time::timeout(
+            Duration::from_secs(5),
+            server.accept_established("2.2"),
+        )
+        .await
+        .expect("The client didn't reconnect immediately");
+        wait_for_state(&client, ConnectionState::Connected).await;
+
+        connection.close(4001, "Application is disabled").await;
+        wait_for_state(&client, ConnectionState::Failed).await;
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![
+                (4200, "Generic reconnect immediately".to_string()),
+                (4001, "Application is disabled".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backoff_close_codes() {
+        let (server, client, mut connection) =
+            connected_client(|config| config.backoff_interval = Duration::from_millis(30)).await;
+
+        // 4100-4199 close codes back off ten times longer than the usual 30ms.
+        connection.close(4150, "Back off").await;
+        let early = tokio::time::timeout(Duration::from_millis(150), server.accept());
+        assert!(early.await.is_err(), "The client reconnected without backing off");
+        let _connection = server.accept_established("1.2").await;
+        wait_for_state(&client, ConnectionState::Connected).await;
+    }
+
+    #[tokio::test]
+    async fn test_on_pusher_error() {
+        let server = test_server::MockServer::start().await;
+        let client = PusherClient::new(server.config()).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&errors);
+        client
+            .on_pusher_error(move |code, message| sink.lock().unwrap().push((code, message)))
+            .await
+            .unwrap();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&reported);
+        client
+            .on_error(move |error| {
+                if let PusherError::PusherProtocolError { code, .. } = error {
+                    sink.lock().unwrap().push(*code);
+                }
+            })
+            .await
+            .unwrap();
+        let mut connection = connect(&client, &server, "1.1").await;
+
+        connection
+            .send_event(
+                "pusher:error",
+                None,
+                &json!({"code": 4301, "message": "Client event rejected"}),
+            )
+            .await;
+        connection
+            .send_event("pusher:error", None, &json!({"code": null, "message": "No code"}))
+            .await;
+        wait_until(|| async { client.metrics().await.events_dispatched == 3 }).await;
+        assert_eq!(client.get_connection_state().await, ConnectionState::Connected);
+
+        // A fatal code ends the connection for good, even if the server keeps it open.
+        connection
+            .send_event(
+                "pusher:error",
+                None,
+                &json!({"code": 4001, "message": "App key not found"}),
+            )
+            .await;
+        wait_for_state(&client, ConnectionState::Failed).await;
+        connection.expect_closed().await;
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![
+                (4301, "Client event rejected".to_string()),
+                (4001, "App key not found".to_string()),
+            ]
+        );
+        assert_eq!(*reported.lock().unwrap(), vec![4301, 4001]);
+    }
+
+    #[tokio::test]
+    async fn test_member_added_and_removed_callbacks() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let joined = Arc::new(Mutex::new(Vec::new()));
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&joined);
+        client
+            .on_member_added("presence-room", move |id, info| {
+                sink.lock().unwrap().push((id, info))
+            })
+            .await
+            .unwrap();
+        let sink = Arc::clone(&left);
+        client
+            .on_member_removed("presence-room", move |id| sink.lock().unwrap().push(id))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.on_member_added("private-room", |_, _| {}).await,
+            Err(PusherError::ChannelError(_))
+        ));
+
+        for (name, channel, data) in [
+            ("member_added", "presence-room", r#"{"user_id":"alice","user_info":{"name":"A"}}"#),
+            ("member_added", "presence-other", r#"{"user_id":"bob","user_info":{}}"#),
+            ("member_removed", "presence-other", r#"{"user_id":"bob"}"#),
+            ("member_removed", "presence-room", r#"{"user_id":"alice"}"#),
+        ] {
+            client
+                .send_test_event(Event::new(
+                    format!("pusher_internal:{}", name),
+                    Some(channel.to_string()),
+                    data.to_string(),
+                ))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async { !left.lock().unwrap().is_empty() }).await;
+
+        assert_eq!(
+            *joined.lock().unwrap(),
+            vec![("alice".to_string(), json!({"name": "A"}))]
+        );
+        assert_eq!(*left.lock().unwrap(), vec!["alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_names_are_rejected_before_sending() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let client = PusherClient::new(server.config()).unwrap();
+
+        assert!(matches!(
+            client.subscribe("my channel").await,
+            Err(PusherError::ChannelError(message)) if message.contains("position 2")
+        ));
+        assert!(!client.is_subscribed("my channel").await);
+        assert!(matches!(
+            client.trigger("news!", "my-event", "{}").await,
+            Err(PusherError::ChannelError(_))
+        ));
+        assert!(matches!(
+            client.trigger("news", &"e".repeat(201), "{}").await,
+            Err(PusherError::EventError(_))
+        ));
+        assert!(server.bodies().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_payload_size_limit() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let mut config = server.config();
+        config.max_payload_size = 16;
+        let client = PusherClient::new_rest_only(config).unwrap();
+
+        client.trigger("news", "my-event", r#"{"n":"01234567"}"#).await.unwrap();
+        assert!(matches!(
+            client.trigger("news", "my-event", r#"{"n":"012345678"}"#).await,
+            Err(PusherError::PayloadTooLarge { size: 17, limit: 16 })
+        ));
+        let event = |data: &str| BatchEvent {
+            channel: "news".to_string(),
+            event: "my-event".to_string(),
+            data: data.to_string(),
+            socket_id: None,
+        };
+        let large = format!("\"{}\"", "a".repeat(20));
+        assert!(matches!(
+            client.trigger_batch(vec![event("{}"), event(&large)]).await,
+            Err(PusherError::PayloadTooLarge { size: 22, .. })
+        ));
+        assert_eq!(server.bodies().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_trigger_multi() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        client
+            .trigger_multi(&["news", "sport"], "my-event", r#"{"n":1}"#)
+            .await
+            .unwrap();
+        client.trigger("news", "my-event", "{}").await.unwrap();
+        let too_many: Vec<String> = (0..=MAX_TRIGGER_CHANNELS).map(|i| format!("c{}", i)).collect();
+        let too_many: Vec<&str> = too_many.iter().map(String::as_str).collect();
+        assert!(matches!(
+            client.trigger_multi(&too_many, "my-event", "{}").await,
+            Err(PusherError::ChannelError(_))
+        ));
+        assert!(matches!(
+            client.trigger_multi(&[], "my-event", "{}").await,
+            Err(PusherError::ChannelError(_))
+        ));
+
+        let bodies = server.bodies();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0]["channels"], json!(["news", "sport"]));
+        assert!(bodies[0].get("channel").is_none());
+        assert_eq!(bodies[0]["data"], r#"{"n":1}"#);
+        assert_eq!(bodies[1]["channel"], "news");
+        assert!(bodies[1].get("channels").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_trigger_value() {
+        let server = test_server::MockRestServer::start(|_| Duration::ZERO).await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        client
+            .trigger_value("news", "my-event", &json!({"text": "hi", "n": [1, 2]}))
+            .await
+            .unwrap();
+        let result = client.trigger_value("bad channel", "my-event", &json!({})).await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+        let result = client.trigger("news", "my-event", "{").await;
+        assert!(matches!(result, Err(PusherError::JsonError(_))));
+
+        let bodies = server.bodies();
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0]["channel"], "news");
+        let data: serde_json::Value =
+            serde_json::from_str(bodies[0]["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data, json!({"text": "hi", "n": [1, 2]}));
+    }
+
+    #[tokio::test]
+    async fn test_trigger_with_info() {
+        let server = test_server::MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |body| match body.get("info") {
+                Some(_) => json!({"channels": {"presence-room": {
+                    "subscription_count": 2, "user_count": 1
+                }}}),
+                None => json!({}),
+            },
+        )
+        .await;
+        let client = PusherClient::new_rest_only(server.config()).unwrap();
+
+        let info = ["subscription_count", "user_count"];
+        let response = client
+            .trigger_with_info("presence-room", "my-event", "{}", &info)
+            .await
+            .unwrap();
+        let info = &response.channels["presence-room"];
+        assert_eq!(info.subscription_count, Some(2));
+        assert_eq!(info.user_count, Some(1));
+        assert_eq!(server.bodies()[0]["info"], "subscription_count,user_count");
+
+        let response = client
+            .trigger_with_info("presence-room", "my-event", "{}", &[])
+            .await
+            .unwrap();
+        assert!(response.channels.is_empty());
+        assert!(server.bodies()[1].get("info").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bind_async_awaits_each_handler() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        client
+            .bind_async("my-event", move |event| {
+                let sink = Arc::clone(&sink);
+                async move {
+                    sink.lock().unwrap().push(format!("start {}", event.data));
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    sink.lock().unwrap().push(format!("end {}", event.data));
+                }
+            })
+            .await
+            .unwrap();
+
+        for n in 1..=2 {
+            client
+                .send_test_event(Event::new("my-event".to_string(), None, n.to_string()))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async { log.lock().unwrap().len() == 4 }).await;
+
+        assert_eq!(*log.lock().unwrap(), vec!["start 1", "end 1", "start 2", "end 2"]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_info() {
+        let server = test_server::MockServer::start().await;
+        let mut config = server.config();
+        config.max_reconnection_attempts = 0;
+        let client = PusherClient::new(config).unwrap();
+        assert!(client.get_connection_info().is_none());
+
+        let connection = connect(&client, &server, "1.1").await;
+        assert_eq!(
+            client.get_connection_info(),
+            Some(ConnectionInfo {
+                socket_id: "1.1".to_string(),
+                activity_timeout: Some(Duration::from_secs(120)),
+            })
+        );
+
+        drop(connection);
+        wait_for_state(&client, ConnectionState::Failed).await;
+        assert!(client.get_connection_info().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cloned_handle_shares_the_connection() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let handle = client.clone();
+        tokio::spawn(async move { handle.subscribe("news").await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(connection.recv_json().await["data"]["channel"], "news");
+        assert_eq!(
+            client.subscription_state("news").await,
+            Some(SubscriptionState::Pending)
+        );
+
+        client.clone().disconnect().await.unwrap();
+        connection.expect_closed().await;
+        assert_eq!(client.get_connection_state().await, ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_and_unbind() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let mut commands = attach_command_channel(&client);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        for (channel, label) in [("news", "news"), ("sport", "sport")] {
+            let sink = Arc::clone(&received);
+            client
+                .bind_channel(channel, "my-event", move |_| sink.lock().unwrap().push(label))
+                .await
+                .unwrap();
+        }
+        let sink = Arc::clone(&received);
+        client
+            .bind("my-event", move |_| sink.lock().unwrap().push("global"))
+            .await
+            .unwrap();
+
+        client.subscribe("news").await.unwrap();
+        next_frame(&mut commands).await;
+        client.unsubscribe_and_unbind("news").await.unwrap();
+        assert_eq!(next_frame(&mut commands).await["event"], "pusher:unsubscribe");
+        assert_eq!(client.unbind_channel("news").await, 0);
+
+        for channel in ["news", "sport"] {
+            client
+                .send_test_event(Event::new(
+                    "my-event".to_string(),
+                    Some(channel.to_string()),
+                    "{}".to_string(),
+                ))
+                .await
+                .unwrap();
+        }
+        wait_until(|| async { received.lock().unwrap().len() == 3 }).await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["global", "sport", "global"]);
     }
 }
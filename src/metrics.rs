@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the client's counters, as returned by `PusherClient::metrics`. The counters
+/// start at zero when the client is created and keep counting across reconnects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Events received over the websocket, including `pusher:*` system events.
+    pub events_received: u64,
+    /// Events passed to the bound handlers, after decryption. Events that fail to decrypt
+    /// aren't dispatched, while each member diff of a presence channel counts once more.
+    pub events_dispatched: u64,
+    /// Connections re-established after dropping.
+    pub reconnects: u64,
+    /// Subscriptions the server rejected.
+    pub subscribe_failures: u64,
+    /// Bytes of frames written to the websocket.
+    pub bytes_sent: u64,
+    /// Bytes of frames read from the websocket.
+    pub bytes_received: u64,
+    /// REST requests sent, counting each retry.
+    pub rest_requests: u64,
+    /// REST requests that failed to send or got an error status.
+    pub rest_failures: u64,
+    /// Channels whose subscription the server has confirmed.
+    pub subscribed_channels: usize,
+}
+
+/// The live counters behind `Metrics`, shared by the client, its dispatcher and the
+/// websocket loop.
+#[derive(Default)]
+pub(crate) struct MetricsCounters {
+    pub events_received: AtomicU64,
+    pub events_dispatched: AtomicU64,
+    pub reconnects: AtomicU64,
+    pub subscribe_failures: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub rest_requests: AtomicU64,
+    pub rest_failures: AtomicU64,
+}
+
+impl MetricsCounters {
+    /// Adds `n` to `counter`.
+    pub fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, subscribed_channels: usize) -> Metrics {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Metrics {
+            events_received: get(&self.events_received),
+            events_dispatched: get(&self.events_dispatched),
+            reconnects: get(&self.reconnects),
+            subscribe_failures: get(&self.subscribe_failures),
+            bytes_sent: get(&self.bytes_sent),
+            bytes_received: get(&self.bytes_received),
+            rest_requests: get(&self.rest_requests),
+            rest_failures: get(&self.rest_failures),
+            subscribed_channels,
+        }
+    }
+}
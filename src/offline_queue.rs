@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// A trigger that couldn't reach the REST API, kept to be sent again.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct QueuedTrigger {
+    pub channel: String,
+    pub event: String,
+    pub data: String,
+    pub idempotency_key: Option<String>,
+    pub socket_id: Option<String>,
+}
+
+/// Triggers waiting for the REST API to be reachable again, oldest first. Once `capacity`
+/// triggers are queued, queuing another drops the oldest.
+pub(crate) struct OfflineQueue {
+    capacity: usize,
+    triggers: Mutex<VecDeque<QueuedTrigger>>,
+    /// Held while the queue is sent, so its triggers go out one at a time and in order.
+    sending: tokio::sync::Mutex<()>,
+    /// Whether a task is retrying the queue until it is empty.
+    retrying: AtomicBool,
+}
+
+impl OfflineQueue {
+    /// A queue for up to `capacity` triggers. With a capacity of 0 nothing is queued.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            triggers: Mutex::new(VecDeque::new()),
+            sending: tokio::sync::Mutex::new(()),
+            retrying: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.triggers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Queues `trigger` behind the others, returning the oldest one if it was dropped to
+    /// make room.
+    pub fn push_back(&self, trigger: QueuedTrigger) -> Option<QueuedTrigger> {
+        let mut triggers = self.triggers.lock().unwrap();
+        let dropped = if triggers.len() >= self.capacity {
+            triggers.pop_front()
+        } else {
+            None
+        };
+        triggers.push_back(trigger);
+        dropped
+    }
+
+    /// Puts back a trigger taken with `pop_front` that still couldn't be sent. If the queue
+    /// filled up meanwhile, it is the oldest, so it is returned as dropped instead.
+    pub fn push_front(&self, trigger: QueuedTrigger) -> Option<QueuedTrigger> {
+        let mut triggers = self.triggers.lock().unwrap();
+        if triggers.len() >= self.capacity {
+            return Some(trigger);
+        }
+        triggers.push_front(trigger);
+        None
+    }
+
+    pub fn pop_front(&self) -> Option<QueuedTrigger> {
+        self.triggers.lock().unwrap().pop_front()
+    }
+
+    /// Marks the queue as being retried, returning false if another task already is.
+    pub fn start_retrying(&self) -> bool {
+        !self.retrying.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn stop_retrying(&self) {
+        self.retrying.store(false, Ordering::Release);
+    }
+
+    /// Waits for any other task sending the queue to finish, returning a guard to hold while
+    /// sending it.
+    pub async fn lock_sending(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.sending.lock().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger(event: &str) -> QueuedTrigger {
+        QueuedTrigger {
+            channel: "my-channel".to_string(),
+            event: event.to_string(),
+            data: "{}".to_string(),
+            idempotency_key: None,
+            socket_id: None,
+        }
+    }
+
+    #[test]
+    fn test_drops_oldest_when_full() {
+        let queue = OfflineQueue::new(2);
+        assert!(queue.is_enabled());
+        assert_eq!(queue.push_back(trigger("a")), None);
+        assert_eq!(queue.push_back(trigger("b")), None);
+        assert_eq!(queue.push_back(trigger("c")), Some(trigger("a")));
+        assert_eq!(queue.len(), 2);
+        assert!(queue.is_full());
+
+        let b = queue.pop_front().unwrap();
+        assert_eq!(b.event, "b");
+        queue.push_back(trigger("d"));
+        assert_eq!(queue.push_front(b), Some(trigger("b")));
+        assert_eq!(queue.pop_front(), Some(trigger("c")));
+        assert_eq!(queue.push_front(trigger("c")), None);
+        assert_eq!(queue.pop_front(), Some(trigger("c")));
+        assert_eq!(queue.pop_front(), Some(trigger("d")));
+        assert!(queue.is_empty());
+
+        assert!(!OfflineQueue::new(0).is_enabled());
+    }
+
+    #[test]
+    fn test_one_retrying_task_at_a_time() {
+        let queue = OfflineQueue::new(1);
+        assert!(queue.start_retrying());
+        assert!(!queue.start_retrying());
+        queue.stop_retrying();
+        assert!(queue.start_retrying());
+    }
+}
@@ -0,0 +1,389 @@
+use std::time::Duration;
+
+use crate::channels::{Channel, ChannelType, PresenceMember};
+use crate::error::{PusherError, PusherResult};
+use crate::{presence_channel_data, Event, EventCallback, PusherClient, SubscribeOptions};
+
+/// How long `await_confirmation` waits unless `confirmation_timeout` is set.
+const DEFAULT_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Composes a subscription with all its options, see `PusherClient::subscribe_builder`.
+///
+/// Private, presence and encrypted channels are authorized for the current socket unless a
+/// token is given with `auth`, so the client must be connected first. They are authorized
+/// by `PusherConfig::auth_endpoint` if set, and signed with the app secret otherwise.
+/// Duplicates are skipped and the options are sent again after a reconnect, as with
+/// `PusherClient::subscribe`.
+pub struct SubscribeBuilder<'a> {
+    client: &'a PusherClient,
+    channel_name: String,
+    presence: Option<PresenceMember>,
+    encrypted: bool,
+    auth: Option<String>,
+    confirmation_timeout: Option<Duration>,
+    handlers: Vec<(String, EventCallback)>,
+    subscription_count: bool,
+}
+
+impl<'a> SubscribeBuilder<'a> {
+    pub(crate) fn new(client: &'a PusherClient, channel_name: &str) -> Self {
+        Self {
+            client,
+            channel_name: channel_name.to_string(),
+            presence: None,
+            encrypted: false,
+            auth: None,
+            confirmation_timeout: None,
+            handlers: Vec::new(),
+            subscription_count: false,
+        }
+    }
+
+    /// Joins a presence channel as this member.
+    pub fn presence(mut self, user_id: &str, user_info: Option<serde_json::Value>) -> Self {
+        self.presence = Some(PresenceMember {
+            user_id: user_id.to_string(),
+            user_info,
+        });
+        self
+    }
+
+    /// Subscribes to a `private-encrypted-` channel, so its events are decrypted.
+    pub fn encrypted(mut self) -> Self {
+        self.encrypted = true;
+        self
+    }
+
+    /// Uses a token from an auth endpoint instead of signing the subscription locally.
+    pub fn auth(mut self, token: &str) -> Self {
+        self.auth = Some(token.to_string());
+        self
+    }
+
+    /// Waits for the server to confirm the subscription before `subscribe` returns.
+    pub fn await_confirmation(mut self) -> Self {
+        self.confirmation_timeout
+            .get_or_insert(DEFAULT_CONFIRMATION_TIMEOUT);
+        self
+    }
+
+    /// Waits up to `timeout` for the server to confirm the subscription.
+    pub fn confirmation_timeout(mut self, timeout: Duration) -> Self {
+        self.confirmation_timeout = Some(timeout);
+        self
+    }
+
+    /// Binds a handler for an event on this channel before the subscription is sent.
+    pub fn on_event<F>(mut self, event_name: &str, callback: F) -> Self
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        self.handlers
+            .push((event_name.to_string(), Box::new(callback)));
+        self
+    }
+
+    /// Asks the server to send subscription counts, see `PusherClient::subscribe_with_count`.
+    pub fn with_count(mut self) -> Self {
+        self.subscription_count = true;
+        self
+    }
+
+    fn validate(&self) -> PusherResult<ChannelType> {
+        Channel::validate_name(&self.channel_name)?;
+        let channel_type = ChannelType::from_name(&self.channel_name);
+        let invalid = |reason: &str| {
+            Err(PusherError::ChannelError(format!(
+                "Invalid subscription to '{}': {}",
+                self.channel_name, reason
+            )))
+        };
+        match channel_type {
+            _ if self.encrypted && channel_type != ChannelType::PrivateEncrypted => {
+                invalid("encrypted channels must start with 'private-encrypted-'")
+            }
+            _ if self.presence.is_some() && channel_type != ChannelType::Presence => {
+                invalid("presence data is only for 'presence-' channels")
+            }
+            ChannelType::Presence if self.presence.is_none() => {
+                invalid("presence channels need presence data")
+            }
+            ChannelType::Public if self.auth.is_some() => {
+                invalid("public channels don't take an auth token")
+            }
+            _ => Ok(channel_type),
+        }
+    }
+
+    /// Validates the options and subscribes.
+    ///
+    /// # Returns
+    ///
+    /// A `PusherResult` indicating success or failure. With `await_confirmation`, failure
+    /// includes the server rejecting the subscription or not answering in time.
+    pub async fn subscribe(self) -> PusherResult<()> {
+        let channel_type = self.validate()?;
+        let client = self.client;
+        client.require_realtime()?;
+        let channel_name = self.channel_name.as_str();
+
+        // A given token goes with the member data it was signed for.
+        let channel_data = match &self.auth {
+            Some(_) => self.presence.as_ref().map(presence_channel_data).transpose()?,
+            None => None,
+        };
+        if channel_type == ChannelType::PrivateEncrypted {
+            let shared_secret = client.generate_shared_secret(channel_name)?;
+            client
+                .encrypted_channels
+                .write()
+                .await
+                .insert(channel_name.to_string(), shared_secret);
+        }
+        client
+            .register_channel_handlers(channel_name, self.handlers)
+            .await;
+
+        let options = SubscribeOptions {
+            auth: self.auth,
+            channel_data,
+            presence: self.presence,
+            always_authorize: true,
+            subscription_count: self.subscription_count,
+        };
+        let Some(timeout) = self.confirmation_timeout else {
+            return client.subscribe_channel(channel_name, options).await;
+        };
+        let confirmation = client.expect_confirmation(channel_name);
+        if let Err(e) = client.subscribe_channel(channel_name, options).await {
+            client
+                .pending_subscriptions
+                .lock()
+                .unwrap()
+                .remove(channel_name);
+            return Err(e);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        client
+            .await_confirmation(channel_name, confirmation, deadline, timeout)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{connected_client, test_config, wait_until, MockRestServer};
+    use serde_json::json;
+    use crate::SubscriptionState;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_public_subscription_with_count_handler_and_confirmation() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&received);
+        let subscribe = client
+            .subscribe_builder("news")
+            .with_count()
+            .on_event("story", move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .await_confirmation()
+            .subscribe();
+        let server_side = async {
+            let frame = connection.recv_json().await;
+            assert_eq!(frame["event"], "pusher:subscribe");
+            assert_eq!(frame["data"]["channel"], "news");
+            assert_eq!(frame["data"]["subscription_count"], true);
+            assert!(frame["data"].get("auth").is_none());
+            connection
+                .send_event(
+                    "pusher_internal:subscription_succeeded",
+                    Some("news"),
+                    &json!({}),
+                )
+                .await;
+            connection
+                .send_event("story", Some("news"), &json!({}))
+                .await;
+            connection
+                .send_event("story", Some("other"), &json!({}))
+                .await;
+        };
+        let (result, _) = tokio::join!(subscribe, server_side);
+        result.unwrap();
+        assert!(client.is_subscribed("news").await);
+
+        // Events are dispatched in order, so with both stories in only the first of them was
+        // on the channel.
+        wait_until(|| async { client.metrics().await.events_dispatched == 4 }).await;
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_confirmation_reports_rejection() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let subscribe = client
+            .subscribe_builder("private-secret")
+            .auth("key:signature")
+            .confirmation_timeout(Duration::from_secs(5))
+            .subscribe();
+        let server_side = async {
+            let frame = connection.recv_json().await;
+            assert_eq!(frame["data"]["auth"], "key:signature");
+            connection
+                .send_event(
+                    "pusher:subscription_error",
+                    Some("private-secret"),
+                    &json!({"type": "AuthError", "error": "Forbidden", "status": 403}),
+                )
+                .await;
+        };
+        let (result, _) = tokio::join!(subscribe, server_side);
+        assert!(matches!(result, Err(PusherError::SubscriptionError { .. })));
+        assert_eq!(
+            client.subscription_state("private-secret").await,
+            Some(SubscriptionState::Failed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_combinations_are_rejected() {
+        let client = PusherClient::new(test_config()).unwrap();
+
+        let result = client
+            .subscribe_builder("presence-room")
+            .presence("alice", None)
+            .encrypted()
+            .subscribe()
+            .await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+
+        let result = client
+            .subscribe_builder("private-room")
+            .presence("alice", None)
+            .subscribe()
+            .await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+
+        let result = client.subscribe_builder("presence-room").subscribe().await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+
+        let result = client
+            .subscribe_builder("public")
+            .auth("key:signature")
+            .subscribe()
+            .await;
+        assert!(matches!(result, Err(PusherError::ChannelError(_))));
+
+        assert!(client.get_subscribed_channels().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_presence_subscription_is_signed_locally() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let user_info = json!({"name": "Alice"});
+        client
+            .subscribe_builder("presence-room")
+            .presence("alice", Some(user_info.clone()))
+            .subscribe()
+            .await
+            .unwrap();
+
+        let frame = connection.recv_json().await;
+        let expected_auth = client
+            .authenticate_presence_channel("1.1", "presence-room", "alice", Some(&user_info))
+            .unwrap();
+        assert_eq!(frame["data"]["auth"], expected_auth);
+        assert_eq!(
+            frame["data"]["channel_data"],
+            json!({"user_id": "alice", "user_info": user_info}).to_string()
+        );
+        assert_eq!(
+            client.subscription_state("presence-room").await,
+            Some(SubscriptionState::Pending)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_subscription_stores_shared_secret() {
+        let (_server, client, mut connection) = connected_client(|_| {}).await;
+
+        let channel = "private-encrypted-room";
+        client
+            .subscribe_builder(channel)
+            .encrypted()
+            .subscribe()
+            .await
+            .unwrap();
+
+        let frame = connection.recv_json().await;
+        assert_eq!(
+            frame["data"]["auth"],
+            client.auth.authenticate_socket("1.1", channel).unwrap()
+        );
+        assert_eq!(
+            client.encrypted_channels.read().await.get(channel),
+            Some(&client.generate_shared_secret(channel).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_subscription_uses_auth_endpoint_and_survives_reconnect() {
+        let auth_server = MockRestServer::start_with_response(
+            |_| Duration::ZERO,
+            |body| json!({"auth": format!("key:{}", body["socket_id"].as_str().unwrap())}),
+        )
+        .await;
+        let (server, client, mut connection) = connected_client(|config| {
+            config.app_secret = String::new();
+            config.backoff_interval = Duration::from_millis(10);
+            config.auth_endpoint = Some(format!(
+                "http://{}/pusher/auth",
+                auth_server.config().rest_host.unwrap()
+            ));
+        })
+        .await;
+
+        for _ in 0..2 {
+            client
+                .subscribe_builder("private-room")
+                .with_count()
+                .subscribe()
+                .await
+                .unwrap();
+        }
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["auth"], "key:1.1");
+        assert_eq!(frame["data"]["subscription_count"], true);
+
+        drop(connection);
+        let mut connection = server.accept_established("2.2").await;
+        let frame = connection.recv_json().await;
+        assert_eq!(frame["data"]["channel"], "private-room");
+        assert_eq!(frame["data"]["auth"], "key:2.2");
+        assert_eq!(frame["data"]["subscription_count"], true);
+        assert_eq!(auth_server.bodies().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_signing_without_app_secret_is_rejected() {
+        let (_server, client, _connection) =
+            connected_client(|config| config.app_secret = String::new()).await;
+        let result = client.subscribe_builder("private-room").subscribe().await;
+        assert!(matches!(result, Err(PusherError::AuthError(_))));
+        assert!(client.get_subscribed_channels().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_channel_needs_connection() {
+        let client = PusherClient::new(test_config()).unwrap();
+        let result = client.subscribe_builder("private-room").subscribe().await;
+        assert!(matches!(result, Err(PusherError::ConnectionError(_))));
+    }
+}
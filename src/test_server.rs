@@ -0,0 +1,424 @@
+//! A minimal Pusher-like websocket server for exercising the client in tests.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderName};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::{accept_hdr_async, tungstenite::protocol::Message, WebSocketStream};
+
+use crate::{PusherClient, PusherConfig};
+
+pub struct MockServer {
+    listener: TcpListener,
+    addr: SocketAddr,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        Self { listener, addr }
+    }
+
+    /// A client config pointing at this server.
+    pub fn config(&self) -> PusherConfig {
+        PusherConfig {
+            app_id: "123".to_string(),
+            app_key: "key".to_string(),
+            app_secret: "secret".to_string(),
+            cluster: "mt1".to_string(),
+            host: Some(self.addr.to_string()),
+            use_tls: false,
+            ..Default::default()
+        }
+    }
+
+    /// Accepts the next websocket connection without sending anything.
+    pub async fn accept(&self) -> MockConnection {
+        self.accept_with_headers(&[]).await
+    }
+
+    /// Accepts the next websocket connection, adding `headers` to the handshake response.
+    // The callback's error type is dictated by tungstenite.
+    #[allow(clippy::result_large_err)]
+    pub async fn accept_with_headers(&self, headers: &[(&str, &str)]) -> MockConnection {
+        let (stream, _) = self.listener.accept().await.unwrap();
+        let mut request_headers = HeaderMap::new();
+        let add_headers = |request: &Request, mut response: Response| {
+            request_headers = request.headers().clone();
+            for (name, value) in headers {
+                let name = HeaderName::from_bytes(name.as_bytes()).unwrap();
+                response.headers_mut().insert(name, value.parse().unwrap());
+            }
+            Ok(response)
+        };
+        let socket = accept_hdr_async(stream, add_headers).await.unwrap();
+        MockConnection {
+            socket,
+            request_headers,
+        }
+    }
+
+    /// Accepts the next connection and completes the Pusher handshake with `socket_id`.
+    pub async fn accept_established(&self, socket_id: &str) -> MockConnection {
+        let mut connection = self.accept().await;
+        connection
+            .send_event(
+                "pusher:connection_established",
+                None,
+                &json!({"socket_id": socket_id, "activity_timeout": 120}),
+            )
+            .await;
+        connection
+    }
+}
+
+pub struct MockConnection {
+    socket: WebSocketStream<TcpStream>,
+    request_headers: HeaderMap,
+}
+
+impl MockConnection {
+    /// The headers the client sent with the upgrade request.
+    pub fn request_headers(&self) -> &HeaderMap {
+        &self.request_headers
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.socket
+            .send(Message::Text(text.to_string()))
+            .await
+            .unwrap();
+    }
+
+    pub async fn send_binary(&mut self, data: Vec<u8>) {
+        self.socket.send(Message::Binary(data)).await.unwrap();
+    }
+
+    /// Sends `data` in a text frame, even if it isn't valid UTF-8.
+    pub async fn send_text_bytes(&mut self, data: Vec<u8>) {
+        let frame = Frame::message(data, OpCode::Data(Data::Text), true);
+        self.socket.send(Message::Frame(frame)).await.unwrap();
+    }
+
+    /// Sends `parts` as the fragments of one text message, even if it isn't valid UTF-8,
+    /// with a ping between each two of them.
+    pub async fn send_text_fragments(&mut self, parts: Vec<Vec<u8>>) {
+        let last = parts.len() - 1;
+        for (i, part) in parts.into_iter().enumerate() {
+            let data = if i == 0 { Data::Text } else { Data::Continue };
+            let frame = Frame::message(part, OpCode::Data(data), i == last);
+            self.socket.send(Message::Frame(frame)).await.unwrap();
+            if i < last {
+                self.socket.send(Message::Ping(Vec::new())).await.unwrap();
+            }
+        }
+    }
+
+    /// Sends an event with `data` encoded as a JSON string, the way Pusher does.
+    pub async fn send_event(&mut self, event: &str, channel: Option<&str>, data: &Value) {
+        let mut frame = json!({"event": event, "data": data.to_string()});
+        if let Some(channel) = channel {
+            frame["channel"] = json!(channel);
+        }
+        self.send_text(&frame.to_string()).await;
+    }
+
+    /// Closes the connection with a close frame carrying `code` and `reason`.
+    pub async fn close(&mut self, code: u16, reason: &str) {
+        let frame = CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.to_string().into(),
+        };
+        self.socket.close(Some(frame)).await.unwrap();
+    }
+
+    /// Waits for the client to close the connection, failing on any other text frame.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            let message = timeout(Duration::from_secs(5), self.socket.next())
+                .await
+                .expect("Timed out waiting for the connection to close");
+            match message {
+                Some(Ok(Message::Text(text))) => panic!("Unexpected frame: {}", text),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// Waits for the next text frame from the client, parsed as JSON.
+    pub async fn recv_json(&mut self) -> Value {
+        loop {
+            let message = timeout(Duration::from_secs(5), self.socket.next())
+                .await
+                .expect("Timed out waiting for a frame")
+                .expect("Connection closed")
+                .unwrap();
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+}
+
+/// A config for a client that isn't connected in the test.
+pub fn test_config() -> PusherConfig {
+    PusherConfig {
+        app_id: "123".to_string(),
+        app_key: "key".to_string(),
+        app_secret: "secret".to_string(),
+        cluster: "mt1".to_string(),
+        ..Default::default()
+    }
+}
+
+/// Polls `condition` until it holds, instead of sleeping for long enough.
+pub async fn wait_until<F: Future<Output = bool>>(mut condition: impl FnMut() -> F) {
+    timeout(Duration::from_secs(5), async {
+        while !condition().await {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("Condition never held");
+}
+
+/// Connects `client` to `server`, which accepts it as `socket_id`.
+pub async fn connect(
+    client: &PusherClient,
+    server: &MockServer,
+    socket_id: &str,
+) -> MockConnection {
+    let (result, connection) =
+        tokio::join!(client.connect(), server.accept_established(socket_id));
+    result.unwrap();
+    connection
+}
+
+/// A client connected as `1.1` to a new mock server, with `configure` applied to the
+/// server's config.
+pub async fn connected_client(
+    configure: impl FnOnce(&mut PusherConfig),
+) -> (MockServer, PusherClient, MockConnection) {
+    let server = MockServer::start().await;
+    let mut config = server.config();
+    configure(&mut config);
+    let client = PusherClient::new(config).unwrap();
+    let connection = connect(&client, &server, "1.1").await;
+    (server, client, connection)
+}
+
+/// An HTTP proxy that only supports `CONNECT` tunnels, recording the head of each request.
+pub struct MockProxy {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockProxy {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let recorded = Arc::clone(&recorded);
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let read = stream.read(&mut buf).await.unwrap();
+                        if read == 0 {
+                            return;
+                        }
+                        head.extend_from_slice(&buf[..read]);
+                    }
+                    let head = String::from_utf8_lossy(&head).into_owned();
+                    let target = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+                    recorded.lock().unwrap().push(head);
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    stream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://user:secret@{}", self.addr)
+    }
+
+    /// The request heads received, such as `CONNECT 127.0.0.1:1234 HTTP/1.1\r\n...`.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// A minimal HTTP server standing in for the REST API. Every request is answered with a
+/// status (`200` unless given) and a JSON body (`{}` unless given) after a delay chosen from
+/// its JSON body, and the bodies are recorded in the order the responses were sent, along
+/// with the request lines. `429` responses ask to retry after a second.
+/// Form bodies are recorded as JSON objects of strings; requests without a body as `null`.
+pub struct MockRestServer {
+    addr: SocketAddr,
+    bodies: Arc<Mutex<Vec<Value>>>,
+    request_lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockRestServer {
+    pub async fn start(delay: fn(&Value) -> Duration) -> Self {
+        Self::start_with_response(delay, |_| json!({})).await
+    }
+
+    /// Like `start`, but answers each request with the JSON returned by `respond`.
+    pub async fn start_with_response(
+        delay: fn(&Value) -> Duration,
+        respond: fn(&Value) -> Value,
+    ) -> Self {
+        Self::start_inner(Self::bind().await, delay, respond, Vec::new(), 1)
+    }
+
+    /// Like `start`, but answers the first requests with `statuses`, in order. A `429` asks
+    /// to retry after a second.
+    pub async fn start_with_statuses(statuses: Vec<u16>) -> Self {
+        Self::start_inner(Self::bind().await, |_| Duration::ZERO, |_| json!({}), statuses, 1)
+    }
+
+    /// Like `start`, but answers the first request with a `429` asking to retry after
+    /// `retry_after` seconds.
+    pub async fn start_rate_limited(retry_after: u64) -> Self {
+        let listener = Self::bind().await;
+        Self::start_inner(listener, |_| Duration::ZERO, |_| json!({}), vec![429], retry_after)
+    }
+
+    /// Like `start`, but serves `listener`, such as one made from a socket that refused
+    /// connections until now.
+    pub fn start_on(listener: TcpListener) -> Self {
+        Self::start_inner(listener, |_| Duration::ZERO, |_| json!({}), Vec::new(), 1)
+    }
+
+    async fn bind() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    fn start_inner(
+        listener: TcpListener,
+        delay: fn(&Value) -> Duration,
+        respond: fn(&Value) -> Value,
+        statuses: Vec<u16>,
+        retry_after: u64,
+    ) -> Self {
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let addr = listener.local_addr().unwrap();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let request_lines = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&bodies);
+        let recorded_lines = Arc::clone(&request_lines);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let recorded = Arc::clone(&recorded);
+                let recorded_lines = Arc::clone(&recorded_lines);
+                let statuses = Arc::clone(&statuses);
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let (request_line, body) = read_request(&mut stream).await;
+                    let status = statuses.lock().unwrap().next().unwrap_or(200);
+                    sleep(delay(&body)).await;
+                    let response_body = respond(&body).to_string();
+                    recorded.lock().unwrap().push(body);
+                    recorded_lines.lock().unwrap().push(request_line);
+                    let retry_after = if status == 429 {
+                        format!("retry-after: {}\r\n", retry_after)
+                    } else {
+                        String::new()
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {} Status\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+                        status,
+                        retry_after,
+                        response_body.len(),
+                        response_body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        Self {
+            addr,
+            bodies,
+            request_lines,
+        }
+    }
+
+    /// A client config sending REST requests to this server.
+    pub fn config(&self) -> PusherConfig {
+        PusherConfig {
+            app_id: "123".to_string(),
+            app_key: "key".to_string(),
+            app_secret: "secret".to_string(),
+            cluster: "mt1".to_string(),
+            rest_host: Some(self.addr.to_string()),
+            use_tls: false,
+            ..Default::default()
+        }
+    }
+
+    pub fn bodies(&self) -> Vec<Value> {
+        self.bodies.lock().unwrap().clone()
+    }
+
+    /// The request lines, such as `GET /apps/123/channels?... HTTP/1.1`.
+    pub fn request_lines(&self) -> Vec<String> {
+        self.request_lines.lock().unwrap().clone()
+    }
+}
+
+/// Reads a request, returning its request line and parsed body.
+async fn read_request(stream: &mut TcpStream) -> (String, Value) {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let read = stream.read(&mut buf).await.unwrap();
+        if read == 0 {
+            return (String::new(), Value::Null);
+        }
+        request.extend_from_slice(&buf[..read]);
+
+        let Some(header_end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let head = String::from_utf8_lossy(&request[..header_end]);
+        let request_line = head.lines().next().unwrap_or_default().to_string();
+        let headers = head.to_lowercase();
+        let content_length = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        let body = &request[header_end + 4..];
+        if body.len() >= content_length {
+            let body = &body[..content_length];
+            if headers.contains("application/x-www-form-urlencoded") {
+                let form: serde_json::Map<String, Value> = url::form_urlencoded::parse(body)
+                    .map(|(name, value)| (name.into_owned(), json!(value)))
+                    .collect();
+                return (request_line, Value::Object(form));
+            }
+            return (request_line, serde_json::from_slice(body).unwrap_or(Value::Null));
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const TEXT_OPCODE: u8 = 0x1;
+const BINARY_OPCODE: u8 = 0x2;
+/// The longest frame header: two bytes, an 8 byte length and a 4 byte mask.
+const MAX_HEADER_LEN: usize = 14;
+
+/// Wraps the stream of a websocket client so that text frames from the server read as
+/// binary ones, when enabled.
+///
+/// The websocket library rejects a text message that isn't valid UTF-8 before its bytes can
+/// be seen, closing the connection. Relabelling text frames as binary hands every message
+/// over as bytes instead, to be decoded lossily. The frames are only relabelled, never
+/// buffered, so this costs a scan of each frame header. Only the opcode is changed, so a
+/// fragmented text message becomes a binary one whose continuation frames are left as
+/// they are, and the FIN and RSV bits keep their meaning for the library to check.
+pub(crate) struct TextAsBinary<S> {
+    inner: S,
+    scanner: Option<FrameScanner>,
+}
+
+impl<S> TextAsBinary<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        Self {
+            inner,
+            scanner: enabled.then(FrameScanner::default),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TextAsBinary<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some(scanner) = &mut this.scanner {
+            scanner.scan(&mut buf.filled_mut()[start..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TextAsBinary<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Follows the frames read from the server, relabelling text frames as it goes.
+#[derive(Debug, PartialEq)]
+enum FrameScanner {
+    /// Inside the HTTP response to the upgrade, with how much of the blank line ending it
+    /// has been read.
+    Handshake { matched: usize },
+    /// Inside a frame header, with the bytes read so far.
+    Header { header: [u8; MAX_HEADER_LEN], len: usize },
+    /// Inside a frame payload, with how many bytes are left.
+    Payload { remaining: u64 },
+}
+
+impl Default for FrameScanner {
+    fn default() -> Self {
+        Self::Handshake { matched: 0 }
+    }
+}
+
+impl FrameScanner {
+    fn next_header() -> Self {
+        Self::Header {
+            header: [0; MAX_HEADER_LEN],
+            len: 0,
+        }
+    }
+
+    /// Scans the next `bytes` read, relabelling text frames among them.
+    fn scan(&mut self, bytes: &mut [u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            match self {
+                Self::Handshake { matched } => {
+                    *matched = match bytes[i] {
+                        byte if byte == b"\r\n\r\n"[*matched] => *matched + 1,
+                        b'\r' => 1,
+                        _ => 0,
+                    };
+                    i += 1;
+                    if *matched == 4 {
+                        *self = Self::next_header();
+                    }
+                }
+                Self::Header { header, len } => {
+                    if *len == 0 && bytes[i] & 0x0f == TEXT_OPCODE {
+                        bytes[i] = bytes[i] & 0xf0 | BINARY_OPCODE;
+                    }
+                    header[*len] = bytes[i];
+                    *len += 1;
+                    i += 1;
+                    match payload_len(&header[..*len]) {
+                        Some(0) => *self = Self::next_header(),
+                        Some(remaining) => *self = Self::Payload { remaining },
+                        None => {}
+                    }
+                }
+                Self::Payload { remaining } => {
+                    let left = usize::try_from(*remaining).unwrap_or(usize::MAX);
+                    let skipped = (bytes.len() - i).min(left);
+                    i += skipped;
+                    *remaining -= skipped as u64;
+                    if *remaining == 0 {
+                        *self = Self::next_header();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The payload length given by `header`, or None if it is still incomplete.
+fn payload_len(header: &[u8]) -> Option<u64> {
+    let (&length, rest) = header.get(1).zip(header.get(2..))?;
+    let masked = length & 0x80 != 0;
+    let extended = match length & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    if rest.len() < extended + if masked { 4 } else { 0 } {
+        return None;
+    }
+    Some(match extended {
+        2 => u64::from(u16::from_be_bytes([rest[0], rest[1]])),
+        8 => u64::from_be_bytes(rest[..8].try_into().unwrap()),
+        _ => u64::from(length & 0x7f),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relabels_text_frames() {
+        let mut bytes = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n".to_vec();
+        // A text frame, a ping, and a text frame with a 16 bit length.
+        bytes.extend([0x81, 0x03, b'a', 0x01, b'b']);
+        bytes.extend([0x89, 0x00]);
+        bytes.extend([0x81, 126, 0x01, 0x00]);
+        bytes.extend([0x81; 256]);
+        bytes.extend([0x82, 0x00]);
+
+        let mut scanner = FrameScanner::default();
+        // Split across reads, even inside headers.
+        let mut scanned = Vec::new();
+        for chunk in bytes.chunks_mut(3) {
+            scanner.scan(chunk);
+            scanned.extend_from_slice(chunk);
+        }
+
+        let frames = &scanned[scanned.len() - 269..];
+        assert_eq!(frames[..5], [0x82, 0x03, b'a', 0x01, b'b']);
+        assert_eq!(frames[5..7], [0x89, 0x00]);
+        assert_eq!(frames[7..11], [0x82, 126, 0x01, 0x00]);
+        // Payload bytes are left alone.
+        assert!(frames[11..267].iter().all(|&byte| byte == 0x81));
+        assert_eq!(frames[267..269], [0x82, 0x00]);
+        assert_eq!(scanner, FrameScanner::next_header());
+    }
+
+    #[test]
+    fn test_relabels_fragmented_text_frames() {
+        let mut scanner = FrameScanner::next_header();
+        let mut frames = Vec::new();
+        // A text message in three fragments, with a ping and a pong between them. The
+        // fragments' payloads look like headers of text frames.
+        frames.extend([0x01, 0x02, 0x81, 0x01]);
+        frames.extend([0x89, 0x01, 0x81]);
+        frames.extend([0x00, 0x01, 0x81]);
+        frames.extend([0x8a, 0x00]);
+        frames.extend([0x80, 0x01, 0x01]);
+        // RSV bits are kept, and so are the length and mask of a 64 bit header.
+        frames.extend([0xc1, 0x00]);
+        frames.extend([0x81, 0xff, 0, 0, 0, 0, 0, 0, 0, 0x02, 1, 2, 3, 4, 0x81, 0x81]);
+        frames.extend([0x81, 0x00]);
+
+        for chunk in frames.chunks_mut(1) {
+            scanner.scan(chunk);
+        }
+
+        let mut expected = Vec::new();
+        expected.extend([0x02, 0x02, 0x81, 0x01]);
+        expected.extend([0x89, 0x01, 0x81]);
+        expected.extend([0x00, 0x01, 0x81]);
+        expected.extend([0x8a, 0x00]);
+        expected.extend([0x80, 0x01, 0x01]);
+        expected.extend([0xc2, 0x00]);
+        expected.extend([0x82, 0xff, 0, 0, 0, 0, 0, 0, 0, 0x02, 1, 2, 3, 4, 0x81, 0x81]);
+        expected.extend([0x82, 0x00]);
+        assert_eq!(frames, expected);
+        assert_eq!(scanner, FrameScanner::next_header());
+    }
+}
@@ -0,0 +1,29 @@
+//! Spans for the `tracing` feature. Without the feature the macros compile to the plain
+//! future and to nothing, so `log`-only users don't pay for them.
+
+/// Runs `future` inside an info span named `name` with the given fields, when the
+/// `tracing` feature is enabled. The fields are evaluated before `future`.
+macro_rules! traced {
+    ($future:expr, $name:literal $(, $($fields:tt)+)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!($name $(, $($fields)+)?);
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument($future, span);
+        #[cfg(not(feature = "tracing"))]
+        let future = $future;
+        future
+    }};
+}
+
+/// Records `value` in the `field` declared as `tracing::field::Empty` on the current span.
+macro_rules! record_field {
+    ($field:literal, $value:expr) => {{
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, tracing::field::display($value));
+        #[cfg(not(feature = "tracing"))]
+        let _ = &$value;
+    }};
+}
+
+pub(crate) use record_field;
+pub(crate) use traced;
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+use crate::error::{PusherError, PusherResult};
+
+/// How many failures are kept between flushes. Older ones are only counted.
+const MAX_FAILURES: usize = 100;
+
+/// Counts triggers that are in flight and remembers failures no caller was told about, such
+/// as queued triggers that were dropped, so a publisher can wait for everything it started
+/// before shutting down.
+pub(crate) struct TriggerTracker {
+    in_flight: watch::Sender<usize>,
+    failures: Mutex<Failures>,
+}
+
+#[derive(Default)]
+struct Failures {
+    /// The latest failures, oldest first.
+    recent: VecDeque<String>,
+    /// How many older failures were forgotten to make room.
+    forgotten: usize,
+}
+
+impl TriggerTracker {
+    pub fn new() -> Self {
+        Self {
+            in_flight: watch::Sender::new(0),
+            failures: Mutex::new(Failures::default()),
+        }
+    }
+
+    /// Runs `trigger`, counting it as in flight until it completes or is dropped. Its error
+    /// goes to the caller, so it isn't recorded.
+    pub async fn track<T>(
+        &self,
+        trigger: impl Future<Output = PusherResult<T>>,
+    ) -> PusherResult<T> {
+        let _in_flight = InFlight::new(&self.in_flight);
+        trigger.await
+    }
+
+    /// Remembers a failure no caller saw, to be reported by `flush`. Only the latest
+    /// `MAX_FAILURES` are kept.
+    pub fn record_failure(&self, failure: String) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.recent.len() == MAX_FAILURES {
+            failures.recent.pop_front();
+            failures.forgotten += 1;
+        }
+        failures.recent.push_back(failure);
+    }
+
+    /// Waits until no trigger is in flight.
+    pub async fn wait_idle(&self) {
+        let mut in_flight = self.in_flight.subscribe();
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = in_flight.wait_for(|count| *count == 0).await;
+    }
+
+    /// Waits until no trigger is in flight, then returns `PusherError::TriggersFailed` with
+    /// the failures recorded since the last flush, if there were any.
+    pub async fn flush(&self) -> PusherResult<()> {
+        self.wait_idle().await;
+        let failures = std::mem::take(&mut *self.failures.lock().unwrap());
+        if failures.recent.is_empty() {
+            return Ok(());
+        }
+        let mut reported = Vec::with_capacity(failures.recent.len() + 1);
+        if failures.forgotten > 0 {
+            reported.push(format!("{} earlier failure(s) not listed", failures.forgotten));
+        }
+        reported.extend(failures.recent);
+        Err(PusherError::TriggersFailed(reported))
+    }
+}
+
+struct InFlight<'a> {
+    count: &'a watch::Sender<usize>,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(count: &'a watch::Sender<usize>) -> Self {
+        count.send_modify(|count| *count += 1);
+        Self { count }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.count.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_flush_waits_and_reports_failures() {
+        let tracker = TriggerTracker::new();
+        let slow_ok = tracker.track(async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            Ok(())
+        });
+        let failed = tracker.track(async {
+            let e = PusherError::ApiError {
+                status: 500,
+                code: None,
+                message: "boom".into(),
+            };
+            tracker.record_failure(format!("queued trigger: {}", e));
+            Err::<(), _>(e)
+        });
+
+        let (_, _, flushed) = tokio::join!(slow_ok, failed, async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let flushed = tracker.flush().await;
+            (flushed, *tracker.in_flight.borrow())
+        });
+
+        match flushed {
+            (Err(PusherError::TriggersFailed(failures)), 0) => {
+                // The error returned by `track` itself isn't reported again.
+                assert_eq!(failures, vec!["queued trigger: API error (status 500): boom"]);
+            }
+            other => panic!("Unexpected flush result: {:?}", other),
+        }
+        assert!(tracker.flush().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_trigger_is_no_longer_in_flight() {
+        let tracker = TriggerTracker::new();
+        let pending = tracker.track(std::future::pending::<PusherResult<()>>());
+        let _ = tokio::time::timeout(Duration::from_millis(5), pending).await;
+        assert_eq!(*tracker.in_flight.borrow(), 0);
+        assert!(tracker.flush().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_latest_failures() {
+        let tracker = TriggerTracker::new();
+        for i in 0..MAX_FAILURES + 5 {
+            tracker.record_failure(format!("failure {}", i));
+        }
+        match tracker.flush().await {
+            Err(PusherError::TriggersFailed(failures)) => {
+                assert_eq!(failures.len(), MAX_FAILURES + 1);
+                assert_eq!(failures[0], "5 earlier failure(s) not listed");
+                assert_eq!(failures[1], "failure 5");
+                assert_eq!(failures[MAX_FAILURES], format!("failure {}", MAX_FAILURES + 4));
+            }
+            other => panic!("Unexpected flush result: {:?}", other),
+        }
+        assert!(tracker.flush().await.is_ok());
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::PusherResult;
+
+/// A webhook request from Pusher, as sent to the app's webhook URL.
+///
+/// Verify the request with `PusherAuth::verify_webhook` before trusting its contents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    /// When Pusher created the webhook, in milliseconds since the Unix epoch.
+    pub time_ms: u64,
+    pub events: Vec<WebhookEvent>,
+}
+
+impl Webhook {
+    /// Parses the JSON body of a webhook request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PusherError::JsonError` if the body is not a webhook payload.
+    pub fn from_slice(body: &[u8]) -> PusherResult<Self> {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// An event reported in a webhook, named after its `name` field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The first connection subscribed to the channel.
+    ChannelOccupied { channel: String },
+    /// The last connection unsubscribed from the channel.
+    ChannelVacated { channel: String },
+    /// A user joined a presence channel.
+    MemberAdded { channel: String, user_id: String },
+    /// A user left a presence channel.
+    MemberRemoved { channel: String, user_id: String },
+    /// A client sent a `client-` event on a private or presence channel.
+    ClientEvent {
+        channel: String,
+        event: String,
+        data: String,
+        socket_id: String,
+        /// Set for events on presence channels.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<String>,
+    },
+    /// A client subscribed to a cache channel that has no cached event.
+    CacheMiss { channel: String },
+    /// The number of connections subscribed to the channel changed.
+    SubscriptionCount {
+        channel: String,
+        subscription_count: u64,
+    },
+    /// An event this version of the crate doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_webhook() {
+        let body = br#"{
+            "time_ms": 1327078148132,
+            "events": [
+                {"name": "channel_occupied", "channel": "test-channel"},
+                {"name": "member_added", "channel": "presence-room", "user_id": "alice"},
+                {
+                    "name": "client_event",
+                    "channel": "private-room",
+                    "event": "client-typing",
+                    "data": "{}",
+                    "socket_id": "1.1"
+                },
+                {"name": "subscription_count", "channel": "a", "subscription_count": 2},
+                {"name": "something_new", "channel": "a"}
+            ]
+        }"#;
+
+        let webhook = Webhook::from_slice(body).unwrap();
+        assert_eq!(webhook.time_ms, 1327078148132);
+        assert_eq!(
+            webhook.events,
+            vec![
+                WebhookEvent::ChannelOccupied {
+                    channel: "test-channel".to_string()
+                },
+                WebhookEvent::MemberAdded {
+                    channel: "presence-room".to_string(),
+                    user_id: "alice".to_string()
+                },
+                WebhookEvent::ClientEvent {
+                    channel: "private-room".to_string(),
+                    event: "client-typing".to_string(),
+                    data: "{}".to_string(),
+                    socket_id: "1.1".to_string(),
+                    user_id: None
+                },
+                WebhookEvent::SubscriptionCount {
+                    channel: "a".to_string(),
+                    subscription_count: 2
+                },
+                WebhookEvent::Unknown,
+            ]
+        );
+
+        assert!(Webhook::from_slice(b"{\"events\": []}").is_err());
+    }
+}
@@ -26,7 +26,7 @@ async fn setup_client() -> PusherClient {
 
 #[tokio::test]
 async fn test_pusher_client_connection() {
-    let mut client = setup_client().await;
+    let client = setup_client().await;
 
     client.connect().await.unwrap();
     assert_eq!(
@@ -48,7 +48,7 @@ async fn test_pusher_client_connection() {
 
 #[tokio::test]
 async fn test_channel_subscription() {
-    let mut client = setup_client().await;
+    let client = setup_client().await;
 
     // Connect with a timeout
     match timeout(Duration::from_secs(10), client.connect()).await {
@@ -77,8 +77,14 @@ async fn test_channel_subscription() {
         Err(_) => panic!("Subscription timed out"),
     }
 
-    // Wait a bit for the subscription to be processed
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    // Wait for the server to confirm the subscription
+    timeout(Duration::from_secs(5), async {
+        while !client.is_subscribed("test-channel").await {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Subscription was not confirmed");
 
     let channels = client.get_subscribed_channels().await;
     log::info!("Subscribed channels: {:?}", channels);
@@ -87,7 +93,7 @@ async fn test_channel_subscription() {
 
 #[tokio::test]
 async fn test_event_binding() {
-    let mut client = setup_client().await;
+    let client = setup_client().await;
 
     // Connect to Pusher
     client.connect().await.unwrap();
@@ -136,7 +142,7 @@ async fn test_event_binding() {
 #[tokio::test]
 #[ignore]
 async fn test_encrypted_channel() {
-    let mut client = setup_client().await;
+    let client = setup_client().await;
 
     client.connect().await.unwrap();
     client
@@ -152,7 +158,7 @@ async fn test_encrypted_channel() {
 
 #[tokio::test]
 async fn test_send_payload() {
-    let mut client = setup_client().await;
+    let client = setup_client().await;
 
     // Connect with a timeout
     match timeout(Duration::from_secs(10), client.connect()).await {
@@ -225,7 +231,7 @@ async fn test_send_payload() {
 
 #[tokio::test]
 async fn test_presence_channel_subscription() {
-    let mut client = setup_client().await;
+    let client = setup_client().await;
 
     // Connect to Pusher
     client.connect().await.unwrap();